@@ -5,6 +5,11 @@ use tracing::{debug, error, info, trace, warn};
 
 use serde::{Deserialize, Serialize};
 
+use crate::klipper_async::{
+    endpoint::MoonrakerAuth, heaters::HeatingSettings, limits::SafetySettings,
+    motion::MotionSettings, sim_printer::SimSettings, toolchanger::ToolchangerConfig,
+    zprobe::ZProbeSettings,
+};
 use crate::ui::options::Options;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -12,8 +17,16 @@ use crate::ui::options::Options;
 pub struct AppSettings {
     pub camera_index: usize,
     pub printer_url: String,
+    pub printer_auth: MoonrakerAuth,
     pub num_tools: usize,
     pub bounce_amount: f64,
+    pub toolchanger: ToolchangerConfig,
+    pub safety: SafetySettings,
+    pub motion: MotionSettings,
+    pub heating: HeatingSettings,
+    pub z_probe: ZProbeSettings,
+    pub simulate: bool,
+    pub simulation: SimSettings,
 }
 
 impl Default for AppSettings {
@@ -21,8 +34,16 @@ impl Default for AppSettings {
         AppSettings {
             camera_index: 0,
             printer_url: "".to_string(),
+            printer_auth: MoonrakerAuth::default(),
             num_tools: 1,
             bounce_amount: 0.5,
+            toolchanger: ToolchangerConfig::default(),
+            safety: SafetySettings::default(),
+            motion: MotionSettings::default(),
+            heating: HeatingSettings::default(),
+            z_probe: ZProbeSettings::default(),
+            simulate: false,
+            simulation: SimSettings::default(),
         }
     }
 }
@@ -39,9 +60,17 @@ pub fn read_options_from_file<P: AsRef<Path>>(path: P, options: &mut Options) ->
     let appsettings: AppSettings = toml::from_str(&std::fs::read_to_string(&path)?)?;
 
     options.printer_url = appsettings.printer_url;
+    options.printer_auth = appsettings.printer_auth;
     options.camera_index = appsettings.camera_index.to_string();
     options.num_tools = appsettings.num_tools;
     options.bounce_amount = appsettings.bounce_amount;
+    options.toolchanger = appsettings.toolchanger;
+    options.safety = appsettings.safety;
+    options.motion = appsettings.motion;
+    options.heating = appsettings.heating;
+    options.z_probe = appsettings.z_probe;
+    options.simulate = appsettings.simulate;
+    options.sim_settings = appsettings.simulation;
 
     Ok(())
 }
@@ -1,5 +1,22 @@
-pub mod commands;
+pub mod client;
+pub mod endpoint;
+pub mod heaters;
+pub mod http_client;
 pub mod klipper_async_types;
+pub mod limits;
+#[cfg(any(test, feature = "sim"))]
+pub mod mock_moonraker;
+pub mod motion;
+pub mod offsets;
+pub mod preflight;
+pub mod recorder;
+pub mod remote;
+pub mod resolution;
+pub mod runner;
+pub mod sim_printer;
+pub mod toolchanger;
+pub mod tools;
+pub mod zprobe;
 
 use std::sync::Arc;
 
@@ -13,11 +30,28 @@ use futures_util::{
 };
 use tokio::{net::TcpStream, sync::RwLock, time::Instant};
 use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
-use url::Url;
 
+pub use self::client::PrinterClient;
 pub use self::klipper_async_types::*;
+pub use self::runner::CommandRunner;
 use crate::{ui::ui_types::Axis, vision::WebcamMessage};
 
+/// How long to wait for a reply to a JSON-RPC request
+pub const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How long to wait for a G-code script to finish, long enough for homing and tool changes
+pub const GCODE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// How long a single websocket connection attempt may take
+pub const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Reconnect backoff, doubled after every failed attempt
+pub const RECONNECT_DELAY_MIN: std::time::Duration = std::time::Duration::from_millis(500);
+pub const RECONNECT_DELAY_MAX: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// How often to ask moonraker about klippy while it isn't ready, it only tells us about some changes
+pub const KLIPPY_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
 impl KlipperStatus {
     fn _update_pos(
         &mut self,
@@ -66,25 +100,6 @@ impl KlipperStatus {
         Ok(())
     }
 
-    fn _update_resolution(&mut self, stepper_x: &serde_json::Value) -> Result<()> {
-        let rot_dist = stepper_x["rotation_distance"]
-            .as_str()
-            .ok_or(anyhow!("Failed to parse rotation_distance"))?
-            .parse::<f64>()?;
-        let microsteps = stepper_x["microsteps"]
-            .as_str()
-            .ok_or(anyhow!("Failed to parse microsteps"))?
-            .parse::<f64>()?;
-        let steps_per_rot = stepper_x["full_steps_per_rotation"]
-            .as_str()
-            .ok_or(anyhow!("Failed to parse full_steps_per_rotation"))?
-            .parse::<f64>()?;
-
-        self.resolution = rot_dist / (microsteps * steps_per_rot);
-
-        Ok(())
-    }
-
     fn update(
         &mut self,
         sender: &UiInboxSender<KlipperMessage>,
@@ -108,8 +123,40 @@ impl KlipperStatus {
             );
         }
 
-        if let Some(stepper_x) = json.pointer("/result/status/configfile/config/stepper_x") {
-            self._update_resolution(&stepper_x)?;
+        if let Some(config) = json.pointer("/result/status/configfile/config") {
+            match resolution::AxisResolution::from_config(config) {
+                Ok(r) => {
+                    debug!("Resolution: {:?}", r);
+                    self.resolution = Some(r);
+                }
+                Err(e) => warn!("Failed to get resolution from config: {}", e),
+            }
+            match limits::AxisLimits::from_config(config) {
+                Ok(l) => {
+                    debug!("Axis limits: {:?}", l);
+                    self.axis_limits = Some(l);
+                }
+                Err(e) => warn!("Failed to read axis limits: {}", e),
+            }
+            let found = tools::from_config(config);
+            if !found.is_empty() {
+                debug!("Tools from config: {:?}", found);
+                self.tools = found;
+            }
+        }
+
+        /// save_variables only says which tools there are if the config didn't
+        let vars = json
+            .pointer("/params/0/save_variables/variables")
+            .or(json.pointer("/result/status/save_variables/variables"));
+        if let Some(vars) = vars {
+            if self
+                .tools
+                .iter()
+                .all(|t| t.source == tools::ToolSource::SaveVariable)
+            {
+                self.tools = tools::from_variables(vars);
+            }
         }
 
         // if let Some(pos) = json.pointer("/result/status/gcode_move/gcode_position") {
@@ -117,19 +164,24 @@ impl KlipperStatus {
         //     self._update_pos(sender, pos)?;
         // }
 
-        if let Some(pos) = json.pointer("/result/status/gcode_move/position") {
+        /// subscription updates only have what changed, queries have everything
+        let gcode_move = json
+            .pointer("/params/0/gcode_move")
+            .or(json.pointer("/result/status/gcode_move"));
+
+        if let Some(pos) = gcode_move.and_then(|g| g.get("position")) {
             // debug!("updating position from gcode");
             self._update_pos(sender, pos, false)?;
             // warn!("skipping updating position from gcode");
         }
 
-        if let Some(pos) = json.pointer("/result/status/gcode_move/gcode_position") {
+        if let Some(pos) = gcode_move.and_then(|g| g.get("gcode_position")) {
             // debug!("updating position from gcode");
             self._update_pos(sender, pos, true)?;
             // warn!("skipping updating position from gcode");
         }
 
-        if let Some(pos) = json.pointer("/result/status/gcode_move/homing_origin") {
+        if let Some(pos) = gcode_move.and_then(|g| g.get("homing_origin")) {
             // debug!("TODO: Got homing_origin: {:?}", pos);
             self._update_homing_origin(sender, pos)?;
         }
@@ -160,30 +212,34 @@ impl KlipperStatus {
             }
         }
 
-        if let Some(vars) = json.pointer("/result/status/save_variables/variables") {
-            self.vars = Some((Instant::now(), vars.clone()));
+        for root in ["/params/0", "/result/status"] {
+            let Some(objects) = json.pointer(root).and_then(|v| v.as_object()) else {
+                continue;
+            };
+            for (name, data) in objects.iter().filter(|(n, _)| heaters::is_extruder(n)) {
+                self.heaters.entry(name.clone()).or_default().update(data);
+            }
+            self.motion
+                .update(objects.get("motion_report"), objects.get("toolhead"));
         }
 
-        let Some(data) = json.pointer("/params/0/toolhead") else {
-            // bail!("Failed to get toolhead data");
-            return Ok(());
-        };
+        let toolhead = json
+            .pointer("/params/0/toolhead")
+            .or(json.pointer("/result/status/toolhead"));
 
         // debug!("updating status");
         // if let Some(pos) = data.get("position") {
         //     self._update_pos(sender, pos)?;
         // }
 
-        if let Some(axes) = data.get("homed_axes").and_then(|v| v.as_str()) {
+        if let Some(axes) = toolhead
+            .and_then(|t| t.get("homed_axes"))
+            .and_then(|v| v.as_str())
+        {
             let prev_axes = self.homed_axes;
-            match axes {
-                "" => self.homed_axes = (false, false, false),
-                "xy" => self.homed_axes = (true, true, false),
-                "xyz" => self.homed_axes = (true, true, true),
-                _ => {
-                    todo!("homed_axes: {:?}", axes);
-                }
-            }
+            /// any subset, e.g. "z" after `G28 Z`
+            let axes = axes.to_lowercase();
+            self.homed_axes = (axes.contains('x'), axes.contains('y'), axes.contains('z'));
             if self.homed_axes != prev_axes {
                 sender
                     .send(KlipperMessage::AxesHomed(self.homed_axes))
@@ -191,8 +247,8 @@ impl KlipperStatus {
             }
         }
 
-        if let Some(abs) = data
-            .pointer("/params/0/gcode_move/absolute_coordinates")
+        if let Some(abs) = gcode_move
+            .and_then(|g| g.get("absolute_coordinates"))
             .and_then(|v| v.as_bool())
         {
             self.absolute_coordinates = abs;
@@ -200,85 +256,222 @@ impl KlipperStatus {
 
         Ok(())
     }
+
+    /// Nothing queued or running that could move, and still for at least `settle`
+    pub fn is_settled(&self, settle: std::time::Duration) -> bool {
+        self.moves_queued == 0 && self.motion.stationary_for().map_or(false, |t| t >= settle)
+    }
+
+    pub fn is_homed(&self, axis: Axis) -> bool {
+        match axis {
+            Axis::X => self.homed_axes.0,
+            Axis::Y => self.homed_axes.1,
+            Axis::Z => self.homed_axes.2,
+        }
+    }
+
+    /// Tell the UI when the mounted tool changes
+    /// From a notification or a reply, whichever way it arrived
+    pub(super) fn update_from(
+        &mut self,
+        inbox: &UiInboxSender<KlipperMessage>,
+        active_tool_source: &toolchanger::ActiveToolSource,
+        json: &serde_json::Value,
+    ) {
+        if let Err(e) = self.update(inbox, json) {
+            error!("Failed to update status: {}", e);
+        }
+
+        let data = json.pointer("/params/0").or(json.pointer("/result/status"));
+        if let Some(tool) = data.and_then(|d| active_tool_source.parse(d)) {
+            self.set_active_tool(inbox, tool);
+        }
+    }
+
+    pub(super) fn set_active_tool(&mut self, sender: &UiInboxSender<KlipperMessage>, tool: i32) {
+        if self.active_tool == Some(tool) {
+            return;
+        }
+        debug!("Active tool: {}", tool);
+        self.active_tool = Some(tool);
+        sender
+            .send(KlipperMessage::ActiveToolChanged(tool))
+            .unwrap_or_else(|e| {
+                error!("Failed to send active tool message: {:?}", e);
+            });
+    }
 }
 
 impl KlipperConn {
+    /// Doesn't connect, [`KlipperConn::run`] connects and keeps reconnecting
     pub async fn new(
-        url: Url,
+        endpoint: endpoint::MoonrakerEndpoint,
         inbox: UiInboxSender<KlipperMessage>,
         // inbox_position: UiInboxSender<(f64, f64, f64)>,
         rx: tokio::sync::mpsc::Receiver<KlipperCommand>,
+        interrupts: tokio::sync::mpsc::UnboundedReceiver<Interrupt>,
         tx_status: tokio::sync::oneshot::Sender<Arc<RwLock<KlipperStatus>>>,
+        toolchanger: Box<dyn toolchanger::ToolchangerBackend>,
+        safety: limits::SafetySettings,
     ) -> Result<Self> {
-        let url = format!("ws://{}:7125/websocket", url.host_str().unwrap());
-
-        let (ws_stream, _) = connect_async(&url).await?;
-        debug!("Connected to {}", &url);
-
-        let (mut ws_write, mut ws_read) = ws_stream.split();
-
-        // let (tx, rx) = crossbeam_channel::bounded(1);
-
         let current_status = Arc::new(RwLock::new(KlipperStatus::default()));
-
-        let status2 = current_status.clone();
-        let inbox2 = inbox.clone();
-        tokio::spawn(Self::listener(status2, inbox2, ws_read));
+        let (klippy_events_tx, klippy_events) = tokio::sync::mpsc::unbounded_channel();
 
         tx_status.send(current_status.clone()).unwrap_or_else(|e| {
             error!("Failed to send status: {:?}", e);
         });
 
-        let mut out = KlipperConn {
-            url,
-            ws_write,
+        Ok(KlipperConn {
+            endpoint,
+            ws_write: None,
+            listener: None,
             // ws_read,
             current_status,
             inbox,
             channel_from_ui: rx,
+            interrupts: Some(interrupts),
             id: 1,
-        };
-
-        out.init().await?;
+            pending: PendingRequests::default(),
+            gcode_responses: tokio::sync::broadcast::channel(256).0,
+            active_tool_source: toolchanger.active_tool_source(),
+            toolchanger,
+            compatibility: Default::default(),
+            klippy_events,
+            klippy_events_tx,
+            safety,
+            recorder: None,
+        })
+    }
 
-        Ok(out)
+    /// Write every frame sent and received to a JSONL file, see [`recorder`]
+    pub fn with_recorder(mut self, recorder: recorder::SessionRecorder) -> Self {
+        self.recorder = Some(recorder);
+        self
     }
 
-    async fn init(&mut self) -> Result<()> {
-        self.subscribe_to_defaults().await?;
+    /// Open the websocket, start the listener, and [`KlipperConn::init`] if klippy is ready
+    async fn connect(&mut self) -> Result<()> {
+        debug!(
+            "Connecting to {} (auth: {:?})",
+            &self.endpoint, &self.endpoint.auth
+        );
 
-        self.query_object("configfile")
+        let request = self.endpoint.websocket_request().await?;
+        let (ws_stream, _) = tokio::time::timeout(CONNECT_TIMEOUT, connect_async(request))
             .await
-            .map_err(|e| anyhow!("Failed to query object: {:?}", e))?;
+            .map_err(|_| anyhow!("Timed out connecting to {}", &self.endpoint))??;
+        debug!("Connected to {}", &self.endpoint);
+
+        let (ws_write, ws_read) = ws_stream.split();
+
+        self.ws_write = Some(ws_write);
+        self.listener = Some(tokio::spawn(Self::listener(
+            self.current_status.clone(),
+            self.inbox.clone(),
+            self.pending.clone(),
+            self.gcode_responses.clone(),
+            self.active_tool_source.clone(),
+            self.klippy_events_tx.clone(),
+            self.recorder.clone(),
+            ws_read,
+        )));
+
+        self.current_status.write().await.connected = true;
+        self.inbox
+            .send(KlipperMessage::Connected)
+            .map_err(|e| anyhow!("Failed to send connected message: {:?}", e))?;
+
+        /// older moonraker, or no way to call them from klipper, everything else still works
+        if let Err(e) = self.register_remote_methods().await {
+            warn!("{}", e);
+        }
 
-        self.query_object("stepper_enable")
-            .await
-            .map_err(|e| anyhow!("Failed to query object: {:?}", e))?;
+        self.check_klippy().await?;
 
         Ok(())
     }
 
+    /// Drop the websocket and fail any outstanding requests
+    async fn disconnect(&mut self) {
+        if let Some(listener) = self.listener.take() {
+            listener.abort();
+        }
+        self.ws_write = None;
+        self.pending.lock().clear();
+
+        let mut status = self.current_status.write().await;
+        status.klippy_state = KlippyState::Unknown;
+        status.klippy_message.clear();
+        if status.connected {
+            status.connected = false;
+            self.inbox
+                .send(KlipperMessage::Disconnected)
+                .unwrap_or_else(|e| {
+                    error!("Failed to send disconnected message: {:?}", e);
+                });
+        }
+    }
+
+    /// resolves when the listener task exits, i.e. the websocket closed
+    async fn wait_for_listener(listener: &mut Option<tokio::task::JoinHandle<()>>) {
+        match listener {
+            Some(handle) => {
+                let _ = handle.await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    fn reconnect_delay(attempt: u32) -> std::time::Duration {
+        RECONNECT_DELAY_MIN
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(RECONNECT_DELAY_MAX)
+    }
+
     async fn listener(
         status: Arc<RwLock<KlipperStatus>>,
         inbox: UiInboxSender<KlipperMessage>,
-        mut ws_read: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+        pending: PendingRequests,
+        gcode_responses: tokio::sync::broadcast::Sender<String>,
+        active_tool_source: toolchanger::ActiveToolSource,
+        klippy_events: tokio::sync::mpsc::UnboundedSender<KlippyState>,
+        recorder: Option<recorder::SessionRecorder>,
+        mut ws_read: impl futures_util::Stream<
+                Item = Result<
+                    tokio_tungstenite::tungstenite::Message,
+                    tokio_tungstenite::tungstenite::Error,
+                >,
+            > + Unpin,
     ) {
         debug!("Listening for messages");
         loop {
             // debug!("Listening for messages: Looping");
             let Some(msg) = ws_read.next().await else {
                 warn!("WebSocket closed");
-                return;
+                break;
             };
 
             match msg {
                 Ok(msg) => {
                     // debug!("handling msg");
-                    Self::handle_message(&status, &inbox, msg)
-                        .await
-                        .unwrap_or_else(|e| {
-                            error!("Failed to handle message: {}", e);
-                        });
+                    if let (Some(recorder), Ok(text)) = (recorder.as_ref(), msg.to_text()) {
+                        if !text.is_empty() {
+                            recorder.record(recorder::Direction::In, text);
+                        }
+                    }
+                    Self::handle_message(
+                        &status,
+                        &inbox,
+                        &pending,
+                        &gcode_responses,
+                        &active_tool_source,
+                        &klippy_events,
+                        msg,
+                    )
+                    .await
+                    .unwrap_or_else(|e| {
+                        error!("Failed to handle message: {}", e);
+                    });
                 }
                 Err(e) => {
                     error!("Error receiving message: {}", e);
@@ -286,89 +479,151 @@ impl KlipperConn {
                 }
             }
         }
+
+        /// dropping the senders fails every outstanding request with ConnectionClosed
+        pending.lock().clear();
     }
 
-    pub fn get_id(&mut self) -> usize {
+    /// Allocates a JSON-RPC id and registers a pending reply for it.
+    /// The listener completes the receiver when the matching `result` or `error` arrives.
+    pub fn get_id(&mut self) -> (usize, tokio::sync::oneshot::Receiver<RpcResult>) {
         let id = self.id;
         self.id += 1;
-        id
-    }
 
-    /// toolhead.position is the actual coordinates, before applying tool offsets
-    ///
-    /// gcode_move:
-    ///     homing_origin:    current tool offsets
-    ///     gcode_position:   commanded position (after offset applied)
-    ///     position:         carriage position (before offset applied)
-    pub async fn subscribe_to_defaults(&mut self) -> Result<()> {
-        let msg = serde_json::json!({
-            "jsonrpc": "2.0",
-            "method": "printer.objects.subscribe",
-            "params": {
-                "objects": {
-                    "gcode_move": [
-                        "homing_origin",
-                        "position",
-                        "gcode_position",
-                        "absolute_coordinates",
-                        ],
-                    // "gcode_move": null,
-                    // "toolhead": ["position", "homed_axes"],
-                    "toolhead": ["homed_axes"],
-                    // "toolhead": null,
-                    // "motion_report": null,
-                    // "idle_timeout": null,
-                    "stepper_enable": null,
-                }
-            },
-            "id": self.get_id(),
-        })
-        .to_string();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.pending.lock().insert(id, tx);
 
-        self.ws_write
-            .send(tokio_tungstenite::tungstenite::Message::Text(msg.into()))
-            .await?;
-        Ok(())
+        (id, rx)
     }
 
-    pub async fn query_object(&mut self, object: &str) -> Result<()> {
-        let msg = serde_json::json!({
-            "jsonrpc": "2.0",
-            "method": "printer.objects.query",
-            "params": {
-                "objects": {
-                    object: null,
-                }
-            },
-            "id": self.get_id(),
-        })
-        .to_string();
-        self.ws_write
-            .send(tokio_tungstenite::tungstenite::Message::Text(msg.into()))
+    pub async fn send_json(&mut self, json: &serde_json::Value) -> Result<()> {
+        let Some(ws_write) = self.ws_write.as_mut() else {
+            bail!("Not connected");
+        };
+        let text = json.to_string();
+        if let Some(recorder) = self.recorder.as_ref() {
+            recorder.record(recorder::Direction::Out, &text);
+        }
+        ws_write
+            .send(tokio_tungstenite::tungstenite::Message::Text(text.into()))
             .await?;
         Ok(())
     }
 
-    pub async fn list_objects(&mut self) -> Result<()> {
-        let msg = serde_json::json!({
+    /// Updates for everything in [`PrinterClient::status_objects`] come as `notify_status_update`
+    pub async fn subscribe_to_defaults(&mut self) -> Result<serde_json::Value> {
+        let params = serde_json::json!({
+            "objects": self.status_objects().await,
+        });
+        Ok(self
+            .request("printer.objects.subscribe", Some(params))
+            .await?)
+    }
+}
+
+/// Talks to moonraker over its websocket
+impl PrinterClient for KlipperConn {
+    async fn request_with_timeout(
+        &mut self,
+        method: &str,
+        params: Option<serde_json::Value>,
+        timeout: std::time::Duration,
+    ) -> RpcResult {
+        if self.ws_write.is_none() {
+            return Err(KlipperRpcError::NotConnected);
+        }
+
+        let (id, rx) = self.get_id();
+
+        let mut msg = serde_json::json!({
             "jsonrpc": "2.0",
-            "method": "printer.objects.list",
-            "id": self.get_id(),
-        })
-        .to_string();
-        self.ws_write
-            .send(tokio_tungstenite::tungstenite::Message::Text(msg.into()))
-            .await?;
-        Ok(())
+            "method": method,
+            "id": id,
+        });
+        if let Some(params) = params {
+            msg["params"] = params;
+        }
+
+        if let Err(e) = self.send_json(&msg).await {
+            self.pending.lock().remove(&id);
+            return Err(KlipperRpcError::Send(e.to_string()));
+        }
+
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(_)) => Err(KlipperRpcError::ConnectionClosed),
+            Err(_) => {
+                self.pending.lock().remove(&id);
+                Err(KlipperRpcError::Timeout {
+                    method: method.to_string(),
+                    id,
+                })
+            }
+        }
+    }
+
+    fn status(&self) -> &Arc<RwLock<KlipperStatus>> {
+        &self.current_status
+    }
+
+    fn inbox(&self) -> &UiInboxSender<KlipperMessage> {
+        &self.inbox
+    }
+
+    fn toolchanger(&self) -> &dyn toolchanger::ToolchangerBackend {
+        self.toolchanger.as_ref()
+    }
+
+    fn toolchanger_mut(&mut self) -> &mut dyn toolchanger::ToolchangerBackend {
+        self.toolchanger.as_mut()
+    }
+
+    fn safety(&self) -> &limits::SafetySettings {
+        &self.safety
+    }
+
+    fn gcode_responses(&self) -> Option<tokio::sync::broadcast::Receiver<String>> {
+        Some(self.gcode_responses.subscribe())
     }
 }
 
 /// main loop
-impl KlipperConn {
-    pub async fn run(&mut self) -> Result<()> {
+impl CommandRunner for KlipperConn {
+    /// Runs until the UI closes the command channel, reconnecting with backoff
+    /// whenever the websocket drops
+    async fn run(&mut self) -> Result<()> {
+        /// kept out of `self` so it can be polled while a command borrows it
+        let Some(mut interrupts) = self.interrupts.take() else {
+            bail!("Already running");
+        };
+        let mut attempt = 0;
         loop {
             // debug!("looping");
 
+            if self.ws_write.is_none() {
+                if let Err(e) = self.connect().await {
+                    warn!("Failed to connect to {}: {}", &self.endpoint, e);
+                    self.disconnect().await;
+
+                    let delay = Self::reconnect_delay(attempt);
+                    attempt += 1;
+                    self.inbox
+                        .send(KlipperMessage::Reconnecting(attempt, delay))
+                        .unwrap_or_else(|e| {
+                            error!("Failed to send reconnecting message: {:?}", e);
+                        });
+
+                    if !self.wait_to_reconnect(delay, &mut interrupts).await {
+                        debug!("Channel closed");
+                        return Ok(());
+                    }
+                    continue;
+                }
+                attempt = 0;
+            }
+
+            let klippy_ready = self.klippy_ready().await;
+
             tokio::select! {
                 // Some(Ok(msg)) = self.ws_read.next() => {
                 //     self.handle_message(msg).unwrap();
@@ -379,49 +634,72 @@ impl KlipperConn {
                             debug!("Channel closed");
                             return Ok(());
                         }
-                        Some(cmd) => {
-                            self.handle_command(cmd).await.unwrap();
-                        }
+                        Some(cmd) => self.run_command(cmd, &mut interrupts).await,
                     }
                 }
+                Some(interrupt) = interrupts.recv() => {
+                    self.handle_interrupt(interrupt).await;
+                }
+                Some(state) = self.klippy_events.recv() => {
+                    let res = if state == KlippyState::Disconnected {
+                        self.set_klippy_state(state, String::new()).await
+                    } else {
+                        self.check_klippy().await
+                    };
+                    if let Err(e) = res {
+                        error!("Failed to update klippy state: {}", e);
+                        self.send_error(format!("Failed to update klippy state: {}", e));
+                    }
+                }
+                _ = tokio::time::sleep(KLIPPY_POLL_INTERVAL), if !klippy_ready => {
+                    if let Err(e) = self.check_klippy().await {
+                        debug!("Failed to check klippy state: {}", e);
+                    }
+                }
+                _ = Self::wait_for_listener(&mut self.listener) => {
+                    warn!("Lost connection to {}", &self.endpoint);
+                    self.disconnect().await;
+                }
             };
         }
     }
-}
 
-impl KlipperConn {
-    async fn handle_command(&mut self, cmd: KlipperCommand) -> Result<()> {
-        match cmd {
-            KlipperCommand::MoveToPosition(pos, bounce) => self.move_to_position(pos, bounce).await,
-            KlipperCommand::MoveAxisRelative(axis, amount, bounce) => {
-                self.move_axis_relative(axis, amount, bounce).await
-            }
-            KlipperCommand::HomeXY => self.home_xy().await,
-            KlipperCommand::HomeAll => self.home_all().await,
-            KlipperCommand::GetPosition(tx) => {
-                tx.send(self.get_position().await.ok()).unwrap();
-                Ok(())
-            }
-            KlipperCommand::PickTool(tool) => self.pick_tool(tool).await,
-            KlipperCommand::DropTool => self.dropoff_tool().await,
-            KlipperCommand::AdjustToolOffset(tool, axis, amount) => {
-                self.adjust_tool_offset(tool as usize, axis, amount).await
-            }
-            KlipperCommand::SetToolOffset(tool, axis, amount) => {
-                self.set_tool_offset(tool as usize, axis, amount).await
-            }
-            KlipperCommand::GetToolOffsets => self.get_offsets().await,
-            KlipperCommand::DisableMotors => self.disable_motors().await,
-            KlipperCommand::WaitForMoves => self.wait_for_moves().await,
-            KlipperCommand::Dwell(ms) => self.dwell(ms).await,
-            KlipperCommand::FetchPosition => self.query_object("gcode_move").await,
-        }
+    async fn init(&mut self) -> Result<()> {
+        self.subscribe_to_defaults().await?;
+
+        self.query_object("configfile")
+            .await
+            .map_err(|e| anyhow!("Failed to query object: {:?}", e))?;
+
+        self.query_object("stepper_enable")
+            .await
+            .map_err(|e| anyhow!("Failed to query object: {:?}", e))?;
+
+        Ok(())
     }
 
+    fn commands(&mut self) -> &mut tokio::sync::mpsc::Receiver<KlipperCommand> {
+        &mut self.channel_from_ui
+    }
+
+    fn compatibility(&self) -> &preflight::CompatibilityReport {
+        &self.compatibility
+    }
+
+    fn set_compatibility(&mut self, report: preflight::CompatibilityReport) {
+        self.compatibility = report;
+    }
+}
+
+impl KlipperConn {
     // #[cfg(feature = "nope")]
     async fn handle_message(
         status: &RwLock<KlipperStatus>,
         inbox: &UiInboxSender<KlipperMessage>,
+        pending: &PendingRequests,
+        gcode_responses: &tokio::sync::broadcast::Sender<String>,
+        active_tool_source: &toolchanger::ActiveToolSource,
+        klippy_events: &tokio::sync::mpsc::UnboundedSender<KlippyState>,
         msg: tokio_tungstenite::tungstenite::Message,
     ) -> Result<()> {
         // debug!("handle_message: {:?}", msg);
@@ -469,6 +747,31 @@ impl KlipperConn {
                     }
                 }
             } else if method == "notify_gcode_response" {
+                if let Some(line) = json.pointer("/params/0").and_then(|v| v.as_str()) {
+                    trace!("gcode response: {}", line);
+                    /// no receivers unless a script is running
+                    let _ = gcode_responses.send(line.to_string());
+                    inbox
+                        .send(KlipperMessage::GcodeResponse(line.to_string()))
+                        .unwrap_or_else(|e| {
+                            error!("Failed to send gcode response: {:?}", e);
+                        });
+                }
+            } else if let Some(state) = method.strip_prefix("notify_klippy_") {
+                debug!("klippy {}", state);
+                /// the run loop has the websocket, to ask why and to subscribe again
+                let _ = klippy_events.send(KlippyState::from_str(state));
+            } else if remote::is_remote_method(method) {
+                debug!("remote method called: {}", method);
+                let params = json.get("params").cloned().unwrap_or_default();
+                inbox
+                    .send(KlipperMessage::RemoteMethodCalled(
+                        method.to_string(),
+                        params,
+                    ))
+                    .unwrap_or_else(|e| {
+                        error!("Failed to send remote method call: {:?}", e);
+                    });
             } else if method == "notify_filelist_changed" {
             } else if json.pointer("/result/status/configfile").is_some() {
                 debug!("Got configfile");
@@ -478,8 +781,30 @@ impl KlipperConn {
             }
         }
 
-        if let Err(e) = status.write().await.update(&inbox, &json) {
-            error!("Failed to update status: {}", e);
+        status
+            .write()
+            .await
+            .update_from(&inbox, active_tool_source, &json);
+
+        /// status is updated first, so the caller sees the new state once its reply resolves
+        if let Some(id) = json.get("id").and_then(|v| v.as_u64()) {
+            if let Some(tx) = pending.lock().remove(&(id as usize)) {
+                let res = if let Some(err) = json.get("error") {
+                    Err(KlipperRpcError::Rpc {
+                        code: err["code"].as_i64().unwrap_or(0),
+                        message: err["message"].as_str().unwrap_or("").to_string(),
+                    })
+                } else {
+                    Ok(json
+                        .get("result")
+                        .cloned()
+                        .unwrap_or(serde_json::Value::Null))
+                };
+                /// the caller may have timed out and dropped the receiver
+                let _ = tx.send(res);
+            } else {
+                trace!("Got reply for unknown request id: {}", id);
+            }
         }
 
         // if method == "notify_status_update" {
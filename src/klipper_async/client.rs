@@ -0,0 +1,723 @@
+//! What can be done with a printer, written once on top of a JSON-RPC request.
+//! [`KlipperConn`](super::KlipperConn) sends requests over the websocket,
+//! [`HttpClient`](super::http_client::HttpClient) as REST calls.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tokio::time::Instant;
+use tracing::{debug, error, info, trace, warn};
+
+use std::sync::Arc;
+
+use egui_inbox::UiInboxSender;
+use tokio::sync::RwLock;
+
+use super::{
+    heaters,
+    limits::SafetySettings,
+    motion::{self, MoveSpeed},
+    offsets::{self, CommitReport, OffsetChange},
+    toolchanger::{ActiveToolSource, Persistence, ToolchangerBackend},
+    tools, GcodeError, GcodeErrorKind, KlipperMessage, KlipperStatus, RpcResult, GCODE_TIMEOUT,
+    REQUEST_TIMEOUT,
+};
+use crate::ui::ui_types::Axis;
+
+/// `F` for Z moves that don't ask for a speed, mm/min
+const Z_FEEDRATE: f64 = 500.;
+
+/// `SAVE_GCODE_STATE` slot used around moves with their own feedrate
+const GCODE_STATE_NAME: &str = "TC_UTILS_MOVE";
+
+/// Implementations give the transport and where the state is kept,
+/// every operation is a provided method so they can't drift apart
+#[allow(async_fn_in_trait)]
+pub trait PrinterClient: Send {
+    /// Send a JSON-RPC request and wait for its reply.
+    /// The status is updated from the reply before this returns.
+    async fn request_with_timeout(
+        &mut self,
+        method: &str,
+        params: Option<serde_json::Value>,
+        timeout: std::time::Duration,
+    ) -> RpcResult;
+
+    fn status(&self) -> &Arc<RwLock<KlipperStatus>>;
+
+    fn inbox(&self) -> &UiInboxSender<KlipperMessage>;
+
+    fn toolchanger(&self) -> &dyn ToolchangerBackend;
+
+    /// backends may keep track of the mounted tool and offsets they've set
+    fn toolchanger_mut(&mut self) -> &mut dyn ToolchangerBackend;
+
+    /// keep-out zones, every move is checked against these and the soft limits
+    fn safety(&self) -> &SafetySettings;
+
+    /// Console lines printed from now on, None if the transport doesn't get them
+    fn gcode_responses(&self) -> Option<tokio::sync::broadcast::Receiver<String>> {
+        None
+    }
+
+    /// Send a JSON-RPC request and wait for its reply, using [`REQUEST_TIMEOUT`]
+    async fn request(&mut self, method: &str, params: Option<serde_json::Value>) -> RpcResult {
+        self.request_with_timeout(method, params, REQUEST_TIMEOUT)
+            .await
+    }
+
+    async fn query_object(&mut self, object: &str) -> Result<serde_json::Value> {
+        let params = serde_json::json!({
+            "objects": {
+                object: null,
+            }
+        });
+        Ok(self.request("printer.objects.query", Some(params)).await?)
+    }
+
+    /// `objects` in the same `{name: fields}` format as moonraker takes
+    async fn query_objects(&mut self, objects: serde_json::Value) -> Result<serde_json::Value> {
+        let params = serde_json::json!({
+            "objects": objects,
+        });
+        Ok(self.request("printer.objects.query", Some(params)).await?)
+    }
+
+    async fn list_objects(&mut self) -> Result<serde_json::Value> {
+        Ok(self.request("printer.objects.list", None).await?)
+    }
+
+    /// names from [`Self::list_objects`]
+    async fn object_names(&mut self) -> Result<Vec<String>> {
+        let objects = self.list_objects().await?;
+        Ok(objects["objects"]
+            .as_array()
+            .ok_or_else(|| anyhow!("No objects in reply"))?
+            .iter()
+            .filter_map(|o| o.as_str().map(|s| s.to_string()))
+            .collect())
+    }
+
+    /// toolhead.position is the actual coordinates, before applying tool offsets
+    ///
+    /// gcode_move:
+    ///     homing_origin:    current tool offsets
+    ///     gcode_position:   commanded position (after offset applied)
+    ///     position:         carriage position (before offset applied)
+    ///
+    /// Subscribed to over the websocket, polled over HTTP
+    async fn status_objects(&mut self) -> serde_json::Value {
+        let mut objects = serde_json::json!({
+            "gcode_move": [
+                "homing_origin",
+                "position",
+                "gcode_position",
+                "absolute_coordinates",
+                ],
+            // "gcode_move": null,
+            // "toolhead": ["position", "homed_axes"],
+            "toolhead": ["homed_axes", "print_time", "estimated_print_time"],
+            // "toolhead": null,
+            "motion_report": ["live_position", "live_velocity"],
+            // "idle_timeout": null,
+            "stepper_enable": null,
+        });
+
+        /// every extruder, for the temperatures
+        let names = self.object_names().await.unwrap_or_default();
+        for name in names.iter().filter(|n| heaters::is_extruder(n)) {
+            objects[name] = serde_json::json!(["temperature", "target"]);
+        }
+
+        /// so the active tool is tracked without asking
+        if let Some(serde_json::Value::Object(extra)) =
+            self.toolchanger().active_tool_source().objects()
+        {
+            if let Some(objects) = objects.as_object_mut() {
+                objects.extend(extra);
+            }
+        }
+
+        objects
+    }
+
+    async fn home_all(&mut self) -> Result<()> {
+        self.run_gcode("G28").await
+    }
+
+    async fn home_xy(&mut self) -> Result<()> {
+        self.run_gcode("G28 X Y").await
+    }
+
+    /// carriage position (before offsets applied), read from the reply to the query itself
+    async fn get_position(&mut self) -> Result<(f64, f64, f64)> {
+        Ok(self.get_position_and_origin().await?.0)
+    }
+
+    /// carriage position and the gcode offset, G-code coordinates are the first minus the second
+    async fn get_position_and_origin(&mut self) -> Result<((f64, f64, f64), (f64, f64, f64))> {
+        let res = self.query_object("gcode_move").await?;
+
+        let parse = |key: &str| {
+            let v = res
+                .pointer(&format!("/status/gcode_move/{}", key))
+                .ok_or_else(|| anyhow!("No gcode_move {} in reply", key))?;
+            match (v[0].as_f64(), v[1].as_f64(), v[2].as_f64()) {
+                (Some(x), Some(y), Some(z)) => Ok((x, y, z)),
+                _ => bail!("Failed to parse {}: {:?}", key, v),
+            }
+        };
+
+        Ok((parse("position")?, parse("homing_origin")?))
+    }
+
+    /// Check each leg of a path against the soft limits and keep-out zones, carriage coordinates
+    async fn check_path(&self, path: &[(f64, f64, f64)]) -> Result<()> {
+        let limits = self.status().read().await.axis_limits;
+        for leg in path.windows(2) {
+            self.safety().check_move(limits.as_ref(), leg[0], leg[1])?;
+        }
+        Ok(())
+    }
+
+    async fn pick_tool(&mut self, tool: u32) -> Result<()> {
+        let gcode = self.toolchanger_mut().pick_tool(tool);
+        if let Err(e) = self.run_gcode(&gcode).await {
+            /// part of the macro may have run
+            self.toolchanger_mut().tool_changed(None);
+            return Err(e);
+        }
+        self.tool_changed(tool as i32).await;
+        Ok(())
+    }
+
+    async fn dropoff_tool(&mut self) -> Result<()> {
+        let gcode = self.toolchanger_mut().drop_tool();
+        if let Err(e) = self.run_gcode(&gcode).await {
+            self.toolchanger_mut().tool_changed(None);
+            return Err(e);
+        }
+        self.tool_changed(-1).await;
+        Ok(())
+    }
+
+    /// When the printer doesn't report the mounted tool, assume a change that didn't error worked
+    async fn tool_changed(&mut self, tool: i32) {
+        self.toolchanger_mut().tool_changed(Some(tool));
+        if self.toolchanger().active_tool_source() == ActiveToolSource::LastToolChange {
+            self.status()
+                .write()
+                .await
+                .set_active_tool(self.inbox(), tool);
+        }
+    }
+
+    async fn move_to_position(
+        &mut self,
+        pos: (f64, f64, f64),
+        bounce: Option<f64>,
+        speed: MoveSpeed,
+    ) -> Result<()> {
+        let x = pos.0;
+        let y = pos.1;
+
+        let Ok((carriage, origin)) = self.get_position_and_origin().await else {
+            bail!("Failed to get position");
+        };
+        /// G-code coordinates
+        let start = (
+            carriage.0 - origin.0,
+            carriage.1 - origin.1,
+            carriage.2 - origin.2,
+        );
+        let (x0, y0) = (start.0, start.1);
+        /// an XY move is fine after `G28 X Y`
+        if pos.2 != start.2 {
+            self.check_homed(&[Axis::Z]).await?;
+        }
+        let to_carriage = |(x, y, z): (f64, f64, f64)| (x + origin.0, y + origin.1, z + origin.2);
+
+        let bounce_pos = bounce.map(|bounce_amount| {
+            let x2 = if x0 >= x {
+                x - bounce_amount
+            } else {
+                x + bounce_amount
+            };
+            let y2 = if y0 >= y {
+                y - bounce_amount
+            } else {
+                y + bounce_amount
+            };
+            (x2, y2)
+        });
+
+        let waypoints = motion::plan_travel(start, pos, bounce_pos);
+
+        let mut path = vec![carriage];
+        path.extend(waypoints.iter().map(|p| to_carriage(*p)));
+        self.check_path(&path).await?;
+
+        debug!("Moving to {} {}", x, y);
+
+        let mut prev = start;
+        let mut gcodes = vec![];
+        for p in waypoints {
+            gcodes.extend(motion::move_gcode(prev, p, &speed));
+            prev = p;
+        }
+
+        self.run_moves(&gcodes, speed, true).await
+    }
+
+    async fn move_axis_relative(
+        &mut self,
+        axis: Axis,
+        amount: f64,
+        bounce: Option<f64>,
+        speed: MoveSpeed,
+    ) -> Result<()> {
+        let axis = match axis {
+            Axis::X => "X",
+            Axis::Y => "Y",
+            Axis::Z => "Z",
+            // _ => bail!("Invalid axis"),
+        };
+
+        debug!("Moving axis {} by {}", axis, amount);
+
+        let moves = match bounce {
+            Some(bounce_amount) if axis != "Z" => {
+                // let bounce_amount = 5.;
+
+                if amount > 0.0 {
+                    vec![amount + bounce_amount, -bounce_amount]
+                } else {
+                    vec![amount - bounce_amount, bounce_amount]
+                }
+            }
+            _ => vec![amount],
+        };
+
+        let Ok(carriage) = self.get_position().await else {
+            bail!("Failed to get position");
+        };
+        let mut path = vec![carriage];
+        for m in moves.iter() {
+            let mut p = *path.last().unwrap();
+            match axis {
+                "X" => p.0 += m,
+                "Y" => p.1 += m,
+                _ => p.2 += m,
+            }
+            path.push(p);
+        }
+        self.check_path(&path).await?;
+
+        /// Z is slow unless asked otherwise
+        let feedrate = match speed.feedrate() {
+            Some(f) => Some(f),
+            None if axis == "Z" => Some(Z_FEEDRATE),
+            None => None,
+        };
+
+        let gcodes: Vec<String> = moves
+            .into_iter()
+            .map(|m| match feedrate {
+                Some(f) => format!("_CLIENT_LINEAR_MOVE {}={} F={:.0}", axis, m, f),
+                None => format!("_CLIENT_LINEAR_MOVE {}={}", axis, m),
+            })
+            .collect();
+
+        /// the macro saves and restores the gcode state itself
+        self.run_moves(&gcodes, speed, false).await
+    }
+
+    /// Run moves with the speed and acceleration asked for, putting the printer's own
+    /// limits back afterwards even if a move fails
+    async fn run_moves(
+        &mut self,
+        gcodes: &[String],
+        speed: MoveSpeed,
+        save_state: bool,
+    ) -> Result<()> {
+        if speed.is_default() {
+            for gcode in gcodes {
+                self.run_gcode(gcode).await?;
+            }
+            return Ok(());
+        }
+
+        let prev_limits = self.set_velocity_limit(&speed).await?;
+        /// G1 F sticks, don't leave it changed for whatever runs next
+        let save_state = save_state && speed.speed.is_some();
+        if save_state {
+            self.run_gcode(&format!("SAVE_GCODE_STATE NAME={}", GCODE_STATE_NAME))
+                .await?;
+        }
+
+        let mut res = Ok(());
+        for gcode in gcodes {
+            res = self.run_gcode(gcode).await;
+            if res.is_err() {
+                break;
+            }
+        }
+
+        if save_state {
+            if let Err(e) = self
+                .run_gcode(&format!("RESTORE_GCODE_STATE NAME={}", GCODE_STATE_NAME))
+                .await
+            {
+                warn!("Failed to restore gcode state: {:?}", e);
+            }
+        }
+        if let Some(prev) = prev_limits {
+            if let Err(e) = self.restore_velocity_limit(prev).await {
+                error!("Failed to restore velocity limits: {:?}", e);
+            }
+        }
+
+        res
+    }
+
+    /// `SET_VELOCITY_LIMIT` for the given speed and acceleration,
+    /// returns the printer's previous `(max_velocity, max_accel)` to restore
+    async fn set_velocity_limit(&mut self, speed: &MoveSpeed) -> Result<Option<(f64, f64)>> {
+        if speed.speed.is_none() && speed.accel.is_none() {
+            return Ok(None);
+        }
+
+        let res = self
+            .query_objects(serde_json::json!({ "toolhead": ["max_velocity", "max_accel"] }))
+            .await?;
+        let get = |key: &str| {
+            res.pointer(&format!("/status/toolhead/{}", key))
+                .and_then(|v| v.as_f64())
+                .ok_or_else(|| anyhow!("No toolhead {} in reply", key))
+        };
+        let prev = (get("max_velocity")?, get("max_accel")?);
+
+        /// only ever slower than the printer is configured for
+        let mut gcode = "SET_VELOCITY_LIMIT".to_string();
+        if let Some(v) = speed.speed {
+            gcode.push_str(&format!(" VELOCITY={:.1}", v.min(prev.0)));
+        }
+        if let Some(a) = speed.accel {
+            gcode.push_str(&format!(" ACCEL={:.0}", a.min(prev.1)));
+        }
+        self.run_gcode(&gcode).await?;
+
+        Ok(Some(prev))
+    }
+
+    async fn restore_velocity_limit(&mut self, (velocity, accel): (f64, f64)) -> Result<()> {
+        self.run_gcode(&format!(
+            "SET_VELOCITY_LIMIT VELOCITY={:.1} ACCEL={:.0}",
+            velocity, accel
+        ))
+        .await
+    }
+
+    /// Doesn't wait for it, the temperature is followed in the status
+    async fn set_tool_temperature(&mut self, tool: u32, target: f64) -> Result<()> {
+        let heater = tools::extruder_for(&self.status().read().await.tools, tool);
+        ensure!(
+            self.status().read().await.heaters.contains_key(&heater),
+            "T{} has no heater, printer has no [{}]",
+            tool,
+            heater
+        );
+        self.run_gcode(&format!(
+            "SET_HEATER_TEMPERATURE HEATER={} TARGET={:.1}",
+            heater, target
+        ))
+        .await
+    }
+
+    async fn cool_down(&mut self) -> Result<()> {
+        let heaters: Vec<String> = self
+            .status()
+            .read()
+            .await
+            .heaters
+            .iter()
+            .filter(|(_, h)| h.is_on())
+            .map(|(name, _)| name.clone())
+            .collect();
+        for heater in heaters {
+            self.run_gcode(&format!(
+                "SET_HEATER_TEMPERATURE HEATER={} TARGET=0",
+                heater
+            ))
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Klipper echoes its own errors to the console, only report the ones it can't
+    async fn run_console_gcode(&mut self, gcode: &str) -> Result<()> {
+        /// nothing is echoed without the websocket
+        let echoed = self.gcode_responses().is_some();
+        match self.run_gcode_with_output(gcode).await {
+            Ok(_) => Ok(()),
+            Err(e)
+                if !echoed
+                    || e.kind == GcodeErrorKind::Timeout
+                    || e.kind == GcodeErrorKind::Disconnected =>
+            {
+                self.inbox()
+                    .send(KlipperMessage::GcodeResponse(format!("!! {}", e.message)))
+                    .map_err(|e| anyhow!("Failed to send gcode response: {:?}", e))
+            }
+            Err(e) => {
+                debug!("Console command failed: {}", e);
+                Ok(())
+            }
+        }
+    }
+
+    async fn disable_motors(&mut self) -> Result<()> {
+        self.run_gcode("M18").await
+    }
+
+    async fn wait_for_moves(&mut self) -> Result<()> {
+        self.run_gcode("M400").await
+    }
+
+    async fn dwell(&mut self, ms: u32) -> Result<()> {
+        self.run_gcode(&format!("G4 P{}", ms)).await
+    }
+
+    async fn adjust_tool_offset(&mut self, tool: usize, axis: Axis, amount: f64) -> Result<()> {
+        let offsets = self.read_tool_offsets().await?;
+        let current = offsets
+            .get(tool)
+            .map(|(x, y, z)| match axis {
+                Axis::X => *x,
+                Axis::Y => *y,
+                Axis::Z => *z,
+            })
+            .unwrap_or(0.);
+
+        let gcode = self
+            .toolchanger_mut()
+            .adjust_offset(tool as u32, axis, amount, current);
+        self.run_toolchanger_gcode(&gcode).await
+    }
+
+    async fn set_tool_offset(&mut self, tool: usize, axis: Axis, amount: f64) -> Result<()> {
+        let gcode = self.toolchanger_mut().set_offset(tool as u32, axis, amount);
+        self.run_toolchanger_gcode(&gcode).await
+    }
+
+    /// Set every offset and read them all back, sending the ones that didn't take again.
+    /// Only once they all match are they saved, so a bad write is never made permanent.
+    async fn commit_offsets(&mut self, changes: &[OffsetChange]) -> Result<()> {
+        /// e.g. offsets of tools that aren't mounted, with SET_GCODE_OFFSET
+        let (checked, unverified): (Vec<OffsetChange>, Vec<OffsetChange>) = changes
+            .iter()
+            .partition(|c| self.toolchanger().reads_back(c.tool));
+        if !unverified.is_empty() {
+            warn!(
+                "Can't read back offsets of {}",
+                offsets::describe_changes(&unverified)
+            );
+        }
+
+        let mut pending = changes.to_vec();
+        let mut attempts = 0;
+        let offsets = loop {
+            attempts += 1;
+            for c in pending.iter() {
+                self.set_tool_offset(c.tool as usize, c.axis, c.value)
+                    .await?;
+            }
+
+            let offsets = self.read_tool_offsets().await?;
+            let mismatched = offsets::mismatches(&checked, &offsets);
+            if mismatched.is_empty() {
+                break offsets;
+            }
+
+            let described = offsets::describe_mismatches(&mismatched);
+            if attempts >= offsets::COMMIT_ATTEMPTS {
+                /// still show what the printer has now
+                self.inbox()
+                    .send(KlipperMessage::ToolOffsets(offsets))
+                    .map_err(|e| anyhow!("Failed to send tool offsets: {:?}", e))?;
+                bail!(
+                    "Offsets didn't take after {} attempts, not saved:\n{}",
+                    attempts,
+                    described
+                );
+            }
+            warn!("Offsets didn't take, attempt {}:\n{}", attempts, described);
+            pending = mismatched.into_iter().map(|(c, _)| c).collect();
+        };
+        debug!("Offsets read back after {} attempt(s)", attempts);
+
+        let persistence = self.toolchanger().persistence();
+        let gcode = self.toolchanger_mut().persist_offsets(changes);
+        if !gcode.is_empty() {
+            match self.run_gcode_with_output(&gcode).await {
+                Ok(_) => {}
+                /// SAVE_CONFIG restarts klipper, which may go before it answers
+                Err(e)
+                    if persistence == Persistence::SaveConfig
+                        && (e.kind == GcodeErrorKind::Disconnected
+                            || e.message.contains("Klippy Disconnected")) =>
+                {
+                    debug!("Klipper restarted while saving: {}", e.message);
+                }
+                Err(e) => return Err(anyhow!("Offsets set but not saved: {}", e)),
+            }
+        }
+
+        self.inbox()
+            .send(KlipperMessage::ToolOffsets(offsets.clone()))
+            .map_err(|e| anyhow!("Failed to send tool offsets: {:?}", e))?;
+        self.inbox()
+            .send(KlipperMessage::OffsetsCommitted(CommitReport {
+                changes: changes.to_vec(),
+                offsets,
+                attempts,
+                persistence,
+                unverified,
+            }))
+            .map_err(|e| anyhow!("Failed to send offset commit: {:?}", e))?;
+
+        Ok(())
+    }
+
+    /// Asks the printer if it can tell, otherwise goes by the last tool change
+    async fn get_active_tool(&mut self) -> Result<Option<i32>> {
+        let Some(objects) = self.toolchanger().active_tool_source().objects() else {
+            return Ok(self.status().read().await.active_tool);
+        };
+
+        let res = self.query_objects(objects).await?;
+        let tool = self.toolchanger().active_tool(&res["status"]);
+        if let Some(tool) = tool {
+            self.status()
+                .write()
+                .await
+                .set_active_tool(self.inbox(), tool);
+
+            /// e.g. `T1` typed into the console, the backend may have an offset to apply
+            let gcode = self.toolchanger_mut().mounted_tool(tool);
+            self.run_toolchanger_gcode(&gcode).await?;
+        }
+        Ok(tool)
+    }
+
+    /// Read every tool's offsets and send them to the UI
+    async fn get_offsets(&mut self) -> Result<()> {
+        let offsets = self.read_tool_offsets().await?;
+
+        self.inbox()
+            .send(KlipperMessage::ToolOffsets(offsets))
+            .map_err(|e| anyhow!("Failed to send tool offsets: {:?}", e))?;
+
+        Ok(())
+    }
+
+    /// Klipper refuses to move unhomed axes, catch it before sending anything
+    async fn check_homed(&self, axes: &[Axis]) -> Result<()> {
+        let status = self.status().read().await;
+        let missing: Vec<&str> = axes
+            .iter()
+            .filter(|a| !status.is_homed(**a))
+            .map(|a| a.to_str())
+            .collect();
+        if !missing.is_empty() {
+            bail!("Can't move, {} not homed, home first", missing.join(", "));
+        }
+        Ok(())
+    }
+
+    async fn read_tool_offsets(&mut self) -> Result<Vec<(f64, f64, f64)>> {
+        let status = self.toolchanger_status().await?;
+        self.toolchanger().read_offsets(&status)
+    }
+
+    /// Status of whatever objects the toolchanger backend keeps its state in
+    async fn toolchanger_status(&mut self) -> Result<serde_json::Value> {
+        let objects = self.object_names().await?;
+
+        let query = self.toolchanger().status_objects(&objects);
+        if query.as_object().map_or(true, |q| q.is_empty()) {
+            return Ok(serde_json::json!({}));
+        }
+
+        let res = self.query_objects(query).await?;
+        res.get("status")
+            .cloned()
+            .ok_or_else(|| anyhow!("No status in reply"))
+    }
+
+    /// Some backends have nothing to send for some changes
+    async fn run_toolchanger_gcode(&mut self, gcode: &str) -> Result<()> {
+        if gcode.is_empty() {
+            return Ok(());
+        }
+        self.run_gcode(gcode).await
+    }
+
+    async fn get_variables(&mut self) -> Result<serde_json::Value> {
+        debug!("getting vars");
+        let res = self.query_object("save_variables").await?;
+
+        res.pointer("/status/save_variables/variables")
+            .cloned()
+            .ok_or_else(|| anyhow!("No save_variables in reply"))
+    }
+
+    async fn run_gcode(&mut self, gcode: &str) -> Result<()> {
+        self.run_gcode_with_output(gcode).await?;
+        Ok(())
+    }
+
+    /// Run a script and wait for klipper to finish it.
+    /// Returns the console lines printed while it ran, if the transport gets them.
+    async fn run_gcode_with_output(
+        &mut self,
+        gcode: &str,
+    ) -> std::result::Result<Vec<String>, GcodeError> {
+        let mut rx = self.gcode_responses();
+
+        let params = serde_json::json!({
+            "script": gcode,
+        });
+        let res = self
+            .request_with_timeout("printer.gcode.script", Some(params), GCODE_TIMEOUT)
+            .await;
+
+        /// the listener forwards responses before resolving the reply, so they're all queued by now
+        let mut responses = vec![];
+        while let Some(rx) = rx.as_mut() {
+            match rx.try_recv() {
+                Ok(line) => responses.push(line),
+                Err(tokio::sync::broadcast::error::TryRecvError::Lagged(n)) => {
+                    warn!("Missed {} gcode responses", n);
+                }
+                Err(_) => break,
+            }
+        }
+
+        match res {
+            Ok(_) => Ok(responses),
+            Err(e) => {
+                let e = GcodeError::from_rpc(gcode, e, responses);
+                debug!("{}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Report an error to the UI error list
+    fn send_error(&self, msg: String) {
+        self.inbox()
+            .send(KlipperMessage::KlipperError(msg))
+            .unwrap_or_else(|e| {
+                error!("Failed to send error message: {:?}", e);
+            });
+    }
+}
@@ -0,0 +1,273 @@
+//! Where moonraker is and how to log in, from `printer_url` and `[printer_auth]` in config.toml
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest, handshake::client::Request, http::HeaderValue,
+};
+use url::Url;
+
+/// moonraker's own port, used when `printer_url` doesn't give one and isn't https
+pub const DEFAULT_PORT: u16 = 7125;
+
+/// How requests get to moonraker, `transport = "http"` in config.toml for proxies that block websockets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// status and G-code output are pushed as they change
+    #[default]
+    Websocket,
+    /// status is polled, see [`super::http_client`]
+    Http,
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Websocket => write!(f, "websocket"),
+            Transport::Http => write!(f, "HTTP"),
+        }
+    }
+}
+
+/// Only needed when the printer isn't in moonraker's `trusted_clients`
+#[derive(Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MoonrakerAuth {
+    #[default]
+    None,
+    /// sent as `X-Api-Key` when opening the websocket
+    ApiKey { api_key: String },
+    /// the key is only used to fetch an `access/oneshot_token` before each connection,
+    /// which is passed as `?token=`, for proxies that don't pass the header on
+    OneshotToken { api_key: String },
+}
+
+impl MoonrakerAuth {
+    /// for the HTTP API, which takes the key as a header whichever way the websocket is opened
+    pub fn api_key(&self) -> Option<&str> {
+        match self {
+            MoonrakerAuth::None => None,
+            MoonrakerAuth::ApiKey { api_key } | MoonrakerAuth::OneshotToken { api_key } => {
+                Some(api_key)
+            }
+        }
+    }
+}
+
+/// keep keys out of the logs
+impl std::fmt::Debug for MoonrakerAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoonrakerAuth::None => write!(f, "None"),
+            MoonrakerAuth::ApiKey { .. } => write!(f, "ApiKey"),
+            MoonrakerAuth::OneshotToken { .. } => write!(f, "OneshotToken"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MoonrakerEndpoint {
+    /// `ws(s)://host:port/<prefix>/websocket`
+    pub websocket: Url,
+    /// `http(s)://host:port/<prefix>/`, for the HTTP API
+    pub http: Url,
+    pub auth: MoonrakerAuth,
+}
+
+impl MoonrakerEndpoint {
+    /// Accepts anything from a bare `192.168.0.245` to `wss://host/printer1/websocket`.
+    /// Without a port, http and ws use [`DEFAULT_PORT`], https and wss go through 443.
+    pub fn parse(printer_url: &str, auth: MoonrakerAuth) -> Result<Self> {
+        let printer_url = printer_url.trim();
+        ensure!(!printer_url.is_empty(), "No printer URL set");
+
+        let with_scheme = if printer_url.contains("://") {
+            printer_url.to_string()
+        } else {
+            format!("http://{}", printer_url)
+        };
+
+        let url = Url::parse(&with_scheme)
+            .with_context(|| format!("Invalid printer URL: {:?}", printer_url))?;
+
+        /// `Url` forgets a port that is the scheme's default, so check what was written
+        let port = if has_explicit_port(&with_scheme) {
+            url.port_or_known_default()
+        } else {
+            None
+        };
+
+        Self::from_url_with_port(&url, port, auth)
+    }
+
+    /// Same rules as [`Self::parse`], but `http://host:80` can't be told from `http://host`
+    pub fn from_url(url: &Url, auth: MoonrakerAuth) -> Result<Self> {
+        Self::from_url_with_port(url, url.port(), auth)
+    }
+
+    fn from_url_with_port(url: &Url, port: Option<u16>, auth: MoonrakerAuth) -> Result<Self> {
+        let Some(host) = url.host_str() else {
+            bail!("No host in printer url: {}", url);
+        };
+
+        let tls = match url.scheme() {
+            "http" | "ws" => false,
+            "https" | "wss" => true,
+            s => bail!("Unsupported scheme in printer url: {:?}", s),
+        };
+
+        let port = match port {
+            Some(port) => port,
+            None if tls => 443,
+            None => DEFAULT_PORT,
+        };
+
+        let prefix = url.path().trim_end_matches('/');
+        let prefix = prefix.strip_suffix("/websocket").unwrap_or(prefix);
+
+        let (ws, http) = if tls {
+            ("wss", "https")
+        } else {
+            ("ws", "http")
+        };
+
+        let websocket = Url::parse(&format!("{}://{}:{}{}/websocket", ws, host, port, prefix))?;
+        let http = Url::parse(&format!("{}://{}:{}{}/", http, host, port, prefix))?;
+
+        Ok(Self {
+            websocket,
+            http,
+            auth,
+        })
+    }
+
+    /// Request to open the websocket with, fetching a oneshot token first if needed
+    pub async fn websocket_request(&self) -> Result<Request> {
+        let mut url = self.websocket.clone();
+
+        if let MoonrakerAuth::OneshotToken { api_key } = &self.auth {
+            let token = self.oneshot_token(api_key).await?;
+            url.query_pairs_mut().append_pair("token", &token);
+        }
+
+        let mut req = url.as_str().into_client_request()?;
+
+        if let MoonrakerAuth::ApiKey { api_key } = &self.auth {
+            req.headers_mut().insert(
+                "X-Api-Key",
+                HeaderValue::from_str(api_key).context("Invalid API key")?,
+            );
+        }
+
+        Ok(req)
+    }
+
+    /// Tokens expire after a few seconds, so one is fetched for every connection attempt
+    async fn oneshot_token(&self, api_key: &str) -> Result<String> {
+        let url = self.http.join("access/oneshot_token")?;
+        debug!("Fetching oneshot token from {}", url);
+
+        let res = reqwest::Client::new()
+            .get(url)
+            .header("X-Api-Key", api_key)
+            .timeout(std::time::Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()
+            .context("Failed to get oneshot token")?;
+
+        let json: serde_json::Value = res.json().await?;
+        json["result"]
+            .as_str()
+            .map(|s| s.to_string())
+            .ok_or_else(|| anyhow!("No token in reply: {}", json))
+    }
+}
+
+impl std::fmt::Display for MoonrakerEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.websocket)
+    }
+}
+
+/// `host:port` in the authority of a URL that has a scheme
+fn has_explicit_port(url: &str) -> bool {
+    let Some((_, rest)) = url.split_once("://") else {
+        return false;
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let host_port = authority.rsplit('@').next().unwrap_or("");
+    /// IPv6 hosts are bracketed, so the port is whatever follows `]`
+    let after_host = match host_port.rfind(']') {
+        Some(i) => &host_port[i + 1..],
+        None => host_port,
+    };
+    after_host
+        .rsplit_once(':')
+        .map(|(_, p)| !p.is_empty())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ws(printer_url: &str) -> String {
+        MoonrakerEndpoint::parse(printer_url, MoonrakerAuth::None)
+            .unwrap()
+            .websocket
+            .to_string()
+    }
+
+    #[test]
+    fn parses_printer_urls() {
+        assert_eq!(ws("192.168.0.245"), "ws://192.168.0.245:7125/websocket");
+        assert_eq!(
+            ws("http://192.168.0.245"),
+            "ws://192.168.0.245:7125/websocket"
+        );
+        assert_eq!(
+            ws("http://printer.local:80"),
+            "ws://printer.local/websocket"
+        );
+        assert_eq!(
+            ws("printer.local:7126"),
+            "ws://printer.local:7126/websocket"
+        );
+        assert_eq!(
+            ws("ws://printer.local:7125/websocket"),
+            "ws://printer.local:7125/websocket"
+        );
+        assert_eq!(ws("https://example.com"), "wss://example.com/websocket");
+        assert_eq!(
+            ws("wss://example.com:8443/printer1/"),
+            "wss://example.com:8443/printer1/websocket"
+        );
+        assert_eq!(ws("http://[::1]"), "ws://[::1]:7125/websocket");
+        assert!(MoonrakerEndpoint::parse("", MoonrakerAuth::None).is_err());
+        assert!(MoonrakerEndpoint::parse("ftp://printer", MoonrakerAuth::None).is_err());
+    }
+
+    #[test]
+    fn http_base_keeps_prefix() {
+        let endpoint =
+            MoonrakerEndpoint::parse("https://example.com/printer1", MoonrakerAuth::None).unwrap();
+        assert_eq!(
+            endpoint.http.join("access/oneshot_token").unwrap().as_str(),
+            "https://example.com/printer1/access/oneshot_token"
+        );
+    }
+
+    #[tokio::test]
+    async fn api_key_header() {
+        let auth = MoonrakerAuth::ApiKey {
+            api_key: "abc123".to_string(),
+        };
+        let endpoint = MoonrakerEndpoint::parse("printer.local", auth).unwrap();
+        let req = endpoint.websocket_request().await.unwrap();
+        assert_eq!(req.headers()["X-Api-Key"], "abc123");
+        assert_eq!(format!("{:?}", endpoint.auth), "ApiKey");
+    }
+}
@@ -0,0 +1,84 @@
+//! Extruder heaters. Offsets shift a little with thermal expansion and ooze,
+//! so they can be measured at printing temperature.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+/// `temperature` and `target` of one `extruder`/`extruderN` object
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct HeaterState {
+    pub temperature: f64,
+    pub target: f64,
+}
+
+impl HeaterState {
+    /// status updates only contain the fields that changed
+    pub fn update(&mut self, data: &serde_json::Value) {
+        if let Some(t) = data.get("temperature").and_then(|v| v.as_f64()) {
+            self.temperature = t;
+        }
+        if let Some(t) = data.get("target").and_then(|v| v.as_f64()) {
+            self.target = t;
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.target > 0.
+    }
+
+    /// Heating or cooling towards a target it hasn't reached yet
+    pub fn is_ramping(&self, tolerance: f64) -> bool {
+        self.is_on() && (self.temperature - self.target).abs() > tolerance
+    }
+}
+
+/// `extruder` for T0, `extruder1` for T1, ..., as klipper names them
+pub fn extruder_name(tool: u32) -> String {
+    if tool == 0 {
+        "extruder".to_string()
+    } else {
+        format!("extruder{}", tool)
+    }
+}
+
+/// inverse of [`extruder_name`]
+pub fn tool_for_extruder(object: &str) -> Option<u32> {
+    match object.strip_prefix("extruder")? {
+        "" => Some(0),
+        n => n.parse().ok(),
+    }
+}
+
+/// `extruder`, `extruder1`, but not `extruder_stepper ...`
+pub fn is_extruder(object: &str) -> bool {
+    object
+        .strip_prefix("extruder")
+        .map(|n| n.chars().all(|c| c.is_ascii_digit()))
+        .unwrap_or(false)
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HeatingSettings {
+    /// heat each tool before auto offset measures it
+    pub enabled: bool,
+    pub temperature: f64,
+    /// how close to the target counts as reached, degrees
+    pub tolerance: f64,
+    /// how long it has to stay there before measuring, seconds
+    pub settle_time: f64,
+    /// turn the heaters off when auto offset finishes
+    pub cooldown: bool,
+}
+
+impl Default for HeatingSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            temperature: 200.,
+            tolerance: 2.,
+            settle_time: 10.,
+            cooldown: true,
+        }
+    }
+}
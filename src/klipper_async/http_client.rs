@@ -0,0 +1,310 @@
+//! Moonraker's HTTP API instead of the websocket, for when a proxy won't pass websockets through.
+//! Every JSON-RPC method is also a REST endpoint: `printer.objects.query` is `POST /printer/objects/query`.
+//! Nothing is pushed over HTTP, so the status is polled, and G-code output and remote methods never arrive.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use std::sync::Arc;
+
+use egui_inbox::UiInboxSender;
+use tokio::{sync::RwLock, time::Instant};
+
+use super::{
+    client::PrinterClient,
+    endpoint::MoonrakerEndpoint,
+    limits::SafetySettings,
+    preflight::CompatibilityReport,
+    runner::CommandRunner,
+    toolchanger::{ActiveToolSource, ToolchangerBackend},
+    Interrupt, KlipperCommand, KlipperConn, KlipperMessage, KlipperRpcError, KlipperStatus,
+    KlippyState, RpcResult, KLIPPY_POLL_INTERVAL,
+};
+
+/// How often the status is read while klippy is ready, about as often as klippy sends updates
+pub const HTTP_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+pub struct HttpClient {
+    endpoint: MoonrakerEndpoint,
+    http: reqwest::Client,
+    current_status: Arc<RwLock<KlipperStatus>>,
+    inbox: UiInboxSender<KlipperMessage>,
+    channel_from_ui: tokio::sync::mpsc::Receiver<KlipperCommand>,
+    /// taken by [`CommandRunner::run`]
+    interrupts: Option<tokio::sync::mpsc::UnboundedReceiver<Interrupt>>,
+    toolchanger: Box<dyn ToolchangerBackend>,
+    active_tool_source: ActiveToolSource,
+    compatibility: CompatibilityReport,
+    safety: SafetySettings,
+    /// [`PrinterClient::status_objects`], read every poll once klippy is ready
+    polled_objects: Option<serde_json::Value>,
+    /// the last poll got an answer
+    connected: bool,
+    last_klippy_check: Instant,
+}
+
+impl HttpClient {
+    /// Takes the same channels as [`KlipperConn::new`], doesn't connect until [`CommandRunner::run`]
+    pub async fn new(
+        endpoint: MoonrakerEndpoint,
+        inbox: UiInboxSender<KlipperMessage>,
+        rx: tokio::sync::mpsc::Receiver<KlipperCommand>,
+        interrupts: tokio::sync::mpsc::UnboundedReceiver<Interrupt>,
+        tx_status: tokio::sync::oneshot::Sender<Arc<RwLock<KlipperStatus>>>,
+        toolchanger: Box<dyn ToolchangerBackend>,
+        safety: SafetySettings,
+    ) -> Result<Self> {
+        let current_status = Arc::new(RwLock::new(KlipperStatus::default()));
+
+        tx_status.send(current_status.clone()).unwrap_or_else(|e| {
+            error!("Failed to send status: {:?}", e);
+        });
+
+        let http = reqwest::Client::builder()
+            .build()
+            .context("Failed to build HTTP client")?;
+
+        Ok(Self {
+            endpoint,
+            http,
+            current_status,
+            inbox,
+            channel_from_ui: rx,
+            interrupts: Some(interrupts),
+            active_tool_source: toolchanger.active_tool_source(),
+            toolchanger,
+            compatibility: Default::default(),
+            safety,
+            polled_objects: None,
+            connected: false,
+            last_klippy_check: Instant::now(),
+        })
+    }
+
+    /// Moonraker only takes GET for requests that just read, and POST for ones that change something
+    fn is_post(method: &str, has_params: bool) -> bool {
+        has_params
+            || matches!(
+                method,
+                "printer.emergency_stop" | "printer.restart" | "printer.firmware_restart"
+            )
+    }
+
+    async fn connect(&mut self) -> Result<()> {
+        debug!("Connecting to {}", &self.endpoint.http);
+        self.request("server.info", None)
+            .await
+            .map_err(|e| anyhow!("Failed to reach {}: {}", &self.endpoint.http, e))?;
+        debug!("Connected to {}", &self.endpoint.http);
+
+        self.connected = true;
+        self.current_status.write().await.connected = true;
+        self.inbox
+            .send(KlipperMessage::Connected)
+            .map_err(|e| anyhow!("Failed to send connected message: {:?}", e))?;
+
+        self.last_klippy_check = Instant::now();
+        self.check_klippy().await
+    }
+
+    async fn disconnect(&mut self) {
+        self.connected = false;
+        self.polled_objects = None;
+
+        let mut status = self.current_status.write().await;
+        status.klippy_state = KlippyState::Unknown;
+        status.klippy_message.clear();
+        if status.connected {
+            status.connected = false;
+            self.inbox
+                .send(KlipperMessage::Disconnected)
+                .unwrap_or_else(|e| {
+                    error!("Failed to send disconnected message: {:?}", e);
+                });
+        }
+    }
+
+    /// Read the status while klippy is ready, otherwise ask how it is every [`KLIPPY_POLL_INTERVAL`].
+    /// A failed read usually means klippy went away, which is asked about straight away.
+    async fn poll(&mut self) -> Result<()> {
+        if self.klippy_ready().await {
+            let Some(objects) = self.polled_objects.clone() else {
+                return Ok(());
+            };
+            match self.query_objects(objects).await {
+                Ok(_) => return Ok(()),
+                Err(e) => debug!("Failed to poll status: {}", e),
+            }
+        } else if self.last_klippy_check.elapsed() < KLIPPY_POLL_INTERVAL {
+            return Ok(());
+        }
+
+        self.last_klippy_check = Instant::now();
+        self.check_klippy().await
+    }
+}
+
+impl PrinterClient for HttpClient {
+    async fn request_with_timeout(
+        &mut self,
+        method: &str,
+        params: Option<serde_json::Value>,
+        timeout: std::time::Duration,
+    ) -> RpcResult {
+        let url = self
+            .endpoint
+            .http
+            .join(&method.replace('.', "/"))
+            .map_err(|e| KlipperRpcError::Send(e.to_string()))?;
+
+        let mut req = if Self::is_post(method, params.is_some()) {
+            self.http
+                .post(url)
+                .json(&params.unwrap_or_else(|| serde_json::json!({})))
+        } else {
+            self.http.get(url)
+        };
+        /// the oneshot token is only for opening websockets, the key itself works here
+        if let Some(api_key) = self.endpoint.auth.api_key() {
+            req = req.header("X-Api-Key", api_key);
+        }
+
+        let res = match req.timeout(timeout).send().await {
+            Ok(res) => res,
+            Err(e) if e.is_timeout() => {
+                return Err(KlipperRpcError::Timeout {
+                    method: method.to_string(),
+                    id: 0,
+                })
+            }
+            Err(e) => return Err(KlipperRpcError::Send(e.to_string())),
+        };
+        let json: serde_json::Value = res
+            .json()
+            .await
+            .map_err(|_| KlipperRpcError::ConnectionClosed)?;
+
+        if let Some(err) = json.get("error") {
+            return Err(KlipperRpcError::Rpc {
+                code: err["code"].as_i64().unwrap_or(0),
+                message: err["message"].as_str().unwrap_or("").to_string(),
+            });
+        }
+
+        /// the websocket listener sees every reply, so queries update the status there too
+        self.current_status
+            .write()
+            .await
+            .update_from(&self.inbox, &self.active_tool_source, &json);
+
+        Ok(json
+            .get("result")
+            .cloned()
+            .unwrap_or(serde_json::Value::Null))
+    }
+
+    fn status(&self) -> &Arc<RwLock<KlipperStatus>> {
+        &self.current_status
+    }
+
+    fn inbox(&self) -> &UiInboxSender<KlipperMessage> {
+        &self.inbox
+    }
+
+    fn toolchanger(&self) -> &dyn ToolchangerBackend {
+        self.toolchanger.as_ref()
+    }
+
+    fn toolchanger_mut(&mut self) -> &mut dyn ToolchangerBackend {
+        self.toolchanger.as_mut()
+    }
+
+    fn safety(&self) -> &SafetySettings {
+        &self.safety
+    }
+}
+
+impl CommandRunner for HttpClient {
+    async fn run(&mut self) -> Result<()> {
+        let Some(mut interrupts) = self.interrupts.take() else {
+            bail!("Already running");
+        };
+        let mut poll = tokio::time::interval(HTTP_POLL_INTERVAL);
+        poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+        let mut attempt = 0;
+        loop {
+            if !self.connected {
+                if let Err(e) = self.connect().await {
+                    warn!("Failed to connect to {}: {}", &self.endpoint.http, e);
+                    self.disconnect().await;
+
+                    let delay = KlipperConn::reconnect_delay(attempt);
+                    attempt += 1;
+                    self.inbox
+                        .send(KlipperMessage::Reconnecting(attempt, delay))
+                        .unwrap_or_else(|e| {
+                            error!("Failed to send reconnecting message: {:?}", e);
+                        });
+
+                    if !self.wait_to_reconnect(delay, &mut interrupts).await {
+                        debug!("Channel closed");
+                        return Ok(());
+                    }
+                    continue;
+                }
+                attempt = 0;
+            }
+
+            tokio::select! {
+                cmd = self.channel_from_ui.recv() => {
+                    match cmd {
+                        None => {
+                            debug!("Channel closed");
+                            return Ok(());
+                        }
+                        Some(cmd) => self.run_command(cmd, &mut interrupts).await,
+                    }
+                }
+                Some(interrupt) = interrupts.recv() => {
+                    self.handle_interrupt(interrupt).await;
+                }
+                _ = poll.tick() => {
+                    if let Err(e) = self.poll().await {
+                        warn!("Lost connection to {}: {}", &self.endpoint.http, e);
+                        self.disconnect().await;
+                    }
+                }
+            };
+        }
+    }
+
+    async fn init(&mut self) -> Result<()> {
+        let objects = self.status_objects().await;
+        /// everything once, like the reply to a subscription
+        self.query_objects(objects.clone()).await?;
+        self.polled_objects = Some(objects);
+
+        self.query_object("configfile")
+            .await
+            .map_err(|e| anyhow!("Failed to query object: {:?}", e))?;
+
+        self.query_object("stepper_enable")
+            .await
+            .map_err(|e| anyhow!("Failed to query object: {:?}", e))?;
+
+        Ok(())
+    }
+
+    fn commands(&mut self) -> &mut tokio::sync::mpsc::Receiver<KlipperCommand> {
+        &mut self.channel_from_ui
+    }
+
+    fn compatibility(&self) -> &CompatibilityReport {
+        &self.compatibility
+    }
+
+    fn set_compatibility(&mut self, report: CompatibilityReport) {
+        self.compatibility = report;
+    }
+}
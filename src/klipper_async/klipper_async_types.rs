@@ -1,32 +1,77 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use egui_inbox::UiInboxSender;
 use futures_util::stream::SplitSink;
 use tokio::{net::TcpStream, sync::RwLock, time::Instant};
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
+use super::motion::MoveSpeed;
 use crate::ui::ui_types::Axis;
 
 #[derive(Debug)]
 pub enum KlipperCommand {
     // MovePosition((f64, f64), Option<f64>),
-    MoveToPosition((f64, f64, f64), Option<f64>),
+    /// position, bounce, speed. Z or XY first depending on which way Z is going
+    MoveToPosition((f64, f64, f64), Option<f64>, MoveSpeed),
     // MovePositionRelative((f64, f64), Option<f64>),
-    MoveAxisRelative(Axis, f64, Option<f64>),
+    MoveAxisRelative(Axis, f64, Option<f64>, MoveSpeed),
     HomeXY,
     HomeAll,
+    /// target in °C for the tool's extruder, 0 turns it off
+    SetToolTemperature(u32, f64),
+    /// every extruder off
+    CoolDown,
+    /// typed into the console
+    RunGcode(String),
+    /// `RESPOND`, for macros waiting on a remote method
+    Respond(super::remote::RespondType, String),
+    /// probe each tool and write Z offsets, travel speed
+    CalibrateZ(super::zprobe::ZProbeSettings, Vec<u32>, MoveSpeed),
     GetPosition(tokio::sync::oneshot::Sender<Option<(f64, f64, f64)>>),
     PickTool(u32),
     DropTool,
     AdjustToolOffset(u32, Axis, f64),
     SetToolOffset(u32, Axis, f64),
     GetToolOffsets,
+    /// set every offset, check they read back the same, then save them
+    CommitOffsets(Vec<super::offsets::OffsetChange>),
+    /// None if the toolchanger backend can't tell, -1 if no tool is mounted
+    GetActiveTool(tokio::sync::oneshot::Sender<Option<i32>>),
+    /// check the mounted tool, so the backend catches up with a change made on the printer's side
+    FollowActiveTool,
     DisableMotors,
     WaitForMoves,
     Dwell(u32),
     FetchPosition,
 }
 
+impl KlipperCommand {
+    /// could move the toolhead, the UI waits for these to finish before trusting the camera
+    pub fn moves(&self) -> bool {
+        matches!(
+            self,
+            KlipperCommand::MoveToPosition(..)
+                | KlipperCommand::MoveAxisRelative(..)
+                | KlipperCommand::HomeXY
+                | KlipperCommand::HomeAll
+                | KlipperCommand::RunGcode(_)
+                | KlipperCommand::CalibrateZ(..)
+                | KlipperCommand::PickTool(_)
+                | KlipperCommand::DropTool
+        )
+    }
+}
+
+/// Sent on their own channel so they're seen while a command is still running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    /// `printer.emergency_stop`, klipper shuts down and needs a `FIRMWARE_RESTART`
+    EmergencyStop,
+    /// give up on the running command and drop everything queued behind it.
+    /// G-code already sent to klipper still finishes
+    Abort,
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub enum KlipperMessage {
     Position((f64, f64, f64)),
@@ -37,51 +82,270 @@ pub enum KlipperMessage {
     // ZHeightStale,
     KlipperError(String),
     ToolOffsets(Vec<(f64, f64, f64)>),
+    /// every change was read back and saved
+    OffsetsCommitted(super::offsets::CommitReport),
+    /// the offset commit failed or was dropped, also reported as a `KlipperError`
+    OffsetCommitFailed(String),
     HomingOriginChanged((f64, f64, f64)),
+    Connected,
+    Disconnected,
+    /// attempt number, delay before the next attempt
+    Reconnecting(u32, std::time::Duration),
+    Compatibility(super::preflight::CompatibilityReport),
+    /// tool reported by the printer, -1 if no tool is mounted
+    ActiveToolChanged(i32),
+    /// state and `state_message` from `printer.info`
+    KlippyStateChanged(KlippyState, String),
+    /// (tool, Z offset) written by a Z calibration
+    ZOffsetsMeasured(Vec<(u32, f64)>),
+    /// a line from `notify_gcode_response`, errors start with `!!`
+    GcodeResponse(String),
+    /// a method from [`super::remote::REMOTE_METHODS`], called from a macro, with its keyword arguments
+    RemoteMethodCalled(String, serde_json::Value),
+}
+
+/// Klippy's own state, as moonraker reports it. Nothing but queries works unless it's `Ready`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum KlippyState {
+    /// not asked yet, or the websocket is down
+    #[default]
+    Unknown,
+    Startup,
+    Ready,
+    Shutdown,
+    Error,
+    /// moonraker is up but not connected to klippy
+    Disconnected,
+}
+
+impl KlippyState {
+    pub fn from_str(s: &str) -> Self {
+        match s {
+            "startup" => KlippyState::Startup,
+            "ready" => KlippyState::Ready,
+            "shutdown" => KlippyState::Shutdown,
+            "error" => KlippyState::Error,
+            "disconnected" => KlippyState::Disconnected,
+            _ => KlippyState::Unknown,
+        }
+    }
+
+    pub fn to_str(&self) -> &str {
+        match self {
+            KlippyState::Unknown => "unknown",
+            KlippyState::Startup => "starting up",
+            KlippyState::Ready => "ready",
+            KlippyState::Shutdown => "shutdown",
+            KlippyState::Error => "error",
+            KlippyState::Disconnected => "disconnected",
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self == KlippyState::Ready
+    }
+}
+
+impl std::fmt::Display for KlippyState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_str())
+    }
+}
+
+/// Connection state as seen by the UI, driven by the connection messages above
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ConnectionState {
+    #[default]
+    Connecting,
+    Connected,
+    Disconnected,
+    Reconnecting(u32, std::time::Duration),
 }
 
+/// Error from a JSON-RPC request sent to moonraker
+#[derive(Debug, Clone)]
+pub enum KlipperRpcError {
+    /// No reply with a matching id arrived before the timeout
+    Timeout { method: String, id: usize },
+    /// Moonraker replied with an `error` object
+    Rpc { code: i64, message: String },
+    /// The request could not be sent
+    Send(String),
+    /// The connection closed before a reply arrived
+    ConnectionClosed,
+    /// There is no websocket to send the request on
+    NotConnected,
+}
+
+impl std::fmt::Display for KlipperRpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KlipperRpcError::Timeout { method, id } => {
+                write!(f, "Timed out waiting for reply to {} (id {})", method, id)
+            }
+            KlipperRpcError::Rpc { code, message } => write!(f, "Error {}: {}", code, message),
+            KlipperRpcError::Send(e) => write!(f, "Failed to send request: {}", e),
+            KlipperRpcError::ConnectionClosed => write!(f, "Connection closed"),
+            KlipperRpcError::NotConnected => write!(f, "Not connected"),
+        }
+    }
+}
+
+impl std::error::Error for KlipperRpcError {}
+
+pub type RpcResult = Result<serde_json::Value, KlipperRpcError>;
+
+/// A `printer.gcode.script` call that klipper refused or that never completed
+#[derive(Debug, Clone)]
+pub struct GcodeError {
+    pub script: String,
+    pub kind: GcodeErrorKind,
+    pub message: String,
+    /// lines from `notify_gcode_response` received while the script ran
+    pub responses: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcodeErrorKind {
+    /// "Must home axis first"
+    NotHomed,
+    /// "Move out of range"
+    OutOfRange,
+    /// "Unknown command", usually a missing macro
+    UnknownCommand,
+    /// any other error reported by klipper
+    Klipper,
+    Timeout,
+    Disconnected,
+}
+
+impl GcodeError {
+    pub fn from_rpc(script: &str, error: KlipperRpcError, responses: Vec<String>) -> Self {
+        let (kind, message) = match error {
+            KlipperRpcError::Rpc { message, .. } => {
+                (GcodeErrorKind::from_message(&message), message)
+            }
+            KlipperRpcError::Timeout { .. } => (GcodeErrorKind::Timeout, error.to_string()),
+            KlipperRpcError::Send(_)
+            | KlipperRpcError::ConnectionClosed
+            | KlipperRpcError::NotConnected => (GcodeErrorKind::Disconnected, error.to_string()),
+        };
+        GcodeError {
+            script: script.to_string(),
+            kind,
+            message,
+            responses,
+        }
+    }
+}
+
+impl GcodeErrorKind {
+    pub fn from_message(message: &str) -> Self {
+        if message.starts_with("Must home axis first") {
+            GcodeErrorKind::NotHomed
+        } else if message.starts_with("Move out of range") {
+            GcodeErrorKind::OutOfRange
+        } else if message.starts_with("Unknown command") {
+            GcodeErrorKind::UnknownCommand
+        } else {
+            GcodeErrorKind::Klipper
+        }
+    }
+}
+
+impl std::fmt::Display for GcodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "G-code {:?} failed: {}", self.script, self.message)
+    }
+}
+
+impl std::error::Error for GcodeError {}
+
+/// Requests waiting for a reply, keyed by JSON-RPC id
+pub type PendingRequests =
+    Arc<parking_lot::Mutex<HashMap<usize, tokio::sync::oneshot::Sender<RpcResult>>>>;
+
+pub type WsWrite =
+    SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, tokio_tungstenite::tungstenite::Message>;
+
 pub struct KlipperConn {
-    pub(super) url: String,
-    pub(super) ws_write: SplitSink<
-        WebSocketStream<MaybeTlsStream<TcpStream>>,
-        tokio_tungstenite::tungstenite::Message,
-    >,
+    pub(super) endpoint: super::endpoint::MoonrakerEndpoint,
+    /// None while disconnected
+    pub(super) ws_write: Option<WsWrite>,
+    pub(super) listener: Option<tokio::task::JoinHandle<()>>,
     // ws_read: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
     // current_status: KlipperStatus,
     pub(super) current_status: Arc<RwLock<KlipperStatus>>,
     pub(super) inbox: UiInboxSender<KlipperMessage>,
     // inbox_position: UiInboxSender<(f64, f64, f64)>,
     pub(super) channel_from_ui: tokio::sync::mpsc::Receiver<KlipperCommand>,
+    /// taken by [`KlipperConn::run`](super::KlipperConn::run)
+    pub(super) interrupts: Option<tokio::sync::mpsc::UnboundedReceiver<Interrupt>>,
     pub(super) id: usize,
+    pub(super) pending: PendingRequests,
+    /// every line from `notify_gcode_response`
+    pub(super) gcode_responses: tokio::sync::broadcast::Sender<String>,
+    pub(super) toolchanger: Box<dyn super::toolchanger::ToolchangerBackend>,
+    /// from the last preflight, everything is assumed to work until then
+    pub(super) compatibility: super::preflight::CompatibilityReport,
+    pub(super) active_tool_source: super::toolchanger::ActiveToolSource,
+    /// `notify_klippy_*` from the listener, handled in [`KlipperConn::run`](super::KlipperConn::run)
+    pub(super) klippy_events: tokio::sync::mpsc::UnboundedReceiver<KlippyState>,
+    pub(super) klippy_events_tx: tokio::sync::mpsc::UnboundedSender<KlippyState>,
+    /// keep-out zones, every move is checked against these and the soft limits
+    pub(super) safety: super::limits::SafetySettings,
+    /// every frame in and out, if recording
+    pub(super) recorder: Option<super::recorder::SessionRecorder>,
 }
 
 #[derive(Clone, Debug)]
 pub struct KlipperStatus {
+    pub connected: bool,
+    pub klippy_state: KlippyState,
+    /// why klippy isn't ready, empty when it is
+    pub klippy_message: String,
     pub last_position_update: Instant,
     pub absolute_coordinates: bool,
     pub position: Option<(f64, f64, f64)>,
     pub gcode_position: Option<(f64, f64, f64)>,
-    // pub active_tool: Option<u32>,
+    /// None until the printer reports it, -1 when no tool is mounted
+    pub active_tool: Option<i32>,
     pub homed_axes: (bool, bool, bool),
-    pub vars: Option<(Instant, serde_json::Value)>,
-    pub resolution: f64,
+    /// mm per microstep of each axis, None until `configfile` has been read
+    pub resolution: Option<super::resolution::AxisResolution>,
+    /// soft limits from `configfile`, None until it's been read
+    pub axis_limits: Option<super::limits::AxisLimits>,
+    /// tools the printer defines, sorted by number, empty until `configfile` or `save_variables` has been read
+    pub tools: Vec<super::tools::ToolInfo>,
     pub motors_enabled: (bool, bool, bool),
     pub homing_origin: (f64, f64, f64),
+    /// `extruder`, `extruder1`, ... by object name
+    pub heaters: std::collections::BTreeMap<String, super::heaters::HeaterState>,
+    pub motion: super::motion::LiveMotion,
+    /// commands that [move](KlipperCommand::moves) sent by the UI and not done yet
+    pub moves_queued: usize,
 }
 
 impl Default for KlipperStatus {
     fn default() -> Self {
         KlipperStatus {
+            connected: false,
+            klippy_state: KlippyState::Unknown,
+            klippy_message: String::new(),
             last_position_update: Instant::now(),
             absolute_coordinates: true,
             position: None,
             gcode_position: None,
-            // active_tool: None,
+            active_tool: None,
             homed_axes: (false, false, false),
-            vars: None,
-            resolution: 0.0,
+            resolution: None,
+            axis_limits: None,
+            tools: vec![],
             motors_enabled: (false, false, false),
             homing_origin: (0.0, 0.0, 0.0),
+            heaters: Default::default(),
+            motion: Default::default(),
+            moves_queued: 0,
         }
     }
 }
@@ -0,0 +1,172 @@
+//! Where the toolhead may go: the printer's own soft limits from `configfile`,
+//! plus keep-out zones (tool docks etc.) from config.toml. Moves are checked before being sent.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use crate::ui::ui_types::Axis;
+
+/// `position_min`/`position_max` of each stepper, carriage coordinates
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AxisLimits {
+    pub min: (f64, f64, f64),
+    pub max: (f64, f64, f64),
+}
+
+impl AxisLimits {
+    /// from `configfile.config`, where every value is a string
+    pub fn from_config(config: &serde_json::Value) -> Result<Self> {
+        let get = |stepper: &str, key: &str| -> Result<f64> {
+            config[stepper][key]
+                .as_str()
+                .ok_or_else(|| anyhow!("No {} in [{}]", key, stepper))?
+                .trim()
+                .parse::<f64>()
+                .with_context(|| format!("Failed to parse {} in [{}]", key, stepper))
+        };
+        /// klipper's default when position_min isn't set
+        let get_min = |stepper: &str| get(stepper, "position_min").unwrap_or(0.);
+
+        Ok(Self {
+            min: (
+                get_min("stepper_x"),
+                get_min("stepper_y"),
+                get_min("stepper_z"),
+            ),
+            max: (
+                get("stepper_x", "position_max")?,
+                get("stepper_y", "position_max")?,
+                get("stepper_z", "position_max")?,
+            ),
+        })
+    }
+
+    pub fn check(&self, pos: (f64, f64, f64)) -> Result<()> {
+        for axis in [Axis::X, Axis::Y, Axis::Z] {
+            let (v, min, max) = (get(pos, axis), get(self.min, axis), get(self.max, axis));
+            if v < min || v > max {
+                bail!(
+                    "{} {:.3} is outside the printer's limits ({:.1} to {:.1})",
+                    axis,
+                    v,
+                    min,
+                    max
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Box the toolhead must never travel through, carriage coordinates
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct KeepOutZone {
+    pub name: String,
+    pub min: (f64, f64),
+    pub max: (f64, f64),
+    /// only applies below this height, e.g. a dock that can be passed over
+    #[serde(default)]
+    pub below_z: Option<f64>,
+}
+
+impl KeepOutZone {
+    pub fn contains(&self, pos: (f64, f64, f64)) -> bool {
+        pos.0 >= self.min.0
+            && pos.0 <= self.max.0
+            && pos.1 >= self.min.1
+            && pos.1 <= self.max.1
+            && self.below_z.map(|z| pos.2 < z).unwrap_or(true)
+    }
+
+    /// If the straight line from `from` to `to` passes through the zone
+    pub fn intersects(&self, from: (f64, f64, f64), to: (f64, f64, f64)) -> bool {
+        if let Some(z) = self.below_z {
+            if from.2 >= z && to.2 >= z {
+                return false;
+            }
+        }
+
+        /// Liang-Barsky, clip the segment against the box
+        let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+        let mut t0: f64 = 0.;
+        let mut t1: f64 = 1.;
+        for (p, q) in [
+            (-dx, from.0 - self.min.0),
+            (dx, self.max.0 - from.0),
+            (-dy, from.1 - self.min.1),
+            (dy, self.max.1 - from.1),
+        ] {
+            if p == 0. {
+                if q < 0. {
+                    return false;
+                }
+            } else {
+                let r = q / p;
+                if p < 0. {
+                    t0 = t0.max(r);
+                } else {
+                    t1 = t1.min(r);
+                }
+            }
+        }
+        t0 <= t1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SafetySettings {
+    pub keep_out: Vec<KeepOutZone>,
+    /// largest single move auto-centering may make, in mm. The nozzle should
+    /// already be near the camera, anything bigger is a bad detection
+    pub max_centering_move: f64,
+}
+
+impl Default for SafetySettings {
+    fn default() -> Self {
+        Self {
+            keep_out: vec![],
+            max_centering_move: 2.0,
+        }
+    }
+}
+
+impl SafetySettings {
+    /// Check a straight move, both ends in carriage coordinates
+    pub fn check_move(
+        &self,
+        limits: Option<&AxisLimits>,
+        from: (f64, f64, f64),
+        to: (f64, f64, f64),
+    ) -> Result<()> {
+        if let Some(limits) = limits {
+            limits.check(to)?;
+        }
+        for zone in self.keep_out.iter() {
+            /// already inside, e.g. right after a tool change, let it leave
+            if zone.contains(from) {
+                debug!("Starting inside keep-out zone {:?}", zone.name);
+                continue;
+            }
+            if zone.intersects(from, to) {
+                bail!(
+                    "Move from ({:.2}, {:.2}) to ({:.2}, {:.2}) would pass through keep-out zone {:?}",
+                    from.0,
+                    from.1,
+                    to.0,
+                    to.1,
+                    zone.name
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn get(pos: (f64, f64, f64), axis: Axis) -> f64 {
+    match axis {
+        Axis::X => pos.0,
+        Axis::Y => pos.1,
+        Axis::Z => pos.2,
+    }
+}
@@ -0,0 +1,2305 @@
+//! Minimal moonraker server for testing [`KlipperConn`](super::KlipperConn) over the websocket and [`HttpClient`](super::http_client::HttpClient) over HTTP
+//! without a printer. Only the JSON-RPC methods and G-code the client uses are simulated.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+
+use super::heaters::{extruder_name, HeaterState};
+use super::sim_printer::{SimPrinter, SimSettings};
+use crate::ui::ui_types::Axis;
+
+/// Subscribed object name -> requested fields (None = all)
+type Subscriptions = HashMap<String, Option<Vec<String>>>;
+
+/// klippy sends subscription updates every 250 ms, faster here so tests don't wait on it
+pub const MOCK_STATUS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+#[derive(Debug, Clone)]
+pub struct MockPrinterState {
+    /// toolhead position, before the gcode offset is applied
+    pub position: (f64, f64, f64),
+    /// gcode offset of the active tool
+    pub homing_origin: (f64, f64, f64),
+    pub homed: (bool, bool, bool),
+    pub absolute_coordinates: bool,
+    pub motors_enabled: bool,
+    /// -1 if no tool is mounted
+    pub active_tool: i32,
+    /// tool numbers, `T<n>` macros and extruders are made for each
+    pub tools: Vec<u32>,
+    /// `save_variables` contents
+    pub variables: serde_json::Map<String, Value>,
+    /// raw `configfile.config`, every value is a string like in klipper
+    pub config: Value,
+    /// every G-code line received, in order
+    pub gcode_log: Vec<String>,
+    /// where the nozzles physically are, for the synthetic camera
+    pub sim: SimPrinter,
+    /// as in `server.info`, "ready", "startup", "shutdown", ...
+    pub klippy_state: String,
+    pub state_message: String,
+    /// current `SET_VELOCITY_LIMIT`s
+    pub max_velocity: f64,
+    pub max_accel: f64,
+    /// one extruder per tool. Heating is instant, the temperature jumps to the target
+    pub heaters: std::collections::BTreeMap<String, HeaterState>,
+    /// how much further each nozzle sticks out than T0, `PROBE` triggers this much higher
+    pub nozzle_z_offsets: Vec<f64>,
+    pub last_z_result: f64,
+    /// scripts starting with this are logged but never answered, like a macro that's still running
+    pub hang_on: Option<String>,
+    /// from `connection.register_remote_method`
+    pub remote_methods: Vec<String>,
+    /// `estimated_print_time` counts from here
+    pub clock: std::time::Instant,
+    /// moves are instant, this is when the last one was made
+    pub print_time: f64,
+}
+
+/// carriage Z where `PROBE` triggers for T0
+pub const MOCK_PROBE_TRIGGER_Z: f64 = 5.;
+
+impl Default for MockPrinterState {
+    fn default() -> Self {
+        Self::new(4)
+    }
+}
+
+/// What a request did, so the connection knows what to send back
+struct MockReply {
+    reply: Value,
+    gcode_responses: Vec<String>,
+    status_changed: bool,
+    /// sent to this connection before the reply, e.g. `notify_klippy_shutdown`
+    notifications: Vec<&'static str>,
+}
+
+impl MockPrinterState {
+    pub fn new(num_tools: usize) -> Self {
+        Self::with_tools(&(0..num_tools as u32).collect::<Vec<_>>())
+    }
+
+    /// Tools don't have to start at 0 or be contiguous
+    pub fn with_tools(tools: &[u32]) -> Self {
+        let mut variables = serde_json::Map::new();
+        for t in tools.iter() {
+            for axis in ["x", "y", "z"] {
+                variables.insert(format!("t{}_{}_offset", t, axis), json!(0.0));
+            }
+        }
+
+        let stepper = |min: f64, max: f64, endstop: f64| {
+            json!({
+                "rotation_distance": "40",
+                "microsteps": "16",
+                "full_steps_per_rotation": "200",
+                "position_min": format!("{}", min),
+                "position_max": format!("{}", max),
+                "position_endstop": format!("{}", endstop),
+            })
+        };
+
+        let mut config = json!({
+            "printer": {
+                "kinematics": "corexy",
+                "max_velocity": "300",
+                "max_accel": "3000",
+            },
+            "stepper_x": stepper(0., 300., 300.),
+            "stepper_y": stepper(0., 300., 300.),
+            "stepper_z": stepper(0., 250., 0.),
+            "save_variables": {
+                "filename": "~/variables.cfg",
+            },
+        });
+        /// leadscrew
+        config["stepper_z"]["rotation_distance"] = json!("8");
+
+        let mut macros = vec![
+            "_CLIENT_LINEAR_MOVE".to_string(),
+            "TC_ADJUST_OFFSET".to_string(),
+            "TC_SET_OFFSET".to_string(),
+            "T_1".to_string(),
+        ];
+        macros.extend(tools.iter().map(|t| format!("T{}", t)));
+
+        let mut heaters = std::collections::BTreeMap::new();
+        for t in tools.iter().copied() {
+            let name = extruder_name(t);
+            config[&name] = json!({ "heater_pin": format!("PA{}", t) });
+            heaters.insert(
+                name,
+                HeaterState {
+                    temperature: 25.,
+                    target: 0.,
+                },
+            );
+        }
+        for m in macros {
+            config[format!("gcode_macro {}", m)] = json!({ "gcode": "" });
+        }
+
+        Self {
+            position: (0., 0., 0.),
+            homing_origin: (0., 0., 0.),
+            homed: (false, false, false),
+            absolute_coordinates: true,
+            motors_enabled: false,
+            active_tool: -1,
+            tools: tools.to_vec(),
+            variables,
+            config,
+            gcode_log: vec![],
+            sim: SimPrinter::default(),
+            klippy_state: "ready".to_string(),
+            state_message: "Printer is ready".to_string(),
+            max_velocity: 300.,
+            max_accel: 3000.,
+            heaters,
+            nozzle_z_offsets: vec![0.; tools.iter().max().map_or(0, |t| *t as usize + 1)],
+            last_z_result: 0.,
+            hang_on: None,
+            remote_methods: vec![],
+            clock: std::time::Instant::now(),
+            print_time: 0.,
+        }
+    }
+
+    pub fn with_sim(mut self, settings: SimSettings) -> Self {
+        self.sim = SimPrinter::new(settings);
+        self
+    }
+
+    /// physical XY of the active nozzle
+    pub fn nozzle_position(&self) -> (f64, f64) {
+        self.sim.nozzle_position(self.active_tool)
+    }
+
+    pub fn gcode_position(&self) -> (f64, f64, f64) {
+        (
+            self.position.0 - self.homing_origin.0,
+            self.position.1 - self.homing_origin.1,
+            self.position.2 - self.homing_origin.2,
+        )
+    }
+
+    pub fn tool_offset(&self, tool: i32) -> (f64, f64, f64) {
+        let get = |axis: &str| {
+            self.variables
+                .get(&format!("t{}_{}_offset", tool, axis))
+                .and_then(|v| v.as_f64())
+                .unwrap_or(0.)
+        };
+        (get("x"), get("y"), get("z"))
+    }
+
+    pub fn object_names(&self) -> Vec<String> {
+        let mut out = vec![
+            "gcode_move".to_string(),
+            "toolhead".to_string(),
+            "motion_report".to_string(),
+            "stepper_enable".to_string(),
+            "configfile".to_string(),
+            "save_variables".to_string(),
+            "probe".to_string(),
+        ];
+        out.extend(self.heaters.keys().cloned());
+        if let Some(config) = self.config.as_object() {
+            out.extend(
+                config
+                    .keys()
+                    .filter(|k| k.starts_with("gcode_macro "))
+                    .cloned(),
+            );
+        }
+        out
+    }
+
+    fn estimated_print_time(&self) -> f64 {
+        self.clock.elapsed().as_secs_f64()
+    }
+
+    fn homed_axes(&self) -> String {
+        let mut s = String::new();
+        for (homed, c) in [
+            (self.homed.0, 'x'),
+            (self.homed.1, 'y'),
+            (self.homed.2, 'z'),
+        ] {
+            if homed {
+                s.push(c);
+            }
+        }
+        s
+    }
+
+    pub fn object_status(&self, name: &str) -> Option<Value> {
+        let (x, y, z) = self.position;
+        let (gx, gy, gz) = self.gcode_position();
+        let (ox, oy, oz) = self.homing_origin;
+        let out = match name {
+            "gcode_move" => json!({
+                "homing_origin": [ox, oy, oz, 0.0],
+                "position": [x, y, z, 0.0],
+                "gcode_position": [gx, gy, gz, 0.0],
+                "absolute_coordinates": self.absolute_coordinates,
+                "speed_factor": 1.0,
+                "extrude_factor": 1.0,
+            }),
+            "toolhead" => json!({
+                "homed_axes": self.homed_axes(),
+                "position": [x, y, z, 0.0],
+                "status": "Ready",
+                "max_velocity": self.max_velocity,
+                "max_accel": self.max_accel,
+                "print_time": self.print_time,
+                "estimated_print_time": self.estimated_print_time(),
+            }),
+            "motion_report" => json!({
+                "live_position": [x, y, z, 0.0],
+                "live_velocity": 0.0,
+                "live_extruder_velocity": 0.0,
+            }),
+            "stepper_enable" => json!({
+                "steppers": {
+                    "stepper_x": self.motors_enabled,
+                    "stepper_y": self.motors_enabled,
+                    "stepper_z": self.motors_enabled,
+                }
+            }),
+            "configfile" => json!({
+                "config": self.config,
+            }),
+            "save_variables" => json!({
+                "variables": self.variables,
+            }),
+            "probe" => json!({
+                "last_z_result": self.last_z_result,
+            }),
+            _ if self.heaters.contains_key(name) => {
+                let h = self.heaters[name];
+                json!({
+                    "temperature": h.temperature,
+                    "target": h.target,
+                })
+            }
+            _ if self.config.get(name).is_some() && name.starts_with("gcode_macro ") => json!({}),
+            _ => return None,
+        };
+        Some(out)
+    }
+
+    /// Status for the requested objects, filtered to the requested fields
+    fn query(&self, objects: &Subscriptions) -> Value {
+        let mut status = serde_json::Map::new();
+        for (name, fields) in objects {
+            let Some(mut obj) = self.object_status(name) else {
+                continue;
+            };
+            if let (Some(fields), Some(map)) = (fields, obj.as_object_mut()) {
+                map.retain(|k, _| fields.contains(k));
+            }
+            status.insert(name.clone(), obj);
+        }
+        Value::Object(status)
+    }
+
+    fn handle_request(&mut self, req: &Value, subs: &mut Subscriptions) -> Option<MockReply> {
+        let id = req.get("id")?.clone();
+        let method = req["method"].as_str().unwrap_or("");
+
+        let mut gcode_responses = vec![];
+        let mut status_changed = false;
+        let mut notifications = vec![];
+
+        let ready = self.klippy_state == "ready";
+
+        let result: Result<Value, (i64, String)> = match method {
+            "server.info" => Ok(json!({
+                "klippy_connected": self.klippy_state != "disconnected",
+                "klippy_state": self.klippy_state,
+            })),
+            "printer.info" if self.klippy_state == "disconnected" => {
+                Err((503, "Klippy Disconnected".to_string()))
+            }
+            "printer.info" => Ok(json!({
+                "state": self.klippy_state,
+                "state_message": self.state_message,
+            })),
+            "server.connection.identify" => Ok(json!({ "connection_id": 1 })),
+            "connection.register_remote_method" => match req["params"]["method_name"].as_str() {
+                Some(name) => {
+                    self.remote_methods.push(name.to_string());
+                    Ok(json!("ok"))
+                }
+                None => Err((400, "No method_name".to_string())),
+            },
+            "printer.emergency_stop" => {
+                self.klippy_state = "shutdown".to_string();
+                self.state_message = "Shutdown due to webhooks request".to_string();
+                self.homed = (false, false, false);
+                self.heaters.values_mut().for_each(|h| h.target = 0.);
+                notifications.push("notify_klippy_shutdown");
+                Ok(json!("ok"))
+            }
+            "printer.gcode.script" if !ready => {
+                Err((503, format!("Klippy is {}", self.klippy_state)))
+            }
+            "printer.objects.list" => Ok(json!({ "objects": self.object_names() })),
+            "printer.objects.query" | "printer.objects.subscribe" => {
+                let objects = parse_objects(&req["params"]["objects"]);
+                if method == "printer.objects.subscribe" {
+                    *subs = objects.clone();
+                }
+                Ok(json!({
+                    "eventtime": 0.0,
+                    "status": self.query(&objects),
+                }))
+            }
+            "printer.gcode.script" => {
+                let script = req["params"]["script"].as_str().unwrap_or("");
+                if matches!(&self.hang_on, Some(h) if script.starts_with(h.as_str())) {
+                    self.gcode_log.push(script.to_string());
+                    return None;
+                }
+                status_changed = true;
+                match self.run_script(script, &mut gcode_responses) {
+                    Ok(()) => Ok(json!("ok")),
+                    Err(e) => {
+                        gcode_responses.push(format!("!! {}", e));
+                        Err((400, e))
+                    }
+                }
+            }
+            _ => Err((-32601, "Method not found".to_string())),
+        };
+
+        let reply = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "error": { "code": code, "message": message },
+                "id": id,
+            }),
+        };
+
+        Some(MockReply {
+            reply,
+            gcode_responses,
+            status_changed,
+            notifications,
+        })
+    }
+
+    pub fn run_script(
+        &mut self,
+        script: &str,
+        responses: &mut Vec<String>,
+    ) -> std::result::Result<(), String> {
+        for line in script.lines() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            self.gcode_log.push(line.to_string());
+            self.run_line(line, responses)?;
+        }
+        Ok(())
+    }
+
+    fn run_line(
+        &mut self,
+        line: &str,
+        responses: &mut Vec<String>,
+    ) -> std::result::Result<(), String> {
+        let mut words = line.split_whitespace();
+        let cmd = words.next().unwrap_or("").to_uppercase();
+        let args: Vec<&str> = words.collect();
+
+        match cmd.as_str() {
+            "G90" => self.absolute_coordinates = true,
+            "G91" => self.absolute_coordinates = false,
+            "M400" | "G4" | "SAVE_GCODE_STATE" | "RESTORE_GCODE_STATE" => {}
+            "RESPOND" => {
+                let params = parse_params(&args);
+                let prefix = match params.get("TYPE").copied().unwrap_or("echo") {
+                    "echo" => "echo:",
+                    "command" => "//",
+                    "error" => "!!",
+                    t => return Err(format!("RESPOND TYPE '{}' is invalid", t)),
+                };
+                /// the message has spaces, so not through parse_params
+                let msg = line
+                    .split_once("MSG=")
+                    .map(|(_, m)| m.trim().trim_matches('"'))
+                    .unwrap_or("");
+                responses.push(format!("{} {}", prefix, msg));
+            }
+            "PROBE" => {
+                if !self.homed.2 {
+                    return Err("Must home before probe".to_string());
+                }
+                let tool = self.active_tool.max(0) as usize;
+                let z =
+                    MOCK_PROBE_TRIGGER_Z + self.nozzle_z_offsets.get(tool).copied().unwrap_or(0.);
+                self.position.2 = z;
+                self.last_z_result = z;
+                responses.push(format!(
+                    "// probe at {:.3},{:.3} is z={:.6}",
+                    self.position.0, self.position.1, z
+                ));
+            }
+            "SET_HEATER_TEMPERATURE" => {
+                let params = parse_params(&args);
+                let name = params
+                    .get("HEATER")
+                    .ok_or_else(|| format!("Error on '{}': missing HEATER", line))?;
+                let target = params
+                    .get("TARGET")
+                    .map(|v| v.parse::<f64>().map_err(|e| e.to_string()))
+                    .transpose()?
+                    .unwrap_or(0.);
+                let Some(heater) = self.heaters.get_mut(*name) else {
+                    return Err(format!("Unknown heater '{}'", name));
+                };
+                heater.target = target;
+                heater.temperature = if target > 0. { target } else { 25. };
+            }
+            "SET_VELOCITY_LIMIT" => {
+                let params = parse_params(&args);
+                let get = |key: &str| params.get(key).map(|v| v.parse::<f64>());
+                if let Some(v) = get("VELOCITY") {
+                    self.max_velocity = v.map_err(|e| e.to_string())?;
+                }
+                if let Some(a) = get("ACCEL") {
+                    self.max_accel = a.map_err(|e| e.to_string())?;
+                }
+            }
+            "M18" | "M84" => {
+                self.motors_enabled = false;
+                self.homed = (false, false, false);
+            }
+            "G28" => {
+                let axes = parse_words(&args);
+                let all = !["X", "Y", "Z"].iter().any(|a| axes.contains_key(*a));
+                self.motors_enabled = true;
+                for axis in [Axis::X, Axis::Y, Axis::Z] {
+                    if all || axes.contains_key(axis.to_str()) {
+                        let endstop = self.config_f64(axis, "position_endstop").unwrap_or(0.);
+                        self.set_axis(axis, endstop);
+                        self.set_homed(axis, true);
+                    }
+                }
+                if all || axes.contains_key("X") || axes.contains_key("Y") {
+                    self.sim.home((self.position.0, self.position.1));
+                }
+            }
+            "G0" | "G1" => {
+                let words = parse_words(&args);
+                let mut target = self.gcode_position();
+                for axis in [Axis::X, Axis::Y, Axis::Z] {
+                    if let Some(v) = words.get(axis.to_str()) {
+                        let v = v.parse::<f64>().map_err(|e| e.to_string())?;
+                        let cur = get_axis(target, axis);
+                        set_axis(
+                            &mut target,
+                            axis,
+                            if self.absolute_coordinates {
+                                v
+                            } else {
+                                cur + v
+                            },
+                        );
+                    }
+                }
+                self.move_gcode(target)?;
+            }
+            "_CLIENT_LINEAR_MOVE" => {
+                let params = parse_params(&args);
+                let mut target = self.gcode_position();
+                for axis in [Axis::X, Axis::Y, Axis::Z] {
+                    if let Some(v) = params.get(axis.to_str()) {
+                        let v = v.parse::<f64>().map_err(|e| e.to_string())?;
+                        set_axis(&mut target, axis, get_axis(target, axis) + v);
+                    }
+                }
+                self.move_gcode(target)?;
+            }
+            "SAVE_VARIABLE" => {
+                let params = parse_params(&args);
+                let (Some(name), Some(value)) = (params.get("VARIABLE"), params.get("VALUE"))
+                else {
+                    return Err(format!("Error on '{}': missing VARIABLE or VALUE", line));
+                };
+                let value = match value.parse::<f64>() {
+                    Ok(v) => json!(v),
+                    Err(_) => json!(value.trim_matches(|c| c == '"' || c == '\'')),
+                };
+                self.variables.insert(name.to_lowercase(), value);
+            }
+            "SET_GCODE_OFFSET" => {
+                let params = parse_params(&args);
+                for axis in [Axis::X, Axis::Y, Axis::Z] {
+                    if let Some(v) = params.get(axis.to_str()) {
+                        let v = v.parse::<f64>().map_err(|e| e.to_string())?;
+                        set_axis(&mut self.homing_origin, axis, v);
+                    }
+                }
+            }
+            "T_1" => {
+                self.active_tool = -1;
+                self.homing_origin = (0., 0., 0.);
+                self.sim.drop_tool();
+            }
+            "TC_SET_OFFSET" | "TC_ADJUST_OFFSET" => {
+                let params = parse_params(&args);
+                let tool = params
+                    .get("TOOL")
+                    .and_then(|v| v.parse::<i32>().ok())
+                    .ok_or_else(|| format!("Error on '{}': missing TOOL", line))?;
+                let axis = params
+                    .get("AXIS")
+                    .map(|v| v.to_lowercase())
+                    .ok_or_else(|| format!("Error on '{}': missing AXIS", line))?;
+                let amount = params
+                    .get("AMOUNT")
+                    .and_then(|v| v.parse::<f64>().ok())
+                    .ok_or_else(|| format!("Error on '{}': missing AMOUNT", line))?;
+
+                /// like the real macros, tools that don't exist are quietly ignored
+                if !self.tools.contains(&(tool as u32)) {
+                    return Ok(());
+                }
+
+                let key = format!("t{}_{}_offset", tool, axis);
+                let prev = self
+                    .variables
+                    .get(&key)
+                    .and_then(|v| v.as_f64())
+                    .unwrap_or(0.);
+                let new = if cmd == "TC_SET_OFFSET" {
+                    amount
+                } else {
+                    prev + amount
+                };
+                self.variables.insert(key, json!(new));
+
+                if tool == self.active_tool {
+                    self.homing_origin = self.tool_offset(tool);
+                }
+            }
+            c if c.starts_with('T') && c[1..].parse::<usize>().is_ok() => {
+                let tool = c[1..].parse::<usize>().unwrap();
+                if !self.tools.contains(&(tool as u32)) {
+                    return Err(format!("Unknown command:\"{}\"", c));
+                }
+                self.active_tool = tool as i32;
+                self.homing_origin = self.tool_offset(tool as i32);
+                self.sim.pick_tool();
+                responses.push(format!("// Tool {} selected", tool));
+            }
+            _ => return Err(format!("Unknown command:\"{}\"", cmd)),
+        }
+
+        Ok(())
+    }
+
+    /// move to a position in gcode coordinates
+    fn move_gcode(&mut self, target: (f64, f64, f64)) -> std::result::Result<(), String> {
+        let (ox, oy, oz) = self.homing_origin;
+        let pos = (target.0 + ox, target.1 + oy, target.2 + oz);
+
+        for axis in [Axis::X, Axis::Y, Axis::Z] {
+            if get_axis(pos, axis) == get_axis(self.position, axis) {
+                continue;
+            }
+            if !self.is_homed(axis) {
+                return Err(format!(
+                    "Must home axis first: {:.3} {:.3} {:.3} [0.000]",
+                    pos.0, pos.1, pos.2
+                ));
+            }
+            let min = self.config_f64(axis, "position_min").unwrap_or(f64::MIN);
+            let max = self.config_f64(axis, "position_max").unwrap_or(f64::MAX);
+            let v = get_axis(pos, axis);
+            if v < min || v > max {
+                return Err(format!(
+                    "Move out of range: {:.3} {:.3} {:.3} [0.000]",
+                    pos.0, pos.1, pos.2
+                ));
+            }
+        }
+
+        self.position = pos;
+        self.print_time = self.estimated_print_time();
+        self.sim.move_to((pos.0, pos.1));
+        Ok(())
+    }
+
+    fn config_f64(&self, axis: Axis, key: &str) -> Option<f64> {
+        let section = format!("stepper_{}", axis.to_str().to_lowercase());
+        self.config[section][key].as_str()?.parse().ok()
+    }
+
+    fn set_axis(&mut self, axis: Axis, v: f64) {
+        set_axis(&mut self.position, axis, v);
+    }
+
+    fn is_homed(&self, axis: Axis) -> bool {
+        match axis {
+            Axis::X => self.homed.0,
+            Axis::Y => self.homed.1,
+            Axis::Z => self.homed.2,
+        }
+    }
+
+    fn set_homed(&mut self, axis: Axis, homed: bool) {
+        match axis {
+            Axis::X => self.homed.0 = homed,
+            Axis::Y => self.homed.1 = homed,
+            Axis::Z => self.homed.2 = homed,
+        }
+    }
+}
+
+fn get_axis(pos: (f64, f64, f64), axis: Axis) -> f64 {
+    match axis {
+        Axis::X => pos.0,
+        Axis::Y => pos.1,
+        Axis::Z => pos.2,
+    }
+}
+
+fn set_axis(pos: &mut (f64, f64, f64), axis: Axis, v: f64) {
+    match axis {
+        Axis::X => pos.0 = v,
+        Axis::Y => pos.1 = v,
+        Axis::Z => pos.2 = v,
+    }
+}
+
+/// "X10 Y20" -> {"X": "10", "Y": "20"}
+fn parse_words<'a>(args: &[&'a str]) -> HashMap<String, &'a str> {
+    args.iter()
+        .filter(|w| !w.is_empty())
+        .map(|w| (w[..1].to_uppercase(), &w[1..]))
+        .collect()
+}
+
+/// "TOOL=1 AXIS=X" -> {"TOOL": "1", "AXIS": "X"}
+fn parse_params<'a>(args: &[&'a str]) -> HashMap<String, &'a str> {
+    args.iter()
+        .filter_map(|w| w.split_once('='))
+        .map(|(k, v)| (k.to_uppercase(), v))
+        .collect()
+}
+
+/// The fields of `status` that differ from `sent`, which is brought up to date.
+/// None if nothing changed
+fn changed_fields(sent: &mut serde_json::Map<String, Value>, status: Value) -> Option<Value> {
+    let Value::Object(status) = status else {
+        return None;
+    };
+    let mut out = serde_json::Map::new();
+    for (name, obj) in status {
+        let Value::Object(fields) = obj else {
+            continue;
+        };
+        let prev = sent.entry(name.clone()).or_insert_with(|| json!({}));
+        for (key, value) in fields {
+            if prev.get(&key) == Some(&value) {
+                continue;
+            }
+            prev[&key] = value.clone();
+            out.entry(name.clone()).or_insert_with(|| json!({}))[&key] = value;
+        }
+    }
+    if out.is_empty() {
+        None
+    } else {
+        Some(Value::Object(out))
+    }
+}
+
+fn parse_objects(objects: &Value) -> Subscriptions {
+    let Some(objects) = objects.as_object() else {
+        return Subscriptions::new();
+    };
+    objects
+        .iter()
+        .map(|(name, fields)| {
+            let fields = fields.as_array().map(|fields| {
+                fields
+                    .iter()
+                    .filter_map(|f| f.as_str().map(|s| s.to_string()))
+                    .collect()
+            });
+            (name.clone(), fields)
+        })
+        .collect()
+}
+
+pub struct MockMoonraker {
+    pub addr: SocketAddr,
+    pub state: Arc<parking_lot::Mutex<MockPrinterState>>,
+    server: tokio::task::JoinHandle<()>,
+    connections: Arc<parking_lot::Mutex<Vec<tokio::task::JoinHandle<()>>>>,
+    /// notifications sent to every connection
+    notify: tokio::sync::broadcast::Sender<Value>,
+}
+
+impl MockMoonraker {
+    /// Listen on a free localhost port
+    pub async fn start(state: MockPrinterState) -> Result<Self> {
+        Self::start_shared(Arc::new(parking_lot::Mutex::new(state))).await
+    }
+
+    /// Same as [`Self::start`], for when something else (the synthetic camera) also needs the state
+    pub async fn start_shared(state: Arc<parking_lot::Mutex<MockPrinterState>>) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        debug!("Mock moonraker listening on {}", addr);
+
+        let connections: Arc<parking_lot::Mutex<Vec<tokio::task::JoinHandle<()>>>> =
+            Default::default();
+
+        let notify = tokio::sync::broadcast::channel(16).0;
+
+        let state2 = state.clone();
+        let connections2 = connections.clone();
+        let notify2 = notify.clone();
+        let server = tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("Mock moonraker accept failed: {}", e);
+                        continue;
+                    }
+                };
+                trace!("Mock moonraker connection from {}", peer);
+                let state = state2.clone();
+                let notify = notify2.subscribe();
+                connections2.lock().push(tokio::spawn(async move {
+                    if let Err(e) = Self::handle_connection(stream, state, notify).await {
+                        debug!("Mock moonraker connection closed: {}", e);
+                    }
+                }));
+            }
+        });
+
+        Ok(Self {
+            addr,
+            state,
+            server,
+            connections,
+            notify,
+        })
+    }
+
+    /// printer url as the user would enter it
+    pub fn url(&self) -> url::Url {
+        url::Url::parse(&format!("http://{}:{}", self.addr.ip(), self.addr.port())).unwrap()
+    }
+
+    /// Close every open websocket, as if moonraker restarted
+    pub fn drop_connections(&self) {
+        for conn in self.connections.lock().drain(..) {
+            conn.abort();
+        }
+    }
+
+    /// Like `FIRMWARE_RESTART`, klippy goes away and comes back unhomed, in "startup".
+    /// Subscriptions are lost, see [`Self::klippy_ready`]
+    pub fn restart_klippy(&self) {
+        {
+            let mut state = self.state.lock();
+            state.klippy_state = "startup".to_string();
+            state.state_message = "Printer is not ready".to_string();
+            state.homed = (false, false, false);
+            state.active_tool = -1;
+        }
+        self.send_notification("notify_klippy_disconnected");
+    }
+
+    pub fn klippy_ready(&self) {
+        {
+            let mut state = self.state.lock();
+            state.klippy_state = "ready".to_string();
+            state.state_message = "Printer is ready".to_string();
+        }
+        self.send_notification("notify_klippy_ready");
+    }
+
+    pub fn shutdown_klippy(&self, message: &str) {
+        {
+            let mut state = self.state.lock();
+            state.klippy_state = "shutdown".to_string();
+            state.state_message = message.to_string();
+        }
+        self.send_notification("notify_klippy_shutdown");
+    }
+
+    /// Like a macro running `action_call_remote_method`
+    pub fn call_remote_method(&self, method: &str, params: Value) {
+        let _ = self.notify.send(json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+        }));
+    }
+
+    fn send_notification(&self, method: &str) {
+        /// no receivers if nobody is connected
+        let _ = self.notify.send(json!({
+            "jsonrpc": "2.0",
+            "method": method,
+        }));
+    }
+
+    /// Websocket if the client asks to upgrade, otherwise a single HTTP API request
+    async fn handle_connection(
+        stream: TcpStream,
+        state: Arc<parking_lot::Mutex<MockPrinterState>>,
+        notify: tokio::sync::broadcast::Receiver<Value>,
+    ) -> Result<()> {
+        let mut buf = [0u8; 4096];
+        let head = loop {
+            let n = stream.peek(&mut buf).await?;
+            ensure!(n > 0, "Connection closed before request");
+            let head = String::from_utf8_lossy(&buf[..n]).to_ascii_lowercase();
+            if head.contains("\r\n\r\n") || n == buf.len() {
+                break head;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        };
+
+        if head.contains("upgrade: websocket") {
+            Self::handle_websocket(stream, state, notify).await
+        } else {
+            Self::handle_http(stream, state).await
+        }
+    }
+
+    /// `POST /printer/objects/query` is handled as `printer.objects.query`, without subscriptions or notifications
+    async fn handle_http(
+        stream: TcpStream,
+        state: Arc<parking_lot::Mutex<MockPrinterState>>,
+    ) -> Result<()> {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::io::BufReader::new(stream);
+
+        let mut request_line = String::new();
+        stream.read_line(&mut request_line).await?;
+        let path = request_line
+            .split_whitespace()
+            .nth(1)
+            .ok_or_else(|| anyhow!("Invalid request line: {:?}", request_line))?;
+        let path = path.split('?').next().unwrap_or("");
+        let method = path.trim_matches('/').replace('/', ".");
+
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await?;
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((k, v)) = line.split_once(':') {
+                if k.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = v.trim().parse()?;
+                }
+            }
+        }
+
+        let mut body = vec![0u8; content_length];
+        stream.read_exact(&mut body).await?;
+        let params: Value = if body.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_slice(&body)?
+        };
+
+        let req = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 0 });
+        let reply = state.lock().handle_request(&req, &mut Subscriptions::new());
+        /// a hung script never gets an answer, until the client gives up
+        let Some(reply) = reply
+        else {
+            return std::future::pending().await;
+        };
+
+        let (code, body) = match reply.reply.get("error") {
+            Some(err) => {
+                let code = err["code"].as_i64().unwrap_or(400);
+                let code = if (400..600).contains(&code) {
+                    code
+                } else {
+                    400
+                };
+                (code, json!({ "error": err }))
+            }
+            None => (200, json!({ "result": reply.reply["result"] })),
+        };
+        let body = body.to_string();
+
+        let response = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            code,
+            body.len(),
+            body
+        );
+        stream.get_mut().write_all(response.as_bytes()).await?;
+        stream.get_mut().shutdown().await?;
+
+        Ok(())
+    }
+
+    async fn handle_websocket(
+        stream: TcpStream,
+        state: Arc<parking_lot::Mutex<MockPrinterState>>,
+        mut notify: tokio::sync::broadcast::Receiver<Value>,
+    ) -> Result<()> {
+        let ws = tokio_tungstenite::accept_async(stream).await?;
+        let (mut write, mut read) = ws.split();
+
+        let mut subs = Subscriptions::new();
+        /// what the client was last sent, updates only carry what changed
+        let mut sent = serde_json::Map::new();
+        let mut tick = tokio::time::interval(MOCK_STATUS_INTERVAL);
+
+        loop {
+            let msg = tokio::select! {
+                msg = read.next() => msg,
+                Ok(note) = notify.recv() => {
+                    if note["method"] == "notify_klippy_disconnected" {
+                        subs.clear();
+                        sent.clear();
+                    }
+                    write.send(Message::Text(note.to_string().into())).await?;
+                    continue;
+                }
+                _ = tick.tick() => {
+                    let update = if subs.is_empty() {
+                        None
+                    } else {
+                        changed_fields(&mut sent, state.lock().query(&subs))
+                    };
+                    if let Some(update) = update {
+                        let msg = json!({
+                            "jsonrpc": "2.0",
+                            "method": "notify_status_update",
+                            "params": [update, 0.0],
+                        });
+                        write.send(Message::Text(msg.to_string().into())).await?;
+                    }
+                    continue;
+                }
+            };
+            let Some(msg) = msg else {
+                break;
+            };
+            let text = match msg? {
+                Message::Text(text) => text,
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            let req: Value = serde_json::from_str(text.as_str())?;
+
+            let (reply, update) = {
+                let mut state = state.lock();
+                let Some(reply) = state.handle_request(&req, &mut subs) else {
+                    continue;
+                };
+                if req["method"] == "printer.objects.subscribe" {
+                    /// the reply has everything
+                    sent.clear();
+                    changed_fields(&mut sent, state.query(&subs));
+                }
+                let update = if reply.status_changed && !subs.is_empty() {
+                    changed_fields(&mut sent, state.query(&subs))
+                } else {
+                    None
+                };
+                (reply, update)
+            };
+
+            for method in reply.notifications {
+                let msg = json!({
+                    "jsonrpc": "2.0",
+                    "method": method,
+                });
+                write.send(Message::Text(msg.to_string().into())).await?;
+            }
+
+            for line in reply.gcode_responses {
+                let msg = json!({
+                    "jsonrpc": "2.0",
+                    "method": "notify_gcode_response",
+                    "params": [line],
+                });
+                write.send(Message::Text(msg.to_string().into())).await?;
+            }
+
+            if let Some(update) = update {
+                let msg = json!({
+                    "jsonrpc": "2.0",
+                    "method": "notify_status_update",
+                    "params": [update, 0.0],
+                });
+                write.send(Message::Text(msg.to_string().into())).await?;
+            }
+
+            write
+                .send(Message::Text(reply.reply.to_string().into()))
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for MockMoonraker {
+    fn drop(&mut self) {
+        self.server.abort();
+        self.drop_connections();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::klipper_async::{
+        endpoint::{MoonrakerEndpoint, Transport},
+        http_client::HttpClient,
+        motion::MoveSpeed,
+        offsets::{OffsetChange, COMMIT_ATTEMPTS},
+        preflight::Feature,
+        recorder::{self, SessionRecorder},
+        remote,
+        toolchanger::{ActiveToolSource, Persistence, ToolchangerConfig},
+        zprobe::{self, ZProbeSettings},
+        CommandRunner, Interrupt, KlipperCommand, KlipperConn, KlipperMessage, KlipperStatus,
+        KlippyState,
+    };
+    use tokio::sync::RwLock;
+
+    struct TestClient {
+        tx: tokio::sync::mpsc::Sender<KlipperCommand>,
+        interrupts: tokio::sync::mpsc::UnboundedSender<Interrupt>,
+        inbox: egui_inbox::UiInbox<KlipperMessage>,
+        status: Arc<RwLock<KlipperStatus>>,
+        messages: Vec<KlipperMessage>,
+    }
+
+    impl TestClient {
+        async fn connect(server: &MockMoonraker) -> Self {
+            Self::connect_with(server, ToolchangerConfig::default()).await
+        }
+
+        async fn connect_with(server: &MockMoonraker, toolchanger: ToolchangerConfig) -> Self {
+            Self::start(server, toolchanger, None, Transport::Websocket).await
+        }
+
+        async fn connect_recorded(server: &MockMoonraker, recorder: SessionRecorder) -> Self {
+            Self::start(
+                server,
+                ToolchangerConfig::default(),
+                Some(recorder),
+                Transport::Websocket,
+            )
+            .await
+        }
+
+        async fn connect_http(server: &MockMoonraker) -> Self {
+            Self::start(server, ToolchangerConfig::default(), None, Transport::Http).await
+        }
+
+        async fn start(
+            server: &MockMoonraker,
+            toolchanger: ToolchangerConfig,
+            recorder: Option<SessionRecorder>,
+            transport: Transport,
+        ) -> Self {
+            let inbox = egui_inbox::UiInbox::new();
+            let (tx, rx) = tokio::sync::mpsc::channel(16);
+            let (interrupts, rx_interrupts) = tokio::sync::mpsc::unbounded_channel();
+            let (tx_status, rx_status) = tokio::sync::oneshot::channel();
+
+            let endpoint = MoonrakerEndpoint::from_url(&server.url(), Default::default()).unwrap();
+            match transport {
+                Transport::Websocket => {
+                    let mut conn = KlipperConn::new(
+                        endpoint,
+                        inbox.sender(),
+                        rx,
+                        rx_interrupts,
+                        tx_status,
+                        toolchanger.backend(),
+                        Default::default(),
+                    )
+                    .await
+                    .unwrap();
+                    if let Some(recorder) = recorder {
+                        conn = conn.with_recorder(recorder);
+                    }
+                    tokio::spawn(async move { conn.run().await });
+                }
+                Transport::Http => {
+                    let mut conn = HttpClient::new(
+                        endpoint,
+                        inbox.sender(),
+                        rx,
+                        rx_interrupts,
+                        tx_status,
+                        toolchanger.backend(),
+                        Default::default(),
+                    )
+                    .await
+                    .unwrap();
+                    tokio::spawn(async move { conn.run().await });
+                }
+            }
+
+            let status = rx_status.await.unwrap();
+
+            let mut client = Self {
+                tx,
+                interrupts,
+                inbox,
+                status,
+                messages: vec![],
+            };
+            client
+                .wait_for(|m| matches!(m, KlipperMessage::Connected))
+                .await;
+            client
+        }
+
+        async fn send(&self, cmd: KlipperCommand) {
+            self.tx.send(cmd).await.unwrap();
+        }
+
+        /// Wait for a message matching `f`, keeping everything received on the way
+        async fn wait_for<F>(&mut self, f: F) -> KlipperMessage
+        where
+            F: Fn(&KlipperMessage) -> bool,
+        {
+            let t0 = std::time::Instant::now();
+            loop {
+                let new: Vec<_> = self.inbox.read_without_ctx().collect();
+                self.messages.extend(new);
+                if let Some(i) = self.messages.iter().position(|m| f(m)) {
+                    return self.messages.remove(i);
+                }
+                assert!(
+                    t0.elapsed() < Duration::from_secs(5),
+                    "Timed out waiting for message, got: {:?}",
+                    self.messages
+                );
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        /// Wait until the printer has been sent `gcode`
+        async fn wait_for_gcode(server: &MockMoonraker, gcode: &str) {
+            let t0 = std::time::Instant::now();
+            while !server.state.lock().gcode_log.iter().any(|l| l == gcode) {
+                assert!(
+                    t0.elapsed() < Duration::from_secs(5),
+                    "Timed out waiting for {}",
+                    gcode
+                );
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        async fn get_position(&self) -> Option<(f64, f64, f64)> {
+            let (tx, rx) = tokio::sync::oneshot::channel();
+            self.send(KlipperCommand::GetPosition(tx)).await;
+            rx.await.unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_and_reads_config() {
+        let server = MockMoonraker::start(MockPrinterState::default())
+            .await
+            .unwrap();
+        let client = TestClient::connect(&server).await;
+
+        let status = client.status.read().await;
+        assert!(status.connected);
+        let res = status.resolution.expect("no resolution");
+        assert_eq!(res.xy(), (40. / (16. * 200.), 40. / (16. * 200.)));
+        assert_eq!(res.z, 8. / (16. * 200.));
+    }
+
+    #[tokio::test]
+    async fn home_and_move() {
+        let server = MockMoonraker::start(MockPrinterState::default())
+            .await
+            .unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        client.send(KlipperCommand::HomeAll).await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::AxesHomed(_)))
+            .await;
+        assert!(matches!(msg, KlipperMessage::AxesHomed((true, true, true))));
+
+        client
+            .send(KlipperCommand::MoveToPosition(
+                (100., 120., 30.),
+                None,
+                Default::default(),
+            ))
+            .await;
+        assert_eq!(client.get_position().await, Some((100., 120., 30.)));
+
+        client
+            .send(KlipperCommand::MoveAxisRelative(
+                Axis::X,
+                1.5,
+                Some(0.5),
+                Default::default(),
+            ))
+            .await;
+        assert_eq!(client.get_position().await, Some((101.5, 120., 30.)));
+
+        let log = server.state.lock().gcode_log.clone();
+        assert!(log.contains(&"_CLIENT_LINEAR_MOVE X=2".to_string()));
+        assert!(log.contains(&"_CLIENT_LINEAR_MOVE X=-0.5".to_string()));
+    }
+
+    #[tokio::test]
+    async fn http_client_polls_status() {
+        let server = MockMoonraker::start(MockPrinterState::new(2))
+            .await
+            .unwrap();
+        let mut client = TestClient::connect_http(&server).await;
+
+        /// only seen in a poll, G28's reply doesn't have it
+        client.send(KlipperCommand::HomeAll).await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::AxesHomed(_)))
+            .await;
+        assert!(matches!(msg, KlipperMessage::AxesHomed((true, true, true))));
+
+        client
+            .send(KlipperCommand::MoveToPosition(
+                (100., 120., 30.),
+                None,
+                Default::default(),
+            ))
+            .await;
+        assert_eq!(client.get_position().await, Some((100., 120., 30.)));
+
+        /// nothing is echoed over HTTP, so the error comes from the reply
+        client
+            .send(KlipperCommand::RunGcode("T9".to_string()))
+            .await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::GcodeResponse(l) if l.starts_with("!!")))
+            .await;
+
+        client.send(KlipperCommand::PickTool(1)).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::ActiveToolChanged(1)))
+            .await;
+    }
+
+    /// the first query is all there is to go on, nothing is pushed over HTTP
+    #[tokio::test]
+    async fn http_client_already_homed() {
+        let mut state = MockPrinterState::new(2);
+        state.homed = (true, true, true);
+        let server = MockMoonraker::start(state).await.unwrap();
+        let mut client = TestClient::connect_http(&server).await;
+
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::AxesHomed((true, true, true))))
+            .await;
+        client
+            .send(KlipperCommand::MoveAxisRelative(
+                Axis::X,
+                1.,
+                None,
+                Default::default(),
+            ))
+            .await;
+        assert_eq!(client.get_position().await.map(|p| p.0), Some(1.));
+    }
+
+    #[tokio::test]
+    async fn moves_with_speed_restore_limits() {
+        let server = MockMoonraker::start(MockPrinterState::default())
+            .await
+            .unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        client.send(KlipperCommand::HomeAll).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::AxesHomed((true, true, true))))
+            .await;
+
+        /// Z starts at 0, so it goes up before XY moves
+        client
+            .send(KlipperCommand::MoveToPosition(
+                (100., 120., 30.),
+                None,
+                MoveSpeed::new(50., 1000.),
+            ))
+            .await;
+        assert_eq!(client.get_position().await, Some((100., 120., 30.)));
+
+        /// going down, XY first
+        client
+            .send(KlipperCommand::MoveToPosition(
+                (50., 60., 10.),
+                None,
+                Default::default(),
+            ))
+            .await;
+        assert_eq!(client.get_position().await, Some((50., 60., 10.)));
+
+        let log = server.state.lock().gcode_log.clone();
+        let moves: Vec<&str> = log
+            .iter()
+            .map(|l| l.as_str())
+            .filter(|l| l.starts_with("G1") || l.starts_with("SET_VELOCITY_LIMIT"))
+            .collect();
+        assert_eq!(
+            moves,
+            vec![
+                "SET_VELOCITY_LIMIT VELOCITY=50.0 ACCEL=1000",
+                "G1 Z30.000 F3000",
+                "G1 X100.000 Y120.000 F3000",
+                "SET_VELOCITY_LIMIT VELOCITY=300.0 ACCEL=3000",
+                "G1 X50.000 Y60.000",
+                "G1 Z10.000",
+            ]
+        );
+        let state = server.state.lock();
+        assert_eq!((state.max_velocity, state.max_accel), (300., 3000.));
+    }
+
+    #[tokio::test]
+    async fn move_before_homing_is_reported() {
+        let server = MockMoonraker::start(MockPrinterState::default())
+            .await
+            .unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        client
+            .send(KlipperCommand::MoveToPosition(
+                (100., 100., 30.),
+                None,
+                Default::default(),
+            ))
+            .await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::KlipperError(_)))
+            .await;
+        let KlipperMessage::KlipperError(e) = msg else {
+            unreachable!()
+        };
+        assert!(e.contains("X, Y not homed"), "{}", e);
+        assert!(server.state.lock().gcode_log.is_empty());
+    }
+
+    /// the subscribe reply says so, there's no update until something changes
+    #[tokio::test]
+    async fn already_homed_on_connect() {
+        let mut state = MockPrinterState::default();
+        state.homed = (true, true, true);
+        let server = MockMoonraker::start(state).await.unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::AxesHomed((true, true, true))))
+            .await;
+        client
+            .send(KlipperCommand::MoveToPosition(
+                (100., 120., 30.),
+                None,
+                Default::default(),
+            ))
+            .await;
+        assert_eq!(client.get_position().await, Some((100., 120., 30.)));
+    }
+
+    #[tokio::test]
+    async fn xy_moves_after_homing_xy() {
+        let server = MockMoonraker::start(MockPrinterState::default())
+            .await
+            .unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        client.send(KlipperCommand::HomeXY).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::AxesHomed((true, true, false))))
+            .await;
+
+        let z = client.get_position().await.unwrap().2;
+        client
+            .send(KlipperCommand::MoveToPosition(
+                (100., 120., z),
+                None,
+                Default::default(),
+            ))
+            .await;
+        assert_eq!(client.get_position().await, Some((100., 120., z)));
+
+        client
+            .send(KlipperCommand::MoveToPosition(
+                (100., 120., z + 10.),
+                None,
+                Default::default(),
+            ))
+            .await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::KlipperError(_)))
+            .await;
+        let KlipperMessage::KlipperError(e) = msg else {
+            unreachable!()
+        };
+        assert!(e.contains("Z not homed"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn partially_homed_axes() {
+        let server = MockMoonraker::start(MockPrinterState::default())
+            .await
+            .unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        /// as if `G28 X` and `G28 Z` were run from the console
+        server.state.lock().homed = (true, false, true);
+        client.send(KlipperCommand::Dwell(1)).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::AxesHomed((true, false, true))))
+            .await;
+
+        client
+            .send(KlipperCommand::MoveAxisRelative(
+                Axis::Y,
+                1.,
+                None,
+                Default::default(),
+            ))
+            .await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::KlipperError(_)))
+            .await;
+        let KlipperMessage::KlipperError(e) = msg else {
+            unreachable!()
+        };
+        assert!(e.contains("Y not homed"), "{}", e);
+
+        client
+            .send(KlipperCommand::MoveAxisRelative(
+                Axis::X,
+                1.,
+                None,
+                Default::default(),
+            ))
+            .await;
+        client.send(KlipperCommand::FetchPosition).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::Position((x, _, _)) if *x == 1.))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn move_outside_limits_is_refused() {
+        let server = MockMoonraker::start(MockPrinterState::default())
+            .await
+            .unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        client.send(KlipperCommand::HomeAll).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::AxesHomed((true, true, true))))
+            .await;
+        let sent = server.state.lock().gcode_log.len();
+
+        /// stepper_z position_max is 250
+        client
+            .send(KlipperCommand::MoveToPosition(
+                (100., 100., 300.),
+                None,
+                Default::default(),
+            ))
+            .await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::KlipperError(_)))
+            .await;
+        let KlipperMessage::KlipperError(e) = msg else {
+            unreachable!()
+        };
+        assert!(e.contains("outside the printer's limits"), "{}", e);
+        assert_eq!(server.state.lock().gcode_log.len(), sent);
+    }
+
+    #[tokio::test]
+    async fn heaters_are_tracked() {
+        let server = MockMoonraker::start(MockPrinterState::new(2))
+            .await
+            .unwrap();
+        let client = TestClient::connect(&server).await;
+
+        let heater = |client: &TestClient, name: &str| {
+            let status = client.status.clone();
+            let name = name.to_string();
+            async move { status.read().await.heaters.get(&name).copied() }
+        };
+
+        client
+            .send(KlipperCommand::SetToolTemperature(1, 210.))
+            .await;
+        client.send(KlipperCommand::Dwell(1)).await;
+        let t0 = std::time::Instant::now();
+        while heater(&client, "extruder1").await.map(|h| h.target) != Some(210.) {
+            assert!(
+                t0.elapsed() < Duration::from_secs(5),
+                "heater never updated"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            heater(&client, "extruder").await.map(|h| h.is_on()),
+            Some(false)
+        );
+
+        client.send(KlipperCommand::CoolDown).await;
+        client.send(KlipperCommand::Dwell(1)).await;
+        let t0 = std::time::Instant::now();
+        while heater(&client, "extruder1").await.map(|h| h.is_on()) != Some(false) {
+            assert!(
+                t0.elapsed() < Duration::from_secs(5),
+                "heater never turned off"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(server
+            .state
+            .lock()
+            .gcode_log
+            .contains(&"SET_HEATER_TEMPERATURE HEATER=extruder1 TARGET=0".to_string()));
+    }
+
+    #[tokio::test]
+    async fn calibrate_z_commits_relative_offsets() {
+        let mut state = MockPrinterState::new(3);
+        state.nozzle_z_offsets = vec![0., 0.25, -0.1];
+        /// the reference tool keeps what it has, the others follow it
+        state
+            .variables
+            .insert("t0_z_offset".to_string(), json!(0.05));
+        let server = MockMoonraker::start(state).await.unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        client.send(KlipperCommand::HomeAll).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::AxesHomed((true, true, true))))
+            .await;
+
+        let settings = ZProbeSettings {
+            position: Some((150., 150.)),
+            ..Default::default()
+        };
+        client
+            .send(KlipperCommand::CalibrateZ(
+                settings,
+                vec![0, 1, 2],
+                Default::default(),
+            ))
+            .await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::ZOffsetsMeasured(_)))
+            .await;
+        let KlipperMessage::ZOffsetsMeasured(offsets) = msg else {
+            unreachable!()
+        };
+        assert_eq!(offsets.len(), 2);
+        for ((tool, found), expected) in offsets.iter().zip([0.3, -0.05]) {
+            assert!((found - expected).abs() < 1e-6, "T{}: {}", tool, found);
+        }
+
+        {
+            let state = server.state.lock();
+            assert_eq!(state.gcode_log.iter().filter(|l| *l == "PROBE").count(), 9);
+            /// nothing written until it's confirmed
+            assert_eq!(state.tool_offset(1).2, 0.);
+        }
+
+        client
+            .send(KlipperCommand::CommitOffsets(zprobe::z_changes(&offsets)))
+            .await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::OffsetsCommitted(_)))
+            .await;
+        let KlipperMessage::OffsetsCommitted(report) = msg else {
+            unreachable!()
+        };
+        assert_eq!(report.attempts, 1);
+        assert!((report.offsets[1].2 - 0.3).abs() < 1e-4);
+
+        let state = server.state.lock();
+        assert_eq!(state.tool_offset(0).2, 0.05);
+        assert!((state.tool_offset(1).2 - 0.3).abs() < 1e-6);
+        assert!((state.tool_offset(2).2 + 0.05).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn console_gcode_echoes_errors() {
+        let server = MockMoonraker::start(MockPrinterState::new(2))
+            .await
+            .unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        client
+            .send(KlipperCommand::RunGcode("T1".to_string()))
+            .await;
+        client
+            .wait_for(
+                |m| matches!(m, KlipperMessage::GcodeResponse(l) if l == "// Tool 1 selected"),
+            )
+            .await;
+
+        /// klipper prints the error itself, it shouldn't also be reported as a failure
+        client
+            .send(KlipperCommand::RunGcode("T9".to_string()))
+            .await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::GcodeResponse(l) if l.starts_with("!!")))
+            .await;
+        client.send(KlipperCommand::FetchPosition).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::Position(_)))
+            .await;
+        assert!(!client
+            .messages
+            .iter()
+            .any(|m| matches!(m, KlipperMessage::KlipperError(_))));
+    }
+
+    #[tokio::test]
+    async fn tool_offsets_round_trip() {
+        let server = MockMoonraker::start(MockPrinterState::new(2))
+            .await
+            .unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        client
+            .send(KlipperCommand::SetToolOffset(1, Axis::X, 0.25))
+            .await;
+        client
+            .send(KlipperCommand::AdjustToolOffset(1, Axis::Y, -0.125))
+            .await;
+        client.send(KlipperCommand::GetToolOffsets).await;
+
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::ToolOffsets(_)))
+            .await;
+        let KlipperMessage::ToolOffsets(offsets) = msg else {
+            unreachable!()
+        };
+        assert_eq!(offsets, vec![(0., 0., 0.), (0.25, -0.125, 0.)]);
+
+        client.send(KlipperCommand::PickTool(1)).await;
+        client.send(KlipperCommand::FetchPosition).await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::HomingOriginChanged((x, _, _)) if *x != 0.))
+            .await;
+        let KlipperMessage::HomingOriginChanged(origin) = msg else {
+            unreachable!()
+        };
+        assert_eq!(origin, (0.25, -0.125, 0.));
+    }
+
+    #[tokio::test]
+    async fn non_contiguous_tools() {
+        let server = MockMoonraker::start(MockPrinterState::with_tools(&[0, 2, 5]))
+            .await
+            .unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        {
+            let status = client.status.read().await;
+            let tools = status.tools.iter().map(|t| t.number).collect::<Vec<_>>();
+            assert_eq!(tools, vec![0, 2, 5]);
+            assert_eq!(status.tools[2].extruder.as_deref(), Some("extruder5"));
+        }
+
+        client
+            .send(KlipperCommand::SetToolOffset(5, Axis::X, 0.5))
+            .await;
+        client.send(KlipperCommand::GetToolOffsets).await;
+
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::ToolOffsets(_)))
+            .await;
+        let KlipperMessage::ToolOffsets(offsets) = msg else {
+            unreachable!()
+        };
+        /// indexed by tool number, past the gaps
+        assert_eq!(offsets.len(), 6);
+        assert_eq!(offsets[5], (0.5, 0., 0.));
+    }
+
+    #[tokio::test]
+    async fn commit_offsets_reads_back() {
+        let server = MockMoonraker::start(MockPrinterState::new(2))
+            .await
+            .unwrap();
+        let config = ToolchangerConfig::SaveVariables {
+            pick_gcode: "T{tool}".to_string(),
+            drop_gcode: "T_1".to_string(),
+            active_tool: None,
+        };
+        let mut client = TestClient::connect_with(&server, config).await;
+
+        let change = |tool, axis, value| OffsetChange { tool, axis, value };
+        client
+            .send(KlipperCommand::CommitOffsets(vec![
+                change(1, Axis::X, 0.25),
+                change(1, Axis::Y, -0.125),
+            ]))
+            .await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::OffsetsCommitted(_)))
+            .await;
+        let KlipperMessage::OffsetsCommitted(report) = msg else {
+            unreachable!()
+        };
+        assert_eq!(report.offsets, vec![(0., 0., 0.), (0.25, -0.125, 0.)]);
+        assert_eq!(report.attempts, 1);
+        assert_eq!(report.persistence, Persistence::SaveVariable);
+
+        /// a new tool is read back as soon as its X offset is saved
+        client
+            .send(KlipperCommand::CommitOffsets(vec![change(5, Axis::X, 0.5)]))
+            .await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::OffsetsCommitted(_)))
+            .await;
+        let KlipperMessage::OffsetsCommitted(report) = msg else {
+            unreachable!()
+        };
+        assert_eq!(report.offsets[5], (0.5, 0., 0.));
+    }
+
+    #[tokio::test]
+    async fn commit_offsets_that_dont_take() {
+        let server = MockMoonraker::start(MockPrinterState::new(2))
+            .await
+            .unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        /// TC_SET_OFFSET ignores tools the printer doesn't have
+        client
+            .send(KlipperCommand::CommitOffsets(vec![OffsetChange {
+                tool: 5,
+                axis: Axis::X,
+                value: 0.5,
+            }]))
+            .await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::OffsetCommitFailed(_)))
+            .await;
+        let KlipperMessage::OffsetCommitFailed(e) = msg else {
+            unreachable!()
+        };
+        assert!(e.contains("T5 X: wanted 0.5000, no offset read"), "{}", e);
+        let writes = server
+            .state
+            .lock()
+            .gcode_log
+            .iter()
+            .filter(|l| l.starts_with("TC_SET_OFFSET TOOL=5 AXIS=X"))
+            .count();
+        assert_eq!(writes, COMMIT_ATTEMPTS);
+    }
+
+    #[tokio::test]
+    async fn save_variables_backend() {
+        let server = MockMoonraker::start(MockPrinterState::new(2))
+            .await
+            .unwrap();
+        let config = ToolchangerConfig::SaveVariables {
+            pick_gcode: "T{tool}".to_string(),
+            drop_gcode: "T_1".to_string(),
+            active_tool: None,
+        };
+        let mut client = TestClient::connect_with(&server, config).await;
+
+        client
+            .send(KlipperCommand::SetToolOffset(1, Axis::X, 0.25))
+            .await;
+        client
+            .send(KlipperCommand::AdjustToolOffset(1, Axis::X, 0.5))
+            .await;
+        client.send(KlipperCommand::GetToolOffsets).await;
+
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::ToolOffsets(_)))
+            .await;
+        let KlipperMessage::ToolOffsets(offsets) = msg else {
+            unreachable!()
+        };
+        assert_eq!(offsets, vec![(0., 0., 0.), (0.75, 0., 0.)]);
+
+        let log = server.state.lock().gcode_log.clone();
+        assert!(log.contains(&"SAVE_VARIABLE VARIABLE=t1_x_offset VALUE=0.750000".to_string()));
+    }
+
+    #[tokio::test]
+    async fn gcode_offset_backend() {
+        let server = MockMoonraker::start(MockPrinterState::new(2))
+            .await
+            .unwrap();
+        let config = ToolchangerConfig::GcodeOffset {
+            pick_gcode: "T{tool}".to_string(),
+            drop_gcode: "T_1".to_string(),
+            offsets: vec![],
+            active_tool: None,
+        };
+        let mut client = TestClient::connect_with(&server, config).await;
+
+        client
+            .send(KlipperCommand::SetToolOffset(1, Axis::Y, -0.125))
+            .await;
+        client.send(KlipperCommand::PickTool(1)).await;
+        client.send(KlipperCommand::FetchPosition).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::HomingOriginChanged((_, y, _)) if *y != 0.))
+            .await;
+        assert_eq!(server.state.lock().homing_origin, (0., -0.125, 0.));
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        client.send(KlipperCommand::GetActiveTool(tx)).await;
+        assert_eq!(rx.await.unwrap(), Some(1));
+
+        /// only the mounted tool's offset is on the printer to read back
+        let changes = vec![
+            OffsetChange {
+                tool: 1,
+                axis: Axis::Y,
+                value: -0.25,
+            },
+            OffsetChange {
+                tool: 0,
+                axis: Axis::X,
+                value: 0.1,
+            },
+        ];
+        client
+            .send(KlipperCommand::CommitOffsets(changes.clone()))
+            .await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::OffsetsCommitted(_)))
+            .await;
+        let KlipperMessage::OffsetsCommitted(report) = msg else {
+            unreachable!()
+        };
+        assert_eq!(report.unverified, vec![changes[1]]);
+        assert_eq!(report.offsets[1], (0., -0.25, 0.));
+        assert_eq!(server.state.lock().homing_origin, (0., -0.25, 0.));
+
+        /// a failed pickup leaves the mounted tool unknown, T5's offset isn't applied
+        client.send(KlipperCommand::PickTool(5)).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::KlipperError(_)))
+            .await;
+        client
+            .send(KlipperCommand::SetToolOffset(5, Axis::X, 0.3))
+            .await;
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        client.send(KlipperCommand::GetActiveTool(tx)).await;
+        rx.await.unwrap();
+        assert_eq!(server.state.lock().homing_origin, (0., -0.25, 0.));
+
+        client.send(KlipperCommand::DropTool).await;
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        client.send(KlipperCommand::GetActiveTool(tx)).await;
+        assert_eq!(rx.await.unwrap(), Some(-1));
+        assert_eq!(server.state.lock().homing_origin, (0., 0., 0.));
+    }
+
+    #[tokio::test]
+    async fn gcode_offset_follows_tool_changes_on_the_printer() {
+        let server = MockMoonraker::start(MockPrinterState::new(2))
+            .await
+            .unwrap();
+        let config = ToolchangerConfig::GcodeOffset {
+            pick_gcode: "T{tool}\nSAVE_VARIABLE VARIABLE=active_tool VALUE={tool}".to_string(),
+            drop_gcode: "T_1\nSAVE_VARIABLE VARIABLE=active_tool VALUE=-1".to_string(),
+            offsets: vec![],
+            active_tool: Some(ActiveToolSource::SaveVariable("active_tool".to_string())),
+        };
+        let mut client = TestClient::connect_with(&server, config).await;
+
+        client
+            .send(KlipperCommand::SetToolOffset(1, Axis::Y, -0.125))
+            .await;
+
+        /// typed into the console rather than picked through us
+        client
+            .send(KlipperCommand::RunGcode(
+                "T1\nSAVE_VARIABLE VARIABLE=active_tool VALUE=1".to_string(),
+            ))
+            .await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::ActiveToolChanged(1)))
+            .await;
+
+        /// the next command catches up before it runs
+        client.send(KlipperCommand::FetchPosition).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::HomingOriginChanged((_, y, _)) if *y != 0.))
+            .await;
+        assert_eq!(server.state.lock().homing_origin, (0., -0.125, 0.));
+
+        client
+            .send(KlipperCommand::RunGcode(
+                "T_1\nSAVE_VARIABLE VARIABLE=active_tool VALUE=-1".to_string(),
+            ))
+            .await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::ActiveToolChanged(-1)))
+            .await;
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        client.send(KlipperCommand::GetActiveTool(tx)).await;
+        assert_eq!(rx.await.unwrap(), Some(-1));
+        assert_eq!(server.state.lock().homing_origin, (0., 0., 0.));
+    }
+
+    #[tokio::test]
+    async fn tracks_active_tool_from_save_variables() {
+        let server = MockMoonraker::start(MockPrinterState::new(2))
+            .await
+            .unwrap();
+        let config = ToolchangerConfig::SaveVariables {
+            pick_gcode: "T{tool}\nSAVE_VARIABLE VARIABLE=active_tool VALUE={tool}".to_string(),
+            drop_gcode: "T_1\nSAVE_VARIABLE VARIABLE=active_tool VALUE=-1".to_string(),
+            active_tool: Some(ActiveToolSource::SaveVariable("active_tool".to_string())),
+        };
+        let mut client = TestClient::connect_with(&server, config).await;
+
+        client.send(KlipperCommand::PickTool(1)).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::ActiveToolChanged(1)))
+            .await;
+        assert_eq!(client.status.read().await.active_tool, Some(1));
+
+        client.send(KlipperCommand::DropTool).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::ActiveToolChanged(-1)))
+            .await;
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        client.send(KlipperCommand::GetActiveTool(tx)).await;
+        assert_eq!(rx.await.unwrap(), Some(-1));
+    }
+
+    #[tokio::test]
+    async fn preflight_reports_missing_macros() {
+        let mut state = MockPrinterState::new(2);
+        state
+            .config
+            .as_object_mut()
+            .unwrap()
+            .remove("gcode_macro _CLIENT_LINEAR_MOVE");
+        let server = MockMoonraker::start(state).await.unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::Compatibility(_)))
+            .await;
+        let KlipperMessage::Compatibility(report) = msg else {
+            unreachable!()
+        };
+        assert_eq!(report.tools, vec![0, 1]);
+        assert_eq!(
+            report
+                .missing
+                .iter()
+                .map(|r| r.object.as_str())
+                .collect::<Vec<_>>(),
+            vec!["gcode_macro _CLIENT_LINEAR_MOVE"]
+        );
+        assert_eq!(
+            report.disabled_features(),
+            vec![Feature::RelativeMoves, Feature::AutoOffset]
+        );
+
+        client
+            .send(KlipperCommand::MoveAxisRelative(
+                Axis::X,
+                1.,
+                None,
+                Default::default(),
+            ))
+            .await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::KlipperError(_)))
+            .await;
+        let KlipperMessage::KlipperError(e) = msg else {
+            unreachable!()
+        };
+        assert!(e.contains("_CLIENT_LINEAR_MOVE"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn klippy_restart_resubscribes() {
+        let server = MockMoonraker::start(MockPrinterState::default())
+            .await
+            .unwrap();
+        let mut client = TestClient::connect(&server).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::KlippyStateChanged(KlippyState::Ready, _)))
+            .await;
+
+        server.shutdown_klippy("MCU 'mcu' shutdown: Timer too close");
+        let msg = client
+            .wait_for(|m| {
+                matches!(
+                    m,
+                    KlipperMessage::KlippyStateChanged(KlippyState::Shutdown, _)
+                )
+            })
+            .await;
+        let KlipperMessage::KlippyStateChanged(_, message) = msg else {
+            unreachable!()
+        };
+        assert_eq!(message, "MCU 'mcu' shutdown: Timer too close");
+
+        /// refused without reaching the printer
+        client.send(KlipperCommand::HomeAll).await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::KlipperError(_)))
+            .await;
+        let KlipperMessage::KlipperError(e) = msg else {
+            unreachable!()
+        };
+        assert!(e.contains("shutdown"), "{}", e);
+        assert!(!server.state.lock().gcode_log.contains(&"G28".to_string()));
+
+        server.restart_klippy();
+        client
+            .wait_for(|m| {
+                matches!(
+                    m,
+                    KlipperMessage::KlippyStateChanged(KlippyState::Disconnected, _)
+                )
+            })
+            .await;
+        server.klippy_ready();
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::KlippyStateChanged(KlippyState::Ready, _)))
+            .await;
+
+        /// only seen if the subscription was made again
+        client.send(KlipperCommand::HomeAll).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::AxesHomed((true, true, true))))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_drop() {
+        let server = MockMoonraker::start(MockPrinterState::default())
+            .await
+            .unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        server.drop_connections();
+
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::Disconnected))
+            .await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::Connected))
+            .await;
+
+        client.send(KlipperCommand::HomeAll).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::AxesHomed((true, true, true))))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn abort_drops_queued_commands() {
+        let mut state = MockPrinterState::default();
+        state.hang_on = Some("G4".to_string());
+        let server = MockMoonraker::start(state).await.unwrap();
+        let mut client = TestClient::connect(&server).await;
+
+        client.send(KlipperCommand::Dwell(60000)).await;
+        TestClient::wait_for_gcode(&server, "G4 P60000").await;
+        client.send(KlipperCommand::HomeAll).await;
+        client.interrupts.send(Interrupt::Abort).unwrap();
+
+        /// None while the queue is being drained, answered once it's done
+        /// even though the dwell never finishes
+        while client.get_position().await.is_none() {}
+        assert!(!server.state.lock().gcode_log.contains(&"G28".to_string()));
+
+        client.send(KlipperCommand::HomeAll).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::AxesHomed((true, true, true))))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn emergency_stop_during_command() {
+        let mut state = MockPrinterState::default();
+        state.hang_on = Some("G4".to_string());
+        let server = MockMoonraker::start(state).await.unwrap();
+        let mut client = TestClient::connect(&server).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::KlippyStateChanged(KlippyState::Ready, _)))
+            .await;
+
+        client.send(KlipperCommand::Dwell(60000)).await;
+        TestClient::wait_for_gcode(&server, "G4 P60000").await;
+        client.send(KlipperCommand::HomeAll).await;
+        client.interrupts.send(Interrupt::EmergencyStop).unwrap();
+
+        client
+            .wait_for(|m| {
+                matches!(
+                    m,
+                    KlipperMessage::KlippyStateChanged(KlippyState::Shutdown, _)
+                )
+            })
+            .await;
+        assert_eq!(server.state.lock().klippy_state, "shutdown");
+        assert!(!server.state.lock().gcode_log.contains(&"G28".to_string()));
+    }
+
+    #[tokio::test]
+    async fn remote_methods_are_forwarded() {
+        let server = MockMoonraker::start(MockPrinterState::default())
+            .await
+            .unwrap();
+        let mut client = TestClient::connect(&server).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::KlippyStateChanged(KlippyState::Ready, _)))
+            .await;
+        assert_eq!(server.state.lock().remote_methods, remote::REMOTE_METHODS);
+
+        server.call_remote_method(remote::LOCATE_TOOL, serde_json::json!({ "tool": "2" }));
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::RemoteMethodCalled(..)))
+            .await;
+        let KlipperMessage::RemoteMethodCalled(method, params) = msg else {
+            unreachable!()
+        };
+        assert_eq!(
+            remote::RemoteCall::parse(&method, &params).unwrap(),
+            remote::RemoteCall::LocateTool(Some(2))
+        );
+
+        client
+            .send(KlipperCommand::Respond(
+                remote::RespondType::Echo,
+                "T2 centered; \"done\"".to_string(),
+            ))
+            .await;
+        let msg = client
+            .wait_for(|m| matches!(m, KlipperMessage::GcodeResponse(_)))
+            .await;
+        let KlipperMessage::GcodeResponse(line) = msg else {
+            unreachable!()
+        };
+        assert_eq!(line, "echo: tc_utils: T2 centered, 'done'");
+    }
+
+    #[tokio::test]
+    async fn replayed_session_matches_live_status() {
+        let path =
+            std::env::temp_dir().join(format!("tc_utils_session_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let server = MockMoonraker::start(MockPrinterState::default())
+            .await
+            .unwrap();
+        let recorder = SessionRecorder::create(&path).unwrap();
+        let mut client = TestClient::connect_recorded(&server, recorder).await;
+
+        client.send(KlipperCommand::HomeAll).await;
+        client
+            .wait_for(|m| matches!(m, KlipperMessage::AxesHomed((true, true, true))))
+            .await;
+        client
+            .send(KlipperCommand::MoveToPosition(
+                (100., 120., 30.),
+                None,
+                Default::default(),
+            ))
+            .await;
+        assert_eq!(client.get_position().await, Some((100., 120., 30.)));
+        let live = client.status.read().await.clone();
+
+        let frames = recorder::load_recording(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(frames.iter().any(|f| f.dir == recorder::Direction::Out
+            && f.frame["method"] == "printer.objects.subscribe"));
+
+        /// nothing is connected, only the recorded frames update the status
+        let status = Arc::new(RwLock::new(KlipperStatus::default()));
+        let inbox = egui_inbox::UiInbox::new();
+        KlipperConn::replay(
+            frames,
+            false,
+            status.clone(),
+            inbox.sender(),
+            ToolchangerConfig::default().backend().as_ref(),
+        )
+        .await;
+
+        let replayed = status.read().await;
+        assert_eq!(replayed.position, live.position);
+        assert_eq!(replayed.gcode_position, live.gcode_position);
+        assert_eq!(replayed.homed_axes, live.homed_axes);
+        assert_eq!(replayed.homing_origin, live.homing_origin);
+        assert_eq!(replayed.resolution, live.resolution);
+        assert_eq!(replayed.axis_limits, live.axis_limits);
+        assert_eq!(replayed.active_tool, live.active_tool);
+
+        let messages: Vec<_> = inbox.read_without_ctx().collect();
+        assert!(messages
+            .iter()
+            .any(|m| matches!(m, KlipperMessage::AxesHomed((true, true, true)))));
+    }
+
+    #[test]
+    fn recording_keeps_text_frames() {
+        let jsonl = concat!(
+            r#"{"t":0.0,"dir":"out","frame":{"jsonrpc":"2.0","method":"server.info","id":1}}"#,
+            "\n\n",
+            r#"{"t":0.5,"dir":"in","frame":"not json"}"#,
+            "\n",
+        );
+        let frames = recorder::parse_recording(jsonl.as_bytes()).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].frame["method"], "server.info");
+        assert_eq!(frames[1].dir, recorder::Direction::In);
+        assert_eq!(frames[1].text(), "not json");
+
+        assert!(recorder::parse_recording("{\"t\":0}".as_bytes()).is_err());
+    }
+}
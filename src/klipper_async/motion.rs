@@ -0,0 +1,223 @@
+//! How fast a move goes, and in what order the axes move to get somewhere safely
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+/// Speed and acceleration for one command, None keeps whatever the printer is set to
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct MoveSpeed {
+    /// mm/s
+    pub speed: Option<f64>,
+    /// mm/s^2
+    pub accel: Option<f64>,
+}
+
+impl MoveSpeed {
+    pub fn new(speed: f64, accel: f64) -> Self {
+        Self {
+            speed: Some(speed),
+            accel: Some(accel),
+        }
+    }
+
+    /// `F` word in mm/min, for G1 and `_CLIENT_LINEAR_MOVE`
+    pub fn feedrate(&self) -> Option<f64> {
+        self.speed.map(|s| s * 60.)
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.speed.is_none() && self.accel.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct MotionSettings {
+    /// moving to the camera, between tools
+    pub travel: MoveSpeed,
+    /// jogging and auto-centering, where the nozzle is already near the camera
+    pub fine: MoveSpeed,
+    /// seconds the toolhead has to be still before camera frames are used
+    pub settle_time: f64,
+}
+
+impl Default for MotionSettings {
+    fn default() -> Self {
+        Self {
+            travel: MoveSpeed {
+                speed: Some(150.),
+                accel: None,
+            },
+            fine: MoveSpeed::new(5., 500.),
+            settle_time: 0.3,
+        }
+    }
+}
+
+/// Waypoints from `from` to `to`, not including `from`.
+/// Going up, Z moves first so XY travels clear of the bed; going down, XY moves first
+/// so Z only drops once it's over the target. The bounce point, if any, is visited at the
+/// travel height just before the target.
+pub fn plan_travel(
+    from: (f64, f64, f64),
+    to: (f64, f64, f64),
+    bounce: Option<(f64, f64)>,
+) -> Vec<(f64, f64, f64)> {
+    let mut path = vec![];
+
+    let z_first = to.2 >= from.2;
+    let travel_z = if z_first { to.2 } else { from.2 };
+
+    if z_first && to.2 != from.2 {
+        path.push((from.0, from.1, to.2));
+    }
+    if let Some((x, y)) = bounce {
+        path.push((x, y, travel_z));
+    }
+    path.push((to.0, to.1, travel_z));
+    if !z_first {
+        path.push(to);
+    }
+
+    path
+}
+
+/// G-code for one leg, only naming the axes that change
+pub fn move_gcode(from: (f64, f64, f64), to: (f64, f64, f64), speed: &MoveSpeed) -> Option<String> {
+    let mut gcode = "G1".to_string();
+    for (name, a, b) in [
+        ("X", from.0, to.0),
+        ("Y", from.1, to.1),
+        ("Z", from.2, to.2),
+    ] {
+        if a != b {
+            gcode.push_str(&format!(" {}{:.3}", name, b));
+        }
+    }
+    if gcode == "G1" {
+        return None;
+    }
+    if let Some(f) = speed.feedrate() {
+        gcode.push_str(&format!(" F{:.0}", f));
+    }
+    Some(gcode)
+}
+
+/// toolhead speed below this counts as stopped, mm/s
+pub const STOPPED_VELOCITY: f64 = 0.01;
+
+/// What the toolhead is doing right now, from `motion_report` and `toolhead`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LiveMotion {
+    /// `live_position`, carriage coordinates
+    pub position: Option<(f64, f64, f64)>,
+    pub velocity: f64,
+    /// when the last queued move ends
+    pub print_time: f64,
+    /// what the MCU clock is at now, on the same scale as `print_time`
+    pub estimated_print_time: f64,
+    /// None while moving, or until the first update after a move was sent
+    pub stationary_since: Option<std::time::Instant>,
+}
+
+impl LiveMotion {
+    /// Either object may be missing, a subscription update only has the fields that changed
+    pub fn update(
+        &mut self,
+        motion_report: Option<&serde_json::Value>,
+        toolhead: Option<&serde_json::Value>,
+    ) {
+        if motion_report.is_none() && toolhead.is_none() {
+            return;
+        }
+
+        if let Some(report) = motion_report {
+            if let Some(pos) = report.get("live_position").and_then(|v| v.as_array()) {
+                if let (Some(x), Some(y), Some(z)) = (
+                    pos.get(0).and_then(|v| v.as_f64()),
+                    pos.get(1).and_then(|v| v.as_f64()),
+                    pos.get(2).and_then(|v| v.as_f64()),
+                ) {
+                    self.position = Some((x, y, z));
+                }
+            }
+            if let Some(v) = report.get("live_velocity").and_then(|v| v.as_f64()) {
+                self.velocity = v;
+            }
+        }
+
+        if let Some(toolhead) = toolhead {
+            if let Some(t) = toolhead.get("print_time").and_then(|v| v.as_f64()) {
+                self.print_time = t;
+            }
+            if let Some(t) = toolhead
+                .get("estimated_print_time")
+                .and_then(|v| v.as_f64())
+            {
+                self.estimated_print_time = t;
+            }
+        }
+
+        if self.is_moving() {
+            self.stationary_since = None;
+        } else if self.stationary_since.is_none() {
+            self.stationary_since = Some(std::time::Instant::now());
+        }
+    }
+
+    /// moving, or moves queued in klipper that haven't run yet
+    pub fn is_moving(&self) -> bool {
+        self.velocity.abs() > STOPPED_VELOCITY || self.print_time > self.estimated_print_time
+    }
+
+    pub fn stationary_for(&self) -> Option<std::time::Duration> {
+        self.stationary_since.map(|t| t.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn travel_up_moves_z_first() {
+        let path = plan_travel((10., 10., 5.), (100., 120., 30.), None);
+        assert_eq!(path, vec![(10., 10., 30.), (100., 120., 30.)]);
+    }
+
+    #[test]
+    fn travel_down_moves_xy_first() {
+        let path = plan_travel((10., 10., 50.), (100., 120., 30.), Some((100.5, 120.5)));
+        assert_eq!(
+            path,
+            vec![(100.5, 120.5, 50.), (100., 120., 50.), (100., 120., 30.)]
+        );
+    }
+
+    #[test]
+    fn gcode_only_names_changed_axes() {
+        let speed = MoveSpeed::new(5., 500.);
+        assert_eq!(
+            move_gcode((1., 2., 3.), (1., 2.5, 3.), &speed).unwrap(),
+            "G1 Y2.500 F300"
+        );
+        assert_eq!(move_gcode((1., 2., 3.), (1., 2., 3.), &speed), None);
+    }
+
+    #[test]
+    fn stationary_once_queued_moves_ran() {
+        let mut motion = LiveMotion::default();
+        let toolhead = serde_json::json!({ "print_time": 12.0, "estimated_print_time": 10.0 });
+        motion.update(None, Some(&toolhead));
+        assert!(motion.is_moving());
+        assert_eq!(motion.stationary_since, None);
+
+        let report = serde_json::json!({ "live_position": [1., 2., 3., 0.], "live_velocity": 0.0 });
+        let toolhead = serde_json::json!({ "estimated_print_time": 12.5 });
+        motion.update(Some(&report), Some(&toolhead));
+        assert!(!motion.is_moving());
+        assert!(motion.stationary_since.is_some());
+        assert_eq!(motion.position, Some((1., 2., 3.)));
+    }
+}
@@ -0,0 +1,149 @@
+//! Writing measured offsets as one transaction: set them all, read them back until they
+//! match, then have the backend save them. See [`PrinterClient::commit_offsets`](super::PrinterClient::commit_offsets)
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use super::toolchanger::Persistence;
+use crate::ui::ui_types::Axis;
+
+/// Offsets are sent with 6 decimals, and read back from floats
+pub const OFFSET_TOLERANCE: f64 = 1e-4;
+
+/// Times the offsets that didn't take are sent again before giving up
+pub const COMMIT_ATTEMPTS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct OffsetChange {
+    pub tool: u32,
+    pub axis: Axis,
+    pub value: f64,
+}
+
+/// One row of the table shown before committing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OffsetDiff {
+    pub change: OffsetChange,
+    /// None if the printer has no offset for this tool yet
+    pub before: Option<f64>,
+}
+
+impl OffsetDiff {
+    pub fn delta(&self) -> Option<f64> {
+        self.before.map(|b| self.change.value - b)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CommitReport {
+    pub changes: Vec<OffsetChange>,
+    /// every tool's offsets as read back after the last attempt
+    pub offsets: Vec<(f64, f64, f64)>,
+    pub attempts: usize,
+    pub persistence: Persistence,
+    /// set, but the backend can't read them back to check
+    #[serde(default)]
+    pub unverified: Vec<OffsetChange>,
+}
+
+/// One axis of a tool's offsets
+pub fn offset_of(offsets: &[(f64, f64, f64)], tool: u32, axis: Axis) -> Option<f64> {
+    let (x, y, z) = offsets.get(tool as usize)?;
+    Some(match axis {
+        Axis::X => *x,
+        Axis::Y => *y,
+        Axis::Z => *z,
+    })
+}
+
+pub fn diff(current: &[(f64, f64, f64)], changes: &[OffsetChange]) -> Vec<OffsetDiff> {
+    changes
+        .iter()
+        .map(|change| OffsetDiff {
+            change: *change,
+            before: offset_of(current, change.tool, change.axis),
+        })
+        .collect()
+}
+
+/// Changes whose value wasn't read back, with what was read instead
+pub fn mismatches(
+    changes: &[OffsetChange],
+    offsets: &[(f64, f64, f64)],
+) -> Vec<(OffsetChange, Option<f64>)> {
+    changes
+        .iter()
+        .filter_map(|change| {
+            let read = offset_of(offsets, change.tool, change.axis);
+            match read {
+                Some(v) if (v - change.value).abs() <= OFFSET_TOLERANCE => None,
+                _ => Some((*change, read)),
+            }
+        })
+        .collect()
+}
+
+/// `T1 X, T2 Y`
+pub fn describe_changes(changes: &[OffsetChange]) -> String {
+    changes
+        .iter()
+        .map(|c| format!("T{} {}", c.tool, c.axis))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// `T1 X: wanted 0.2500, read 0.2400`, one per line
+pub fn describe_mismatches(mismatched: &[(OffsetChange, Option<f64>)]) -> String {
+    mismatched
+        .iter()
+        .map(|(c, read)| match read {
+            Some(v) => format!(
+                "T{} {}: wanted {:.4}, read {:.4}",
+                c.tool, c.axis, c.value, v
+            ),
+            None => format!(
+                "T{} {}: wanted {:.4}, no offset read",
+                c.tool, c.axis, c.value
+            ),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mismatches_within_tolerance() {
+        let changes = [
+            OffsetChange {
+                tool: 1,
+                axis: Axis::X,
+                value: 0.25,
+            },
+            OffsetChange {
+                tool: 1,
+                axis: Axis::Y,
+                value: -0.125,
+            },
+            OffsetChange {
+                tool: 2,
+                axis: Axis::X,
+                value: 0.5,
+            },
+        ];
+        let offsets = vec![(0., 0., 0.), (0.25004, -0.12, 0.)];
+
+        let mismatched = mismatches(&changes, &offsets);
+        assert_eq!(
+            mismatched,
+            vec![(changes[1], Some(-0.12)), (changes[2], None)]
+        );
+
+        let rows = diff(&offsets, &changes);
+        assert_eq!(rows[0].before, Some(0.25004));
+        assert_eq!(rows[1].delta(), Some(-0.125 - -0.12));
+        assert_eq!(rows[2].delta(), None);
+    }
+}
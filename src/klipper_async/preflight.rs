@@ -0,0 +1,149 @@
+//! Check the printer has the macros and objects we rely on, right after connecting
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use super::{toolchanger::ToolchangerBackend, KlipperCommand};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Feature {
+    RelativeMoves,
+    ReadOffsets,
+    WriteOffsets,
+    ToolChanges,
+    /// needs relative moves, writing offsets and tool changes
+    AutoOffset,
+    /// needs writing offsets and tool changes
+    ZCalibration,
+}
+
+impl Feature {
+    pub fn to_str(&self) -> &str {
+        match self {
+            Feature::RelativeMoves => "Relative moves",
+            Feature::ReadOffsets => "Reading tool offsets",
+            Feature::WriteOffsets => "Writing tool offsets",
+            Feature::ToolChanges => "Tool changes",
+            Feature::AutoOffset => "Auto offset",
+            Feature::ZCalibration => "Z calibration",
+        }
+    }
+
+    /// What a command needs, if anything beyond basic G-code
+    pub fn for_command(cmd: &KlipperCommand) -> Option<Self> {
+        match cmd {
+            KlipperCommand::MoveAxisRelative(..) => Some(Feature::RelativeMoves),
+            KlipperCommand::PickTool(_) | KlipperCommand::DropTool => Some(Feature::ToolChanges),
+            KlipperCommand::AdjustToolOffset(..)
+            | KlipperCommand::SetToolOffset(..)
+            | KlipperCommand::CommitOffsets(_) => Some(Feature::WriteOffsets),
+            KlipperCommand::GetToolOffsets => Some(Feature::ReadOffsets),
+            KlipperCommand::CalibrateZ(..) => Some(Feature::ZCalibration),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Feature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_str())
+    }
+}
+
+/// A printer object (as named by `printer.objects.list`) and what stops working without it
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Requirement {
+    pub object: String,
+    pub features: Vec<Feature>,
+}
+
+impl Requirement {
+    pub fn new(object: impl Into<String>, features: &[Feature]) -> Self {
+        Self {
+            object: object.into(),
+            features: features.to_vec(),
+        }
+    }
+
+    pub fn gcode_macro(name: &str, features: &[Feature]) -> Self {
+        Self::new(format!("gcode_macro {}", name), features)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CompatibilityReport {
+    /// name of the toolchanger backend checked against
+    pub backend: String,
+    pub missing: Vec<Requirement>,
+    /// tool numbers the printer appears to have
+    pub tools: Vec<u32>,
+}
+
+impl CompatibilityReport {
+    /// `objects` as returned by `printer.objects.list`
+    pub fn check(objects: &[String], toolchanger: &dyn ToolchangerBackend) -> Self {
+        let mut required = vec![Requirement::gcode_macro(
+            "_CLIENT_LINEAR_MOVE",
+            &[Feature::RelativeMoves],
+        )];
+        required.extend(toolchanger.requirements());
+
+        let missing = required
+            .into_iter()
+            .filter(|r| !objects.contains(&r.object))
+            .collect();
+
+        Self {
+            backend: toolchanger.name().to_string(),
+            missing,
+            tools: toolchanger.available_tools(objects),
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    pub fn disabled_features(&self) -> Vec<Feature> {
+        let mut out: Vec<Feature> = vec![];
+        for f in self.missing.iter().flat_map(|r| r.features.iter()) {
+            if !out.contains(f) {
+                out.push(*f);
+            }
+        }
+        /// features built on the others
+        let composite: [(Feature, &[Feature]); 2] = [
+            (
+                Feature::AutoOffset,
+                &[
+                    Feature::RelativeMoves,
+                    Feature::WriteOffsets,
+                    Feature::ToolChanges,
+                ],
+            ),
+            (
+                Feature::ZCalibration,
+                &[Feature::WriteOffsets, Feature::ToolChanges],
+            ),
+        ];
+        for (feature, needed) in composite {
+            if !out.contains(&feature) && needed.iter().any(|f| out.contains(f)) {
+                out.push(feature);
+            }
+        }
+        out
+    }
+
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        !self.disabled_features().contains(&feature)
+    }
+
+    /// Objects missing for a feature, for error messages
+    pub fn missing_for(&self, feature: Feature) -> Vec<&str> {
+        self.missing
+            .iter()
+            .filter(|r| r.features.contains(&feature))
+            .map(|r| r.object.as_str())
+            .collect()
+    }
+}
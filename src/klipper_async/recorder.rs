@@ -0,0 +1,173 @@
+//! Recording of every websocket frame to a JSONL file, and replaying one back through
+//! the listener. Bugs in [`KlipperStatus::update`] depend on which frames arrived in what order,
+//! a recording of the session they happened in reproduces them.
+//!
+//! One frame per line:
+//! ```text
+//! {"t":0.012,"dir":"out","frame":{"jsonrpc":"2.0","method":"server.info","id":1}}
+//! {"t":0.015,"dir":"in","frame":{"jsonrpc":"2.0","result":{...},"id":1}}
+//! ```
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use std::{
+    io::{BufRead, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use egui_inbox::UiInboxSender;
+use futures_util::Stream;
+use tokio::sync::RwLock;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+use super::{toolchanger, KlipperConn, KlipperMessage, KlipperStatus};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// from moonraker
+    In,
+    /// to moonraker
+    Out,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RecordedFrame {
+    /// seconds since the recording started
+    pub t: f64,
+    pub dir: Direction,
+    /// parsed if it was JSON, so the file can be read, otherwise the text as a string
+    pub frame: serde_json::Value,
+}
+
+impl RecordedFrame {
+    pub fn text(&self) -> String {
+        match &self.frame {
+            serde_json::Value::String(s) => s.clone(),
+            v => v.to_string(),
+        }
+    }
+}
+
+/// Shared between the run loop, which records what's sent, and the listener
+#[derive(Clone)]
+pub struct SessionRecorder {
+    file: Arc<parking_lot::Mutex<std::io::BufWriter<std::fs::File>>>,
+    start: std::time::Instant,
+}
+
+impl std::fmt::Debug for SessionRecorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionRecorder").finish_non_exhaustive()
+    }
+}
+
+impl SessionRecorder {
+    /// Appends, reconnects in the same run go in the same file
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open recording {}", path.display()))?;
+        info!("Recording websocket session to {}", path.display());
+        Ok(Self {
+            file: Arc::new(parking_lot::Mutex::new(std::io::BufWriter::new(file))),
+            start: std::time::Instant::now(),
+        })
+    }
+
+    pub fn record(&self, dir: Direction, text: &str) {
+        let frame = serde_json::from_str(text)
+            .unwrap_or_else(|_| serde_json::Value::String(text.to_string()));
+        let frame = RecordedFrame {
+            t: self.start.elapsed().as_secs_f64(),
+            dir,
+            frame,
+        };
+
+        let Ok(mut line) = serde_json::to_string(&frame) else {
+            return;
+        };
+        line.push('\n');
+
+        /// flushed every line, the interesting part is usually right before a crash
+        let mut file = self.file.lock();
+        if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+            warn!("Failed to record frame: {}", e);
+        }
+    }
+}
+
+pub fn load_recording(path: &Path) -> Result<Vec<RecordedFrame>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open recording {}", path.display()))?;
+    parse_recording(std::io::BufReader::new(file))
+}
+
+pub fn parse_recording(reader: impl BufRead) -> Result<Vec<RecordedFrame>> {
+    let mut frames = vec![];
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let frame = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid frame on line {}", i + 1))?;
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+/// The frames moonraker sent, as the websocket delivered them.
+/// With `realtime`, each is held back until its time since the first one has passed
+pub fn replay_stream(
+    frames: Vec<RecordedFrame>,
+    realtime: bool,
+) -> impl Stream<Item = Result<Message, tungstenite::Error>> + Send + Unpin {
+    let frames: std::collections::VecDeque<_> = frames
+        .into_iter()
+        .filter(|f| f.dir == Direction::In)
+        .collect();
+    let start = tokio::time::Instant::now();
+    let t0 = frames.front().map(|f| f.t).unwrap_or(0.);
+
+    Box::pin(futures_util::stream::unfold(
+        frames,
+        move |mut frames| async move {
+            let frame = frames.pop_front()?;
+            if realtime {
+                let at = std::time::Duration::from_secs_f64((frame.t - t0).max(0.));
+                tokio::time::sleep_until(start + at).await;
+            }
+            Some((Ok(Message::Text(frame.text().into())), frames))
+        },
+    ))
+}
+
+impl KlipperConn {
+    /// Feed a recording through the listener, as if it came from moonraker, returns once every frame has been handled.
+    /// Replies go nowhere, there are no requests waiting for them
+    pub async fn replay(
+        frames: Vec<RecordedFrame>,
+        realtime: bool,
+        status: Arc<RwLock<KlipperStatus>>,
+        inbox: UiInboxSender<KlipperMessage>,
+        toolchanger: &dyn toolchanger::ToolchangerBackend,
+    ) {
+        let (klippy_events, _) = tokio::sync::mpsc::unbounded_channel();
+        Self::listener(
+            status,
+            inbox,
+            Default::default(),
+            tokio::sync::broadcast::channel(256).0,
+            toolchanger.active_tool_source(),
+            klippy_events,
+            None,
+            replay_stream(frames, realtime),
+        )
+        .await;
+    }
+}
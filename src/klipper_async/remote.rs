@@ -0,0 +1,125 @@
+//! Methods registered with moonraker, so klipper macros can start a calibration:
+//!
+//! ```text
+//! [gcode_macro TC_UTILS_CALIBRATE_ALL]
+//! gcode:
+//!     {action_call_remote_method("tc_utils_calibrate_all")}
+//!
+//! [gcode_macro TC_UTILS_LOCATE_TOOL]
+//! gcode:
+//!     {action_call_remote_method("tc_utils_locate_tool", tool=params.TOOL|int)}
+//! ```
+//!
+//! Progress and the result come back as `RESPOND` lines, which needs `[respond]` in printer.cfg
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use super::{client::PrinterClient, KlipperConn};
+
+pub const CALIBRATE_ALL: &str = "tc_utils_calibrate_all";
+pub const LOCATE_TOOL: &str = "tc_utils_locate_tool";
+pub const REPEATABILITY: &str = "tc_utils_repeatability";
+
+pub const REMOTE_METHODS: [&str; 3] = [CALIBRATE_ALL, LOCATE_TOOL, REPEATABILITY];
+
+pub fn is_remote_method(method: &str) -> bool {
+    REMOTE_METHODS.contains(&method)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteCall {
+    /// Locate All Nozzles
+    CalibrateAll,
+    /// center a single tool over the camera, the mounted one if None
+    LocateTool(Option<u32>),
+    /// tool, number of pickups
+    Repeatability(Option<u32>, Option<usize>),
+}
+
+impl RemoteCall {
+    /// `params` is the object of keyword arguments given to `action_call_remote_method`
+    pub fn parse(method: &str, params: &serde_json::Value) -> Result<Self> {
+        match method {
+            CALIBRATE_ALL => Ok(RemoteCall::CalibrateAll),
+            LOCATE_TOOL => Ok(RemoteCall::LocateTool(
+                get_uint(params, "tool")?.map(|t| t as u32),
+            )),
+            REPEATABILITY => Ok(RemoteCall::Repeatability(
+                get_uint(params, "tool")?.map(|t| t as u32),
+                get_uint(params, "count")?.map(|c| c as usize),
+            )),
+            _ => bail!("Unknown remote method: {}", method),
+        }
+    }
+
+    pub fn method(&self) -> &'static str {
+        match self {
+            RemoteCall::CalibrateAll => CALIBRATE_ALL,
+            RemoteCall::LocateTool(_) => LOCATE_TOOL,
+            RemoteCall::Repeatability(_, _) => REPEATABILITY,
+        }
+    }
+}
+
+/// macros can pass numbers as strings, `tool=params.TOOL` without `|int`
+fn get_uint(params: &serde_json::Value, key: &str) -> Result<Option<u64>> {
+    match params.get(key) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(v) => v
+            .as_u64()
+            .or_else(|| v.as_str().and_then(|s| s.trim().parse().ok()))
+            .map(Some)
+            .ok_or_else(|| anyhow!("Invalid {}: {}", key, v)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RespondType {
+    /// `echo: ` prefix
+    Echo,
+    /// `!! ` prefix, shown as an error in mainsail/fluidd
+    Error,
+}
+
+/// Double quotes would end the message early, and `;` or `#` start a comment
+pub fn respond_gcode(kind: RespondType, msg: &str) -> String {
+    let kind = match kind {
+        RespondType::Echo => "echo",
+        RespondType::Error => "error",
+    };
+    let msg: String = msg
+        .chars()
+        .map(|c| match c {
+            '"' => '\'',
+            ';' => ',',
+            '#' | '\n' => ' ',
+            c => c,
+        })
+        .collect();
+    format!("RESPOND TYPE={} MSG=\"tc_utils: {}\"", kind, msg)
+}
+
+impl KlipperConn {
+    /// Has to be done again for every new websocket, moonraker forgets them when it closes
+    pub(super) async fn register_remote_methods(&mut self) -> Result<()> {
+        let params = serde_json::json!({
+            "client_name": "toolchanger_utils",
+            "version": env!("CARGO_PKG_VERSION"),
+            "type": "other",
+            "url": "https://github.com/RussHewgill/toolchanger_utils",
+        });
+        self.request("server.connection.identify", Some(params))
+            .await
+            .map_err(|e| anyhow!("Failed to identify: {}", e))?;
+
+        for method in REMOTE_METHODS {
+            let params = serde_json::json!({ "method_name": method });
+            self.request("connection.register_remote_method", Some(params))
+                .await
+                .map_err(|e| anyhow!("Failed to register {}: {}", method, e))?;
+        }
+        debug!("Registered remote methods");
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+//! Distance the toolhead moves per microstep, per axis. Which steppers move an axis
+//! depends on `[printer] kinematics`, and the coarsest of them limits how finely it can be positioned.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+/// mm per microstep
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AxisResolution {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl AxisResolution {
+    /// from `configfile.config`, where every value is a string
+    pub fn from_config(config: &serde_json::Value) -> Result<Self> {
+        let kinematics = config["printer"]["kinematics"]
+            .as_str()
+            .ok_or_else(|| anyhow!("No kinematics in [printer]"))?
+            .trim();
+
+        let step = |name: &str| step_distance(config, name);
+        /// coarsest of several steppers moving together
+        let coarsest = |names: &[&str]| -> Result<f64> {
+            let mut out: f64 = 0.;
+            for name in names {
+                out = out.max(step(name)?);
+            }
+            Ok(out)
+        };
+
+        let (x, y, z) = match kinematics {
+            "cartesian" | "limited_cartesian" => {
+                (step("stepper_x")?, step("stepper_y")?, step("stepper_z")?)
+            }
+            /// both motors move for a move along either axis
+            "corexy" | "limited_corexy" => {
+                let xy = coarsest(&["stepper_x", "stepper_y"])?;
+                (xy, xy, step("stepper_z")?)
+            }
+            "corexz" | "limited_corexz" => {
+                let xz = coarsest(&["stepper_x", "stepper_z"])?;
+                (xz, step("stepper_y")?, xz)
+            }
+            /// stepper_x is the `x - y` motor, so X only moves stepper_x, Y needs both
+            "hybrid_corexy" => (
+                step("stepper_x")?,
+                coarsest(&["stepper_x", "stepper_y"])?,
+                step("stepper_z")?,
+            ),
+            "hybrid_corexz" => (
+                step("stepper_x")?,
+                step("stepper_y")?,
+                coarsest(&["stepper_x", "stepper_z"])?,
+            ),
+            /// not linear, but the towers' step distance is what the carriage moves near the center
+            "delta" | "rotary_delta" => {
+                let d = coarsest(&["stepper_a", "stepper_b", "stepper_c"])?;
+                (d, d, d)
+            }
+            "deltesian" => {
+                let d = coarsest(&["stepper_left", "stepper_right"])?;
+                (d, step("stepper_y")?, d)
+            }
+            k => bail!("Unsupported kinematics: {}", k),
+        };
+
+        /// extra motors on the same axis, e.g. a dual carriage or stepper_z1
+        let extra = |base: &str| -> Result<f64> {
+            let mut out: f64 = 0.;
+            if let Some(sections) = config.as_object() {
+                for name in sections.keys() {
+                    let is_extra = name
+                        .strip_prefix(base)
+                        .map(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+                        .unwrap_or(false);
+                    if is_extra || (base == "stepper_x" && name == "dual_carriage") {
+                        out = out.max(step(name)?);
+                    }
+                }
+            }
+            Ok(out)
+        };
+
+        Ok(Self {
+            x: x.max(extra("stepper_x")?),
+            y: y.max(extra("stepper_y")?),
+            z: z.max(extra("stepper_z")?),
+        })
+    }
+
+    /// what auto offset can center the nozzle to
+    pub fn xy(&self) -> (f64, f64) {
+        (self.x, self.y)
+    }
+}
+
+/// `rotation_distance / (full_steps_per_rotation * microsteps * gear_ratio)`
+fn step_distance(config: &serde_json::Value, stepper: &str) -> Result<f64> {
+    let section = &config[stepper];
+    ensure!(section.is_object(), "No [{}] section", stepper);
+
+    let get = |key: &str| -> Result<Option<f64>> {
+        let Some(v) = section[key].as_str() else {
+            return Ok(None);
+        };
+        v.trim()
+            .parse::<f64>()
+            .map(Some)
+            .with_context(|| format!("Failed to parse {} in [{}]", key, stepper))
+    };
+
+    let rot_dist = get("rotation_distance")?
+        .ok_or_else(|| anyhow!("No rotation_distance in [{}]", stepper))?;
+    let microsteps = get("microsteps")?.ok_or_else(|| anyhow!("No microsteps in [{}]", stepper))?;
+    /// klipper's default
+    let full_steps = get("full_steps_per_rotation")?.unwrap_or(200.);
+    let gear_ratio = match section["gear_ratio"].as_str() {
+        Some(s) => parse_gear_ratio(s).with_context(|| format!("In [{}]", stepper))?,
+        None => 1.,
+    };
+
+    ensure!(
+        rot_dist > 0. && microsteps > 0. && full_steps > 0.,
+        "Invalid step settings in [{}]",
+        stepper
+    );
+    Ok(rot_dist / (full_steps * microsteps * gear_ratio))
+}
+
+/// `80:16, 3:1`, each pair is driven:driving and the overall ratio is their product
+fn parse_gear_ratio(s: &str) -> Result<f64> {
+    let mut ratio = 1.;
+    for pair in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (a, b) = pair
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid gear_ratio: {}", s))?;
+        let a = a.trim().parse::<f64>()?;
+        let b = b.trim().parse::<f64>()?;
+        ensure!(a > 0. && b > 0., "Invalid gear_ratio: {}", s);
+        ratio *= a / b;
+    }
+    Ok(ratio)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn stepper(rot_dist: &str, microsteps: &str) -> serde_json::Value {
+        json!({ "rotation_distance": rot_dist, "microsteps": microsteps })
+    }
+
+    #[test]
+    fn kinematics_and_gear_ratio() {
+        let mut config = json!({
+            "printer": { "kinematics": "cartesian" },
+            "stepper_x": stepper("40", "16"),
+            "stepper_y": stepper("32", "32"),
+            "stepper_z": stepper("8", "16"),
+            "stepper_z1": stepper("8", "8"),
+        });
+        config["stepper_z"]["gear_ratio"] = json!("80:16, 3:1");
+
+        let res = AxisResolution::from_config(&config).unwrap();
+        assert_eq!(res.x, 40. / 3200.);
+        assert_eq!(res.y, 32. / 6400.);
+        /// stepper_z1 is coarser than the geared stepper_z
+        assert_eq!(res.z, 8. / 1600.);
+
+        config["printer"]["kinematics"] = json!("corexy");
+        let res = AxisResolution::from_config(&config).unwrap();
+        assert_eq!(res.xy(), (40. / 3200., 40. / 3200.));
+
+        /// a coarse stepper_x drags Y down with it, but not the other way round
+        config["stepper_x"] = stepper("40", "8");
+        config["printer"]["kinematics"] = json!("hybrid_corexy");
+        let res = AxisResolution::from_config(&config).unwrap();
+        assert_eq!(res.xy(), (40. / 1600., 40. / 1600.));
+        assert_eq!(res.z, 8. / 1600.);
+
+        config["stepper_x"] = stepper("32", "32");
+        config["stepper_y"] = stepper("40", "8");
+        let res = AxisResolution::from_config(&config).unwrap();
+        assert_eq!(res.xy(), (32. / 6400., 40. / 1600.));
+
+        config["printer"]["kinematics"] = json!("hybrid_corexz");
+        config["stepper_z"] = stepper("8", "32");
+        config.as_object_mut().unwrap().remove("stepper_z1");
+        let res = AxisResolution::from_config(&config).unwrap();
+        assert_eq!(res.x, 32. / 6400.);
+        assert_eq!(res.y, 40. / 1600.);
+        /// stepper_x is coarser than stepper_z
+        assert_eq!(res.z, 32. / 6400.);
+        config["stepper_z"] = stepper("40", "8");
+        let res = AxisResolution::from_config(&config).unwrap();
+        assert_eq!((res.x, res.z), (32. / 6400., 40. / 1600.));
+
+        config["printer"]["kinematics"] = json!("polar");
+        assert!(AxisResolution::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,336 @@
+//! Taking commands from the UI and following klippy's state, the same for every [`PrinterClient`]
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use super::{
+    client::PrinterClient, preflight, remote, zprobe, Interrupt, KlipperCommand, KlipperMessage,
+    KlippyState,
+};
+use crate::ui::ui_types::Axis;
+
+#[allow(async_fn_in_trait)]
+pub trait CommandRunner: PrinterClient {
+    /// Runs until the UI closes the command channel, reconnecting whenever the printer goes away
+    async fn run(&mut self) -> Result<()>;
+
+    /// Once klippy is ready: start following the status, and read the config
+    async fn init(&mut self) -> Result<()>;
+
+    fn commands(&mut self) -> &mut tokio::sync::mpsc::Receiver<KlipperCommand>;
+
+    /// from the last preflight, everything is assumed to work until then
+    fn compatibility(&self) -> &preflight::CompatibilityReport;
+
+    fn set_compatibility(&mut self, report: preflight::CompatibilityReport);
+
+    /// Ask moonraker how klippy is doing
+    async fn check_klippy(&mut self) -> Result<()> {
+        let info = self.request("server.info", None).await?;
+        let mut state = KlippyState::from_str(info["klippy_state"].as_str().unwrap_or(""));
+        let mut message = String::new();
+
+        /// only answers while klippy is connected, but has the reason it isn't ready
+        if info["klippy_connected"].as_bool().unwrap_or(false) {
+            match self.request("printer.info", None).await {
+                Ok(info) => {
+                    state = KlippyState::from_str(info["state"].as_str().unwrap_or(""));
+                    message = info["state_message"].as_str().unwrap_or("").to_string();
+                }
+                Err(e) => debug!("printer.info failed: {}", e),
+            }
+        }
+
+        self.set_klippy_state(state, message).await
+    }
+
+    /// Tell the UI, and [`Self::init`] again once klippy becomes ready, since a restart loses subscriptions
+    async fn set_klippy_state(&mut self, state: KlippyState, message: String) -> Result<()> {
+        let prev = {
+            let mut status = self.status().write().await;
+            let prev = (status.klippy_state, status.klippy_message.clone());
+            status.klippy_state = state;
+            status.klippy_message = message.clone();
+            prev
+        };
+
+        if prev == (state, message.clone()) {
+            return Ok(());
+        }
+
+        if state.is_ready() {
+            info!("Klippy is ready");
+        } else {
+            warn!("Klippy is {}: {}", state, message.trim());
+        }
+
+        self.inbox()
+            .send(KlipperMessage::KlippyStateChanged(state, message))
+            .map_err(|e| anyhow!("Failed to send klippy state: {:?}", e))?;
+
+        if state.is_ready() && !prev.0.is_ready() {
+            self.init().await?;
+
+            if let Err(e) = self.preflight().await {
+                warn!("Preflight check failed: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check for the macros and objects we depend on, and tell the UI what won't work
+    async fn preflight(&mut self) -> Result<()> {
+        let objects = self.object_names().await?;
+
+        let report = preflight::CompatibilityReport::check(&objects, self.toolchanger());
+        if report.is_ok() {
+            debug!("Preflight ok, tools: {:?}", report.tools);
+        } else {
+            for r in report.missing.iter() {
+                warn!("Printer is missing {}", r.object);
+            }
+        }
+
+        self.set_compatibility(report.clone());
+        self.inbox()
+            .send(KlipperMessage::Compatibility(report))
+            .map_err(|e| anyhow!("Failed to send compatibility report: {:?}", e))?;
+
+        Ok(())
+    }
+
+    async fn klippy_ready(&self) -> bool {
+        self.status().read().await.klippy_state.is_ready()
+    }
+
+    /// Run one command from the UI unless klippy can't take it, giving up on it if interrupted
+    async fn run_command(
+        &mut self,
+        cmd: KlipperCommand,
+        interrupts: &mut tokio::sync::mpsc::UnboundedReceiver<Interrupt>,
+    ) {
+        let moves = cmd.moves();
+        let commits = matches!(cmd, KlipperCommand::CommitOffsets(_));
+        let (state, message) = {
+            let status = self.status().read().await;
+            (status.klippy_state, status.klippy_message.clone())
+        };
+        if !state.is_ready() {
+            let reason = if message.is_empty() {
+                format!("Klipper is {}", state)
+            } else {
+                format!("Klipper is {} ({})", state, message.trim())
+            };
+            self.reject_command(cmd, &reason);
+            self.moves_done(moves as usize).await;
+            return;
+        }
+
+        let res = tokio::select! {
+            res = self.handle_command(cmd) => Ok(res),
+            Some(interrupt) = interrupts.recv() => Err(interrupt),
+        };
+        match res {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                error!("Failed to handle command: {}", e);
+                if commits {
+                    self.send_commit_failed(format!("{}", e));
+                }
+                self.send_error(format!("{}", e));
+            }
+            Err(interrupt) => {
+                warn!("{:?} while a command was running", interrupt);
+                if commits {
+                    self.send_commit_failed(format!("{:?} while committing", interrupt));
+                }
+                self.handle_interrupt(interrupt).await;
+            }
+        }
+        self.moves_done(moves as usize).await;
+    }
+
+    async fn handle_command(&mut self, cmd: KlipperCommand) -> Result<()> {
+        if let Some(feature) = preflight::Feature::for_command(&cmd) {
+            if !self.compatibility().is_enabled(feature) {
+                bail!(
+                    "{} unavailable, printer is missing: {}",
+                    feature,
+                    self.compatibility().missing_for(feature).join(", ")
+                );
+            }
+        }
+
+        /// the offset has to follow tool changes made from the printer's side
+        if self.toolchanger().follows_active_tool()
+            && !matches!(
+                cmd,
+                KlipperCommand::GetActiveTool(_) | KlipperCommand::FollowActiveTool
+            )
+        {
+            if let Err(e) = self.get_active_tool().await {
+                debug!("Failed to check the mounted tool: {}", e);
+            }
+        }
+
+        match &cmd {
+            /// Z only if it changes, checked once the current position is known
+            KlipperCommand::MoveToPosition(..) => self.check_homed(&[Axis::X, Axis::Y]).await?,
+            KlipperCommand::MoveAxisRelative(axis, ..) => self.check_homed(&[*axis]).await?,
+            KlipperCommand::CalibrateZ(..) => {
+                self.check_homed(&[Axis::X, Axis::Y, Axis::Z]).await?
+            }
+            _ => {}
+        }
+
+        match cmd {
+            KlipperCommand::MoveToPosition(pos, bounce, speed) => {
+                self.move_to_position(pos, bounce, speed).await
+            }
+            KlipperCommand::MoveAxisRelative(axis, amount, bounce, speed) => {
+                self.move_axis_relative(axis, amount, bounce, speed).await
+            }
+            KlipperCommand::SetToolTemperature(tool, target) => {
+                self.set_tool_temperature(tool, target).await
+            }
+            KlipperCommand::CoolDown => self.cool_down().await,
+            KlipperCommand::RunGcode(gcode) => self.run_console_gcode(&gcode).await,
+            KlipperCommand::Respond(kind, msg) => {
+                self.run_gcode(&remote::respond_gcode(kind, &msg)).await
+            }
+            KlipperCommand::CalibrateZ(settings, tools, speed) => {
+                zprobe::calibrate_z(self, &settings, &tools, speed).await
+            }
+            KlipperCommand::HomeXY => self.home_xy().await,
+            KlipperCommand::HomeAll => self.home_all().await,
+            KlipperCommand::GetPosition(tx) => {
+                let pos = self.get_position().await;
+                let _ = tx.send(pos.as_ref().ok().copied());
+                pos.map(|_| ())
+            }
+            KlipperCommand::PickTool(tool) => self.pick_tool(tool).await,
+            KlipperCommand::DropTool => self.dropoff_tool().await,
+            KlipperCommand::AdjustToolOffset(tool, axis, amount) => {
+                self.adjust_tool_offset(tool as usize, axis, amount).await
+            }
+            KlipperCommand::SetToolOffset(tool, axis, amount) => {
+                self.set_tool_offset(tool as usize, axis, amount).await
+            }
+            KlipperCommand::GetToolOffsets => self.get_offsets().await,
+            KlipperCommand::CommitOffsets(changes) => self.commit_offsets(&changes).await,
+            KlipperCommand::GetActiveTool(tx) => {
+                let tool = self.get_active_tool().await;
+                let _ = tx.send(tool.as_ref().ok().copied().flatten());
+                tool.map(|_| ())
+            }
+            KlipperCommand::FollowActiveTool => self.get_active_tool().await.map(|_| ()),
+            KlipperCommand::DisableMotors => self.disable_motors().await,
+            KlipperCommand::WaitForMoves => self.wait_for_moves().await,
+            KlipperCommand::Dwell(ms) => self.dwell(ms).await,
+            KlipperCommand::FetchPosition => self.query_object("gcode_move").await.map(|_| ()),
+        }
+    }
+
+    /// The running command has already been dropped by the time this is called
+    async fn handle_interrupt(&mut self, interrupt: Interrupt) {
+        if interrupt == Interrupt::EmergencyStop {
+            /// works whatever state klippy is in
+            match self.request("printer.emergency_stop", None).await {
+                Ok(_) => warn!("Emergency stop sent"),
+                Err(e) => {
+                    error!("Failed to send emergency stop: {}", e);
+                    self.send_error(format!("Failed to send emergency stop: {}", e));
+                }
+            }
+        }
+
+        let mut dropped = 0;
+        let mut moves = 0;
+        while let Ok(cmd) = self.commands().try_recv() {
+            moves += cmd.moves() as usize;
+            match cmd {
+                KlipperCommand::GetPosition(tx) => {
+                    let _ = tx.send(None);
+                }
+                KlipperCommand::GetActiveTool(tx) => {
+                    let _ = tx.send(None);
+                }
+                _ => dropped += 1,
+            }
+        }
+        info!("{:?}: dropped {} queued commands", interrupt, dropped);
+        self.moves_done(moves).await;
+    }
+
+    /// Count off commands that could have moved the toolhead, whether they ran or not.
+    /// Whatever the toolhead was doing before doesn't count as settled any more.
+    async fn moves_done(&self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        let mut status = self.status().write().await;
+        status.moves_queued = status.moves_queued.saturating_sub(n);
+        status.motion.stationary_since = None;
+    }
+
+    fn reject_command(&self, cmd: KlipperCommand, reason: &str) {
+        match cmd {
+            KlipperCommand::GetPosition(tx) => {
+                let _ = tx.send(None);
+            }
+            KlipperCommand::GetActiveTool(tx) => {
+                let _ = tx.send(None);
+            }
+            KlipperCommand::FetchPosition | KlipperCommand::FollowActiveTool => {}
+            KlipperCommand::CommitOffsets(_) => {
+                warn!("{}, dropping offset commit", reason);
+                self.send_commit_failed(reason.to_string());
+                self.send_error(format!("{}, dropped offset commit", reason));
+            }
+            cmd => {
+                warn!("{}, dropping command: {:?}", reason, cmd);
+                self.send_error(format!("{}, dropped {:?}", reason, cmd));
+            }
+        }
+    }
+
+    /// Tells the offset commit window, which can't go by every `KlipperError`
+    fn send_commit_failed(&self, msg: String) {
+        self.inbox()
+            .send(KlipperMessage::OffsetCommitFailed(msg))
+            .unwrap_or_else(|e| {
+                error!("Failed to send offset commit message: {:?}", e);
+            });
+    }
+
+    /// Sleep before the next connection attempt, rejecting any commands sent meanwhile.
+    /// Returns false if the command channel was closed.
+    async fn wait_to_reconnect(
+        &mut self,
+        delay: std::time::Duration,
+        interrupts: &mut tokio::sync::mpsc::UnboundedReceiver<Interrupt>,
+    ) -> bool {
+        let sleep = tokio::time::sleep(delay);
+        tokio::pin!(sleep);
+
+        loop {
+            tokio::select! {
+                _ = &mut sleep => return true,
+                Some(interrupt) = interrupts.recv() => {
+                    debug!("Not connected, ignoring {:?}", interrupt);
+                }
+                cmd = self.commands().recv() => {
+                    match cmd {
+                        None => return false,
+                        Some(cmd) => {
+                            let moves = cmd.moves();
+                            self.reject_command(cmd, "Printer not connected");
+                            self.moves_done(moves as usize).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Physical model behind [`MockPrinterState`](super::mock_moonraker::MockPrinterState),
+//! so the synthetic camera can see where a nozzle really is, not just where klipper thinks it is.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use rand::prelude::*;
+use rand_xoshiro::{rand_core::SeedableRng, Xoshiro256PlusPlus};
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SimSettings {
+    /// true XY position of each nozzle relative to the carriage, in mm.
+    /// Missing tools are treated as (0, 0)
+    pub nozzle_offsets: Vec<(f64, f64)>,
+    /// lost motion when an axis changes direction, in mm
+    pub backlash: (f64, f64),
+    /// std dev of where the carriage ends up after each move, in mm
+    pub position_noise: f64,
+    /// std dev of where a tool sits on the carriage after each pickup, in mm
+    pub pickup_noise: f64,
+    /// machine position that appears in the center of the camera
+    pub camera_position: (f64, f64),
+    /// std dev of per-pixel camera noise, in grey levels
+    pub image_noise: f64,
+    pub seed: u64,
+}
+
+impl Default for SimSettings {
+    fn default() -> Self {
+        Self {
+            nozzle_offsets: vec![],
+            backlash: (0., 0.),
+            position_noise: 0.,
+            pickup_noise: 0.,
+            camera_position: (150., 150.),
+            image_noise: 0.,
+            seed: 0,
+        }
+    }
+}
+
+impl SimSettings {
+    /// expected offset of `tool` as measured against T0, in the sign convention klipper uses
+    pub fn expected_tool_offset(&self, tool: usize) -> (f64, f64) {
+        let t0 = self.nozzle_offset(0);
+        let t = self.nozzle_offset(tool);
+        (t0.0 - t.0, t0.1 - t.1)
+    }
+
+    pub fn nozzle_offset(&self, tool: usize) -> (f64, f64) {
+        self.nozzle_offsets.get(tool).copied().unwrap_or((0., 0.))
+    }
+}
+
+#[cfg(any(test, feature = "sim"))]
+#[derive(Debug, Clone)]
+pub struct SimPrinter {
+    pub settings: SimSettings,
+    /// where the carriage really is, after backlash and noise
+    carriage: (f64, f64),
+    /// backlash only, without noise, so errors don't accumulate between moves
+    slack: (f64, f64),
+    /// where the mounted tool is sitting this time it was picked up
+    pickup_error: (f64, f64),
+    rng: Xoshiro256PlusPlus,
+}
+
+#[cfg(any(test, feature = "sim"))]
+impl Default for SimPrinter {
+    fn default() -> Self {
+        Self::new(SimSettings::default())
+    }
+}
+
+#[cfg(any(test, feature = "sim"))]
+impl SimPrinter {
+    pub fn new(settings: SimSettings) -> Self {
+        let rng = Xoshiro256PlusPlus::seed_from_u64(settings.seed);
+        Self {
+            settings,
+            carriage: (0., 0.),
+            slack: (0., 0.),
+            pickup_error: (0., 0.),
+            rng,
+        }
+    }
+
+    /// Carriage was homed, backlash is taken up against the endstop
+    pub fn home(&mut self, pos: (f64, f64)) {
+        self.slack = pos;
+        self.carriage = pos;
+    }
+
+    /// Commanded carriage position changed to `pos`
+    pub fn move_to(&mut self, pos: (f64, f64)) {
+        self.slack.0 = Self::take_up_slack(self.slack.0, pos.0, self.settings.backlash.0);
+        self.slack.1 = Self::take_up_slack(self.slack.1, pos.1, self.settings.backlash.1);
+
+        let noise = self.settings.position_noise;
+        self.carriage = (
+            self.slack.0 + self.gaussian(noise),
+            self.slack.1 + self.gaussian(noise),
+        );
+    }
+
+    /// The axis only follows once the command is more than half the backlash away
+    fn take_up_slack(actual: f64, commanded: f64, backlash: f64) -> f64 {
+        let half = backlash / 2.;
+        if commanded > actual + half {
+            commanded - half
+        } else if commanded < actual - half {
+            commanded + half
+        } else {
+            actual
+        }
+    }
+
+    pub fn pick_tool(&mut self) {
+        let noise = self.settings.pickup_noise;
+        self.pickup_error = (self.gaussian(noise), self.gaussian(noise));
+    }
+
+    pub fn drop_tool(&mut self) {
+        self.pickup_error = (0., 0.);
+    }
+
+    /// Where the nozzle of `tool` really is, or the bare carriage if no tool is mounted
+    pub fn nozzle_position(&self, tool: i32) -> (f64, f64) {
+        if tool < 0 {
+            return self.carriage;
+        }
+        let (ox, oy) = self.settings.nozzle_offset(tool as usize);
+        (
+            self.carriage.0 + ox + self.pickup_error.0,
+            self.carriage.1 + oy + self.pickup_error.1,
+        )
+    }
+
+    /// Box-Muller, rand_distr isn't worth a dependency for this
+    pub fn gaussian(&mut self, std_dev: f64) -> f64 {
+        if std_dev == 0. {
+            return 0.;
+        }
+        let u1: f64 = self.rng.random::<f64>().max(f64::MIN_POSITIVE);
+        let u2: f64 = self.rng.random::<f64>();
+        std_dev * (-2. * u1.ln()).sqrt() * (2. * std::f64::consts::PI * u2).cos()
+    }
+}
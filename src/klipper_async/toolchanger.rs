@@ -0,0 +1,698 @@
+//! Toolchanger setups differ in where tool offsets live and how tools are changed.
+//! A [`ToolchangerBackend`] only turns requests into G-code and reads results out of
+//! printer status, a [`PrinterClient`](super::PrinterClient) does the talking to moonraker.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use serde_json::{json, Value};
+
+use super::{
+    offsets::OffsetChange,
+    preflight::{Feature, Requirement},
+};
+use crate::ui::ui_types::Axis;
+
+pub trait ToolchangerBackend: std::fmt::Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Printer objects this backend needs, checked on connect
+    fn requirements(&self) -> Vec<Requirement>;
+
+    /// Tool numbers the printer has, judging by `printer.objects.list`
+    fn available_tools(&self, objects: &[String]) -> Vec<u32>;
+
+    /// Objects to query (`printer.objects.query` format) for reading offsets and the active tool,
+    /// given every object from `printer.objects.list`
+    fn status_objects(&self, objects: &[String]) -> Value;
+
+    /// Offsets of every tool, from the `status` of a query of [`Self::status_objects`]
+    fn read_offsets(&self, status: &Value) -> Result<Vec<(f64, f64, f64)>>;
+
+    /// If [`Self::read_offsets`] gets `tool`'s offsets from the printer rather than remembering them
+    fn reads_back(&self, tool: u32) -> bool {
+        true
+    }
+
+    /// Where the printer reports the mounted tool
+    fn active_tool_source(&self) -> ActiveToolSource;
+
+    /// None if the backend can't tell, -1 if no tool is mounted
+    fn active_tool(&self, status: &Value) -> Option<i32> {
+        self.active_tool_source().parse(status)
+    }
+
+    /// G-code to set an offset, may be empty
+    fn set_offset(&mut self, tool: u32, axis: Axis, amount: f64) -> String;
+
+    /// G-code to add `amount` to an offset currently at `current`, may be empty
+    fn adjust_offset(&mut self, tool: u32, axis: Axis, amount: f64, current: f64) -> String;
+
+    /// Where offsets end up once they're set
+    fn persistence(&self) -> Persistence;
+
+    /// G-code to keep offsets that were just set over a restart, may be empty
+    fn persist_offsets(&mut self, changes: &[OffsetChange]) -> String {
+        String::new()
+    }
+
+    fn pick_tool(&mut self, tool: u32) -> String;
+
+    fn drop_tool(&mut self) -> String;
+
+    /// Called once a tool change has run, -1 for none mounted.
+    /// None if it failed, and what's mounted is anyone's guess
+    fn tool_changed(&mut self, tool: Option<i32>) {}
+
+    /// If the printer's idea of the mounted tool has to be checked before every command
+    fn follows_active_tool(&self) -> bool {
+        false
+    }
+
+    /// The printer reports `tool` mounted, maybe changed from the console or a macro.
+    /// G-code to catch up with it, may be empty
+    fn mounted_tool(&mut self, tool: i32) -> String {
+        String::new()
+    }
+}
+
+/// How offsets outlast a klipper restart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Persistence {
+    /// written to the save_variables file as they're set
+    SaveVariable,
+    /// pending config changes until `SAVE_CONFIG`, which restarts klipper
+    SaveConfig,
+    /// lost on restart
+    Session,
+}
+
+impl Persistence {
+    pub fn describe(&self) -> &'static str {
+        match self {
+            Persistence::SaveVariable => "saved with SAVE_VARIABLE",
+            Persistence::SaveConfig => "saved with SAVE_CONFIG, klipper will restart",
+            Persistence::Session => "kept until klipper restarts",
+        }
+    }
+}
+
+/// Where the printer keeps the number of the mounted tool
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActiveToolSource {
+    /// klipper-toolchanger's `toolchanger.tool_number`
+    Toolchanger,
+    /// a save_variables key
+    SaveVariable(String),
+    /// `variable_<variable>` of `[gcode_macro <macro_name>]`
+    MacroVariable {
+        macro_name: String,
+        variable: String,
+    },
+    /// the printer doesn't say, go by the last tool change we made
+    LastToolChange,
+}
+
+impl ActiveToolSource {
+    /// What to subscribe to, in `printer.objects.subscribe` format
+    pub fn objects(&self) -> Option<Value> {
+        match self {
+            Self::Toolchanger => Some(json!({ "toolchanger": ["tool_number"] })),
+            Self::SaveVariable(_) => Some(json!({ "save_variables": ["variables"] })),
+            Self::MacroVariable {
+                macro_name,
+                variable,
+            } => {
+                let mut out = serde_json::Map::new();
+                out.insert(format!("gcode_macro {}", macro_name), json!([variable]));
+                Some(Value::Object(out))
+            }
+            Self::LastToolChange => None,
+        }
+    }
+
+    /// Read the tool out of a status, which may be a partial update
+    pub fn parse(&self, status: &Value) -> Option<i32> {
+        let v = match self {
+            Self::Toolchanger => status.pointer("/toolchanger/tool_number")?,
+            Self::SaveVariable(key) => status.pointer("/save_variables/variables")?.get(key)?,
+            Self::MacroVariable {
+                macro_name,
+                variable,
+            } => status
+                .get(&format!("gcode_macro {}", macro_name))?
+                .get(variable)?,
+            Self::LastToolChange => return None,
+        };
+        /// save_variables may hold `1.0` rather than `1`
+        v.as_i64()
+            .or_else(|| v.as_f64().map(|v| v.round() as i64))
+            .map(|v| v as i32)
+    }
+}
+
+/// Which [`ToolchangerBackend`] a printer uses, from `[toolchanger]` in config.toml
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToolchangerConfig {
+    /// `TC_SET_OFFSET`/`TC_ADJUST_OFFSET` macros, offsets in save_variables, `T{n}`/`T_1`
+    TcMacros {
+        #[serde(default)]
+        active_tool: Option<ActiveToolSource>,
+    },
+    /// klipper-toolchanger, offsets in the `[tool Tn]` sections
+    KlipperToolchanger,
+    /// offsets in save_variables as `t{n}_x_offset`, written with `SAVE_VARIABLE`
+    SaveVariables {
+        /// `{tool}` is replaced with the tool number
+        #[serde(default = "default_pick_gcode")]
+        pick_gcode: String,
+        #[serde(default = "default_drop_gcode")]
+        drop_gcode: String,
+        /// if the macros keep track of the mounted tool
+        #[serde(default)]
+        active_tool: Option<ActiveToolSource>,
+    },
+    /// stock klipper, the offset is applied with `SET_GCODE_OFFSET` after each pickup.
+    /// Offsets are only kept for the session, starting from `offsets`
+    GcodeOffset {
+        #[serde(default = "default_pick_gcode")]
+        pick_gcode: String,
+        #[serde(default = "default_drop_gcode")]
+        drop_gcode: String,
+        #[serde(default)]
+        offsets: Vec<(f64, f64, f64)>,
+        #[serde(default)]
+        active_tool: Option<ActiveToolSource>,
+    },
+}
+
+fn default_pick_gcode() -> String {
+    "T{tool}".to_string()
+}
+
+fn default_drop_gcode() -> String {
+    "T_1".to_string()
+}
+
+impl Default for ToolchangerConfig {
+    fn default() -> Self {
+        Self::TcMacros { active_tool: None }
+    }
+}
+
+impl ToolchangerConfig {
+    pub fn backend(&self) -> Box<dyn ToolchangerBackend> {
+        match self.clone() {
+            Self::TcMacros { active_tool } => Box::new(TcMacros {
+                active_tool: active_tool.unwrap_or(ActiveToolSource::LastToolChange),
+            }),
+            Self::KlipperToolchanger => Box::new(KlipperToolchanger),
+            Self::SaveVariables {
+                pick_gcode,
+                drop_gcode,
+                active_tool,
+            } => Box::new(SaveVariables {
+                pick_gcode,
+                drop_gcode,
+                active_tool: active_tool.unwrap_or(ActiveToolSource::LastToolChange),
+            }),
+            Self::GcodeOffset {
+                pick_gcode,
+                drop_gcode,
+                offsets,
+                active_tool,
+            } => Box::new(GcodeOffset {
+                pick_gcode,
+                drop_gcode,
+                offsets,
+                active_tool: active_tool.unwrap_or(ActiveToolSource::LastToolChange),
+                active: None,
+            }),
+        }
+    }
+}
+
+/// `gcode_macro` requirement for the first command of a G-code template
+fn macro_requirement(gcode: &str, tool: u32, features: &[Feature]) -> Option<Requirement> {
+    let name = gcode.split_whitespace().next()?;
+    Some(Requirement::gcode_macro(
+        &name.replace("{tool}", &tool.to_string()),
+        features,
+    ))
+}
+
+/// Tools that have a pickup macro, for a template like `T{tool}`
+fn tools_with_macro(objects: &[String], pick_gcode: &str) -> Vec<u32> {
+    let Some(name) = pick_gcode.split_whitespace().next() else {
+        return vec![];
+    };
+    let Some((prefix, suffix)) = name.split_once("{tool}") else {
+        return vec![];
+    };
+    let mut tools: Vec<u32> = objects
+        .iter()
+        .filter_map(|o| o.strip_prefix("gcode_macro "))
+        .filter_map(|m| m.strip_prefix(prefix)?.strip_suffix(suffix)?.parse().ok())
+        .collect();
+    tools.sort();
+    tools
+}
+
+fn axis_name(axis: Axis) -> String {
+    axis.to_str().to_lowercase()
+}
+
+fn set_axis(offset: &mut (f64, f64, f64), axis: Axis, v: f64) {
+    match axis {
+        Axis::X => offset.0 = v,
+        Axis::Y => offset.1 = v,
+        Axis::Z => offset.2 = v,
+    }
+}
+
+/// `t{n}_x_offset` etc. from the save_variables status, indexed by tool number.
+/// Numbers without offsets in between, and axes not saved yet, are left at 0
+fn offsets_from_variables(status: &Value) -> Result<Vec<(f64, f64, f64)>> {
+    let vars = status
+        .pointer("/save_variables/variables")
+        .ok_or_else(|| anyhow!("No save_variables in reply"))?;
+
+    let mut offsets = Vec::new();
+
+    for tool in super::tools::from_variables(vars) {
+        let t = tool.number;
+        let get = |axis: &str| match vars.get(&format!("t{}_{}_offset", t, axis)) {
+            None => Ok(0.),
+            Some(v) => v
+                .as_f64()
+                .ok_or_else(|| anyhow!("Failed to parse tool {} {} offset", t, axis)),
+        };
+        set_tool(&mut offsets, t, (get("x")?, get("y")?, get("z")?));
+    }
+
+    Ok(offsets)
+}
+
+/// Offsets are indexed by tool number, so gaps are filled in
+fn set_tool(offsets: &mut Vec<(f64, f64, f64)>, tool: u32, offset: (f64, f64, f64)) {
+    let t = tool as usize;
+    if offsets.len() <= t {
+        offsets.resize(t + 1, (0., 0., 0.));
+    }
+    offsets[t] = offset;
+}
+
+/// The macro set this app was written against
+#[derive(Debug)]
+pub struct TcMacros {
+    active_tool: ActiveToolSource,
+}
+
+impl ToolchangerBackend for TcMacros {
+    fn name(&self) -> &'static str {
+        "TC_* macros"
+    }
+
+    fn requirements(&self) -> Vec<Requirement> {
+        vec![
+            Requirement::new("save_variables", &[Feature::ReadOffsets]),
+            Requirement::gcode_macro("TC_SET_OFFSET", &[Feature::WriteOffsets]),
+            Requirement::gcode_macro("TC_ADJUST_OFFSET", &[Feature::WriteOffsets]),
+            Requirement::gcode_macro("T_1", &[Feature::ToolChanges]),
+            Requirement::gcode_macro("T0", &[Feature::ToolChanges]),
+        ]
+    }
+
+    fn available_tools(&self, objects: &[String]) -> Vec<u32> {
+        tools_with_macro(objects, "T{tool}")
+    }
+
+    fn status_objects(&self, objects: &[String]) -> Value {
+        json!({ "save_variables": null })
+    }
+
+    fn read_offsets(&self, status: &Value) -> Result<Vec<(f64, f64, f64)>> {
+        offsets_from_variables(status)
+    }
+
+    fn active_tool_source(&self) -> ActiveToolSource {
+        self.active_tool.clone()
+    }
+
+    fn set_offset(&mut self, tool: u32, axis: Axis, amount: f64) -> String {
+        format!(
+            "TC_SET_OFFSET TOOL={} AXIS={} AMOUNT={:.6}",
+            tool, axis, amount
+        )
+    }
+
+    fn adjust_offset(&mut self, tool: u32, axis: Axis, amount: f64, current: f64) -> String {
+        format!(
+            "TC_ADJUST_OFFSET TOOL={} AXIS={} AMOUNT={:.6}",
+            tool, axis, amount
+        )
+    }
+
+    /// the macros store them with SAVE_VARIABLE
+    fn persistence(&self) -> Persistence {
+        Persistence::SaveVariable
+    }
+
+    fn pick_tool(&mut self, tool: u32) -> String {
+        format!("T{}", tool)
+    }
+
+    fn drop_tool(&mut self) -> String {
+        "T_1".to_string()
+    }
+}
+
+/// <https://github.com/viesturz/klipper-toolchanger>
+#[derive(Debug, Default)]
+pub struct KlipperToolchanger;
+
+impl ToolchangerBackend for KlipperToolchanger {
+    fn name(&self) -> &'static str {
+        "klipper-toolchanger"
+    }
+
+    fn requirements(&self) -> Vec<Requirement> {
+        vec![Requirement::new(
+            "toolchanger",
+            &[
+                Feature::ReadOffsets,
+                Feature::WriteOffsets,
+                Feature::ToolChanges,
+            ],
+        )]
+    }
+
+    /// `[tool T0]` etc, the number comes from the name since `tool_number` needs a query
+    fn available_tools(&self, objects: &[String]) -> Vec<u32> {
+        let mut tools: Vec<u32> = objects
+            .iter()
+            .filter_map(|o| o.strip_prefix("tool "))
+            .filter_map(|name| {
+                let digits = name.trim_start_matches(|c: char| !c.is_ascii_digit());
+                digits.parse().ok()
+            })
+            .collect();
+        tools.sort();
+        tools
+    }
+
+    fn status_objects(&self, objects: &[String]) -> Value {
+        let mut out = serde_json::Map::new();
+        out.insert("toolchanger".to_string(), Value::Null);
+        for name in objects.iter().filter(|o| o.starts_with("tool ")) {
+            out.insert(name.clone(), Value::Null);
+        }
+        Value::Object(out)
+    }
+
+    fn read_offsets(&self, status: &Value) -> Result<Vec<(f64, f64, f64)>> {
+        let Some(objects) = status.as_object() else {
+            bail!("Invalid status: {:?}", status);
+        };
+
+        let mut offsets = vec![];
+        for (name, tool) in objects.iter().filter(|(k, _)| k.starts_with("tool ")) {
+            let Some(n) = tool["tool_number"].as_i64().filter(|n| *n >= 0) else {
+                debug!("Skipping {} without a tool number", name);
+                continue;
+            };
+            let get = |key: &str| {
+                tool[key]
+                    .as_f64()
+                    .ok_or_else(|| anyhow!("Failed to parse {} {}", name, key))
+            };
+            set_tool(
+                &mut offsets,
+                n as u32,
+                (
+                    get("gcode_x_offset")?,
+                    get("gcode_y_offset")?,
+                    get("gcode_z_offset")?,
+                ),
+            );
+        }
+
+        Ok(offsets)
+    }
+
+    fn active_tool_source(&self) -> ActiveToolSource {
+        ActiveToolSource::Toolchanger
+    }
+
+    fn set_offset(&mut self, tool: u32, axis: Axis, amount: f64) -> String {
+        format!(
+            "SET_TOOL_PARAMETER T={} PARAMETER=gcode_{}_offset VALUE={:.6}",
+            tool,
+            axis_name(axis),
+            amount
+        )
+    }
+
+    fn adjust_offset(&mut self, tool: u32, axis: Axis, amount: f64, current: f64) -> String {
+        self.set_offset(tool, axis, current + amount)
+    }
+
+    fn persistence(&self) -> Persistence {
+        Persistence::SaveConfig
+    }
+
+    /// Each changed parameter goes to the pending config, then all at once to printer.cfg
+    fn persist_offsets(&mut self, changes: &[OffsetChange]) -> String {
+        let mut lines: Vec<String> = vec![];
+        for c in changes {
+            let line = format!(
+                "SAVE_TOOL_PARAMETER T={} PARAMETER=gcode_{}_offset",
+                c.tool,
+                axis_name(c.axis)
+            );
+            if !lines.contains(&line) {
+                lines.push(line);
+            }
+        }
+        if lines.is_empty() {
+            return String::new();
+        }
+        lines.push("SAVE_CONFIG".to_string());
+        lines.join("\n")
+    }
+
+    fn pick_tool(&mut self, tool: u32) -> String {
+        format!("SELECT_TOOL T={}", tool)
+    }
+
+    fn drop_tool(&mut self) -> String {
+        "UNSELECT_TOOL".to_string()
+    }
+}
+
+#[derive(Debug)]
+pub struct SaveVariables {
+    pick_gcode: String,
+    drop_gcode: String,
+    active_tool: ActiveToolSource,
+}
+
+impl ToolchangerBackend for SaveVariables {
+    fn name(&self) -> &'static str {
+        "save_variables"
+    }
+
+    fn requirements(&self) -> Vec<Requirement> {
+        let mut out = vec![Requirement::new(
+            "save_variables",
+            &[Feature::ReadOffsets, Feature::WriteOffsets],
+        )];
+        out.extend(macro_requirement(
+            &self.pick_gcode,
+            0,
+            &[Feature::ToolChanges],
+        ));
+        out.extend(macro_requirement(
+            &self.drop_gcode,
+            0,
+            &[Feature::ToolChanges],
+        ));
+        out
+    }
+
+    fn available_tools(&self, objects: &[String]) -> Vec<u32> {
+        tools_with_macro(objects, &self.pick_gcode)
+    }
+
+    fn status_objects(&self, objects: &[String]) -> Value {
+        json!({ "save_variables": null })
+    }
+
+    fn read_offsets(&self, status: &Value) -> Result<Vec<(f64, f64, f64)>> {
+        offsets_from_variables(status)
+    }
+
+    fn active_tool_source(&self) -> ActiveToolSource {
+        self.active_tool.clone()
+    }
+
+    fn set_offset(&mut self, tool: u32, axis: Axis, amount: f64) -> String {
+        format!(
+            "SAVE_VARIABLE VARIABLE=t{}_{}_offset VALUE={:.6}",
+            tool,
+            axis_name(axis),
+            amount
+        )
+    }
+
+    fn adjust_offset(&mut self, tool: u32, axis: Axis, amount: f64, current: f64) -> String {
+        self.set_offset(tool, axis, current + amount)
+    }
+
+    fn persistence(&self) -> Persistence {
+        Persistence::SaveVariable
+    }
+
+    fn pick_tool(&mut self, tool: u32) -> String {
+        self.pick_gcode.replace("{tool}", &tool.to_string())
+    }
+
+    fn drop_tool(&mut self) -> String {
+        self.drop_gcode.clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct GcodeOffset {
+    pick_gcode: String,
+    drop_gcode: String,
+    offsets: Vec<(f64, f64, f64)>,
+    active_tool: ActiveToolSource,
+    /// last tool we mounted, to know when a new offset needs applying
+    active: Option<i32>,
+}
+
+impl GcodeOffset {
+    fn offset(&self, tool: u32) -> (f64, f64, f64) {
+        self.offsets
+            .get(tool as usize)
+            .copied()
+            .unwrap_or((0., 0., 0.))
+    }
+
+    fn apply(&self, (x, y, z): (f64, f64, f64)) -> String {
+        format!("SET_GCODE_OFFSET X={:.6} Y={:.6} Z={:.6}", x, y, z)
+    }
+}
+
+impl ToolchangerBackend for GcodeOffset {
+    fn name(&self) -> &'static str {
+        "SET_GCODE_OFFSET"
+    }
+
+    fn requirements(&self) -> Vec<Requirement> {
+        let mut out = vec![];
+        out.extend(macro_requirement(
+            &self.pick_gcode,
+            0,
+            &[Feature::ToolChanges],
+        ));
+        out.extend(macro_requirement(
+            &self.drop_gcode,
+            0,
+            &[Feature::ToolChanges],
+        ));
+        out
+    }
+
+    fn available_tools(&self, objects: &[String]) -> Vec<u32> {
+        tools_with_macro(objects, &self.pick_gcode)
+    }
+
+    fn status_objects(&self, objects: &[String]) -> Value {
+        json!({ "gcode_move": ["homing_origin"] })
+    }
+
+    /// Only the mounted tool's offset is on the printer, the rest are what we remember
+    fn read_offsets(&self, status: &Value) -> Result<Vec<(f64, f64, f64)>> {
+        let mut offsets = self.offsets.clone();
+        let Some(tool) = self.active.filter(|t| *t >= 0) else {
+            return Ok(offsets);
+        };
+        let v = status
+            .pointer("/gcode_move/homing_origin")
+            .ok_or_else(|| anyhow!("No gcode_move homing_origin in status"))?;
+        let origin = match (v[0].as_f64(), v[1].as_f64(), v[2].as_f64()) {
+            (Some(x), Some(y), Some(z)) => (x, y, z),
+            _ => bail!("Failed to parse homing_origin: {:?}", v),
+        };
+        set_tool(&mut offsets, tool as u32, origin);
+        Ok(offsets)
+    }
+
+    fn reads_back(&self, tool: u32) -> bool {
+        self.active == Some(tool as i32)
+    }
+
+    fn active_tool_source(&self) -> ActiveToolSource {
+        self.active_tool.clone()
+    }
+
+    fn set_offset(&mut self, tool: u32, axis: Axis, amount: f64) -> String {
+        let t = tool as usize;
+        if self.offsets.len() <= t {
+            set_tool(&mut self.offsets, tool, (0., 0., 0.));
+        }
+        set_axis(&mut self.offsets[t], axis, amount);
+
+        if self.active == Some(tool as i32) {
+            self.apply(self.offsets[t])
+        } else {
+            String::new()
+        }
+    }
+
+    fn adjust_offset(&mut self, tool: u32, axis: Axis, amount: f64, current: f64) -> String {
+        self.set_offset(tool, axis, current + amount)
+    }
+
+    fn persistence(&self) -> Persistence {
+        Persistence::Session
+    }
+
+    fn pick_tool(&mut self, tool: u32) -> String {
+        format!(
+            "{}\n{}",
+            self.pick_gcode.replace("{tool}", &tool.to_string()),
+            self.apply(self.offset(tool))
+        )
+    }
+
+    fn drop_tool(&mut self) -> String {
+        format!("{}\n{}", self.drop_gcode, self.apply((0., 0., 0.)))
+    }
+
+    fn tool_changed(&mut self, tool: Option<i32>) {
+        self.active = tool;
+    }
+
+    /// only if the printer can say, otherwise our own tool changes are all we know of
+    fn follows_active_tool(&self) -> bool {
+        self.active_tool != ActiveToolSource::LastToolChange
+    }
+
+    fn mounted_tool(&mut self, tool: i32) -> String {
+        if self.active == Some(tool) {
+            return String::new();
+        }
+        debug!("T{} mounted behind our back, applying its offset", tool);
+        self.active = Some(tool);
+        if tool < 0 {
+            self.apply((0., 0., 0.))
+        } else {
+            self.apply(self.offset(tool as u32))
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Which tools the printer has, from `configfile` or failing that `save_variables`,
+//! so tool numbers don't have to start at 0 or be contiguous
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use serde_json::Value;
+
+use super::heaters::extruder_name;
+
+/// Where a tool was found, in order of preference
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ToolSource {
+    /// klipper-toolchanger's `[tool <name>]`
+    ToolSection,
+    /// `[gcode_macro T<n>]`
+    Macro,
+    /// `t<n>_x_offset` in save_variables
+    SaveVariable,
+    /// nothing found, numbered from `num_tools` in the options
+    Options,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ToolInfo {
+    pub number: u32,
+    /// section or macro name, `T<n>` otherwise
+    pub name: String,
+    /// extruder object the tool heats with, if the printer has it
+    pub extruder: Option<String>,
+    /// where the tool parks, if the config says
+    pub dock: Option<(f64, f64)>,
+    pub source: ToolSource,
+}
+
+impl ToolInfo {
+    pub fn numbered(number: u32, source: ToolSource) -> Self {
+        Self {
+            number,
+            name: format!("T{}", number),
+            extruder: None,
+            dock: None,
+            source,
+        }
+    }
+
+    /// `T1 (left): extruder1, dock 14.0, 300.0`
+    pub fn describe(&self) -> String {
+        let mut out = format!("T{}", self.number);
+        if self.name != format!("T{}", self.number) {
+            out.push_str(&format!(" ({})", self.name));
+        }
+        let mut details = vec![];
+        if let Some(e) = &self.extruder {
+            details.push(e.clone());
+        }
+        if let Some((x, y)) = self.dock {
+            details.push(format!("dock {:.1}, {:.1}", x, y));
+        }
+        if !details.is_empty() {
+            out.push_str(&format!(": {}", details.join(", ")));
+        }
+        out
+    }
+}
+
+/// from `configfile.config`, `[tool ...]` sections if there are any, otherwise `T<n>` macros
+pub fn from_config(config: &Value) -> Vec<ToolInfo> {
+    let Some(sections) = config.as_object() else {
+        return vec![];
+    };
+
+    /// every value is a string
+    let get = |section: &Value, key: &str| -> Option<String> {
+        section
+            .get(key)
+            .and_then(|v| v.as_str())
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+    };
+    let get_f64 = |section: &Value, key: &str| get(section, key)?.parse::<f64>().ok();
+    let dock =
+        |section: &Value, x: &str, y: &str| Some((get_f64(section, x)?, get_f64(section, y)?));
+    let has_section = |name: &str| sections.contains_key(name);
+
+    let mut tools: Vec<ToolInfo> = vec![];
+    for (key, section) in sections.iter() {
+        let Some(name) = key.strip_prefix("tool ") else {
+            continue;
+        };
+        /// `tool_number` is optional, `T0` etc. is the usual name
+        let number = get(section, "tool_number")
+            .and_then(|n| n.parse().ok())
+            .or_else(|| {
+                name.trim_start_matches(|c: char| !c.is_ascii_digit())
+                    .parse()
+                    .ok()
+            });
+        let Some(number) = number else {
+            debug!("Skipping [{}] without a tool number", key);
+            continue;
+        };
+        tools.push(ToolInfo {
+            number,
+            name: name.to_string(),
+            extruder: get(section, "extruder"),
+            dock: dock(section, "params_park_x", "params_park_y"),
+            source: ToolSource::ToolSection,
+        });
+    }
+
+    if tools.is_empty() {
+        for (key, section) in sections.iter() {
+            let Some(number) = key
+                .strip_prefix("gcode_macro ")
+                .and_then(|m| m.strip_prefix(['T', 't']))
+                .and_then(|n| n.parse::<u32>().ok())
+            else {
+                continue;
+            };
+            tools.push(ToolInfo {
+                number,
+                name: format!("T{}", number),
+                /// macros don't say, go by klipper's naming
+                extruder: Some(extruder_name(number)).filter(|e| has_section(e)),
+                dock: dock(section, "variable_park_x", "variable_park_y"),
+                source: ToolSource::Macro,
+            });
+        }
+    }
+
+    tools.sort_by_key(|t| t.number);
+    tools.dedup_by_key(|t| t.number);
+    tools
+}
+
+/// from the `variables` of `save_variables`, every tool with a `t<n>_x_offset`
+pub fn from_variables(vars: &Value) -> Vec<ToolInfo> {
+    let Some(vars) = vars.as_object() else {
+        return vec![];
+    };
+    let mut tools: Vec<ToolInfo> = vars
+        .keys()
+        .filter_map(|k| k.strip_prefix('t')?.strip_suffix("_x_offset")?.parse().ok())
+        .map(|n| ToolInfo::numbered(n, ToolSource::SaveVariable))
+        .collect();
+    tools.sort_by_key(|t| t.number);
+    tools
+}
+
+/// Extruder object of `tool`, klipper's naming unless the config says otherwise
+pub fn extruder_for(tools: &[ToolInfo], tool: u32) -> String {
+    tools
+        .iter()
+        .find(|t| t.number == tool)
+        .and_then(|t| t.extruder.clone())
+        .unwrap_or_else(|| extruder_name(tool))
+}
+
+/// inverse of [`extruder_for`]
+pub fn tool_for_extruder(tools: &[ToolInfo], extruder: &str) -> Option<u32> {
+    match tools
+        .iter()
+        .find(|t| t.extruder.as_deref() == Some(extruder))
+    {
+        Some(t) => Some(t.number),
+        None => super::heaters::tool_for_extruder(extruder),
+    }
+}
+
+/// T0.. for when the printer didn't tell us
+pub fn from_options(num_tools: usize) -> Vec<ToolInfo> {
+    (0..num_tools as u32)
+        .map(|n| ToolInfo::numbered(n, ToolSource::Options))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn tools_from_config() {
+        let mut config = json!({
+            "extruder": {},
+            "extruder5": {},
+            "gcode_macro T0": { "gcode": "" },
+            "gcode_macro T5": { "gcode": "", "variable_park_x": "250", "variable_park_y": "300.5" },
+            "gcode_macro T_1": { "gcode": "" },
+            "gcode_macro TC_SET_OFFSET": { "gcode": "" },
+        });
+
+        let tools = from_config(&config);
+        assert_eq!(
+            tools.iter().map(|t| t.number).collect::<Vec<_>>(),
+            vec![0, 5]
+        );
+        assert_eq!(tools[0].extruder.as_deref(), Some("extruder"));
+        assert_eq!(tools[1].extruder.as_deref(), Some("extruder5"));
+        assert_eq!(tools[1].dock, Some((250., 300.5)));
+        assert_eq!(tools[1].source, ToolSource::Macro);
+
+        /// tool sections win over macros
+        config["tool left"] = json!({
+            "tool_number": "3",
+            "extruder": "extruder5",
+            "params_park_x": "14",
+            "params_park_y": "310",
+        });
+        config["tool T7"] = json!({});
+        let tools = from_config(&config);
+        assert_eq!(tools.len(), 2);
+        assert_eq!(
+            tools[0].describe(),
+            "T3 (left): extruder5, dock 14.0, 310.0"
+        );
+        assert_eq!(tools[1].describe(), "T7");
+        assert_eq!(tools[1].source, ToolSource::ToolSection);
+
+        assert_eq!(extruder_for(&tools, 3), "extruder5");
+        assert_eq!(extruder_for(&tools, 7), "extruder7");
+        assert_eq!(tool_for_extruder(&tools, "extruder5"), Some(3));
+        assert_eq!(tool_for_extruder(&tools, "extruder"), Some(0));
+    }
+
+    #[test]
+    fn tools_from_variables() {
+        let vars = json!({
+            "t0_x_offset": 0.0,
+            "t0_y_offset": 0.0,
+            "t12_x_offset": 0.1,
+            "tool_current": 0,
+        });
+        let tools = from_variables(&vars);
+        assert_eq!(
+            tools.iter().map(|t| t.number).collect::<Vec<_>>(),
+            vec![0, 12]
+        );
+    }
+}
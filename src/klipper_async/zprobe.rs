@@ -0,0 +1,171 @@
+//! Z offsets from touching every tool off on the same probe or Z switch
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use super::{
+    client::PrinterClient,
+    motion::MoveSpeed,
+    offsets::{self, OffsetChange},
+    KlipperMessage,
+};
+use crate::ui::ui_types::Axis;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ZProbeSettings {
+    /// XY over the probe or switch, G-code coordinates
+    pub position: Option<(f64, f64)>,
+    /// height to travel at, and to go back to between samples
+    pub start_z: f64,
+    /// `PROBE`, or a macro that ends the same way: `probe at x,y is z=...` printed,
+    /// or `last_z_result` of the `probe` object set
+    pub probe_gcode: String,
+    pub samples: usize,
+    /// the other tools' Z offsets are relative to this one
+    pub reference_tool: u32,
+}
+
+impl Default for ZProbeSettings {
+    fn default() -> Self {
+        Self {
+            position: None,
+            start_z: 10.,
+            probe_gcode: "PROBE".to_string(),
+            samples: 3,
+            reference_tool: 0,
+        }
+    }
+}
+
+/// `// probe at 150.000,150.000 is z=1.234567` -> 1.234567
+pub fn parse_probe_result(line: &str) -> Option<f64> {
+    let (_, z) = line.rsplit_once("is z=")?;
+    z.trim().parse().ok().filter(|z: &f64| z.is_finite())
+}
+
+/// Trigger heights are carriage Z. A nozzle that sticks out further triggers higher,
+/// and needs the same positive Z offset to print at the same height as the reference.
+pub fn relative_offsets(triggers: &[(u32, f64)], reference_tool: u32) -> Result<Vec<(u32, f64)>> {
+    let Some((_, reference)) = triggers.iter().find(|(t, _)| *t == reference_tool) else {
+        bail!("Reference tool T{} wasn't probed", reference_tool);
+    };
+    Ok(triggers.iter().map(|(t, z)| (*t, z - reference)).collect())
+}
+
+fn median(samples: &mut [f64]) -> f64 {
+    samples.sort_by(f64::total_cmp);
+    samples[samples.len() / 2]
+}
+
+/// Probe every tool in `tools` and report the other tools' Z offsets. The reference tool
+/// keeps its Z offset, the others are measured from it.
+/// Nothing is written, they're committed like any other measured offsets once confirmed.
+pub async fn calibrate_z<C: PrinterClient + ?Sized>(
+    client: &mut C,
+    settings: &ZProbeSettings,
+    tools: &[u32],
+    speed: MoveSpeed,
+) -> Result<()> {
+    let Some((x, y)) = settings.position else {
+        bail!("No Z probe position set");
+    };
+    ensure!(settings.samples > 0, "Z probe samples must be at least 1");
+    ensure!(
+        tools.contains(&settings.reference_tool),
+        "Reference tool T{} isn't one of the tools to probe",
+        settings.reference_tool
+    );
+
+    let mut triggers = vec![];
+    for &tool in tools {
+        client.pick_tool(tool).await?;
+        client
+            .move_to_position((x, y, settings.start_z), None, speed)
+            .await?;
+
+        let mut samples = vec![];
+        for i in 0..settings.samples {
+            let z = probe_once(client, &settings.probe_gcode).await?;
+            debug!("T{} sample {}: z={:.4}", tool, i, z);
+            samples.push(z);
+            client
+                .move_to_position((x, y, settings.start_z), None, speed)
+                .await?;
+        }
+
+        let z = median(&mut samples);
+        info!("T{} triggered at z={:.4}", tool, z);
+        triggers.push((tool, z));
+    }
+    client.dropoff_tool().await?;
+
+    let offsets = relative_offsets(&triggers, settings.reference_tool)?;
+    let current = client.read_tool_offsets().await?;
+    let reference_z = offsets::offset_of(&current, settings.reference_tool, Axis::Z).unwrap_or(0.);
+    let offsets: Vec<(u32, f64)> = offsets
+        .into_iter()
+        .filter(|(t, _)| *t != settings.reference_tool)
+        .map(|(t, z)| (t, reference_z + z))
+        .collect();
+
+    client
+        .inbox()
+        .send(KlipperMessage::ZOffsetsMeasured(offsets))
+        .map_err(|e| anyhow!("Failed to send Z offsets: {:?}", e))?;
+
+    Ok(())
+}
+
+/// What [`calibrate_z`] measured, as changes to commit
+pub fn z_changes(offsets: &[(u32, f64)]) -> Vec<OffsetChange> {
+    offsets
+        .iter()
+        .map(|(tool, z)| OffsetChange {
+            tool: *tool,
+            axis: Axis::Z,
+            value: *z,
+        })
+        .collect()
+}
+
+/// Carriage Z the probe triggered at
+async fn probe_once<C: PrinterClient + ?Sized>(client: &mut C, probe_gcode: &str) -> Result<f64> {
+    let lines = client.run_gcode_with_output(probe_gcode).await?;
+    if let Some(z) = lines.iter().rev().find_map(|l| parse_probe_result(l)) {
+        return Ok(z);
+    }
+
+    /// nothing printed, e.g. a macro that hides the output
+    let res = client
+        .query_objects(serde_json::json!({ "probe": ["last_z_result"] }))
+        .await?;
+    res.pointer("/status/probe/last_z_result")
+        .and_then(|v| v.as_f64())
+        .filter(|z| z.is_finite())
+        .ok_or_else(|| anyhow!("No probe result after {}", probe_gcode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_results() {
+        assert_eq!(
+            parse_probe_result("// probe at 150.000,150.000 is z=1.234567"),
+            Some(1.234567)
+        );
+        assert_eq!(
+            parse_probe_result("// probe at 150.000,150.000 is z=nan"),
+            None
+        );
+        assert_eq!(
+            parse_probe_result("// probe at 150.000,150.000 is z=inf"),
+            None
+        );
+        assert_eq!(parse_probe_result("ok"), None);
+
+        assert_eq!(median(&mut [2., 1., 3.]), 2.);
+    }
+}
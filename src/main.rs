@@ -10,7 +10,6 @@
 
 pub mod appconfig;
 pub mod klipper_async;
-// pub mod klipper_protocol;
 pub mod logging;
 pub mod saved_data;
 pub mod tests;
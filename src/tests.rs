@@ -129,6 +129,7 @@ pub fn _main_tests() -> Result<()> {
             &mut image,
             &settings,
             &mut detectors,
+            &[],
         ) {
             Err(e) => {
                 error!("Failed to locate nozzle in image {}: {}", path, e);
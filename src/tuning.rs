@@ -245,6 +245,7 @@ impl OptimizeData {
                 &img,
                 &self.vision_params,
                 &mut detectors,
+                &[],
             ) {
                 Err(e) => {
                     // error!("Failed to locate nozzle in image {}: {}", path, e);
@@ -495,6 +496,7 @@ impl OptimizeData {
                 &img,
                 &settings,
                 &mut detectors,
+                &[],
             ) {
                 Err(e) => {
                     // error!("Failed to locate nozzle in image {}: {}", path, e);
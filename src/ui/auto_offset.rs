@@ -9,6 +9,12 @@ use crate::vision::VisionSettings;
 
 use super::ui_types::{App, Axis};
 
+/// how long a tool change may take before auto offset gives up on it
+const TOOL_CHANGE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// how long heating a tool may take
+const HEAT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(600);
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AutoOffsetType {
     None,
@@ -34,22 +40,6 @@ impl App {
         //     self.running_average.clear();
         // }
 
-        if matches!(self.auto_offset.auto_offset_type, AutoOffsetType::AllTools) {
-            if self.auto_offset.current_tool == -1 {
-                /// reset tool offsets to 0
-                for tool in 0..self.options.num_tools {
-                    self.set_tool_offset(tool, Axis::X, 0.0);
-                    self.set_tool_offset(tool, Axis::Y, 0.0);
-                }
-
-                self.dropoff_tool();
-                self.pickup_tool(0, true);
-                self.auto_offset.current_tool = 0;
-
-                return;
-            }
-        }
-
         let mut confidence = self.running_average.confidence();
         let mut guess = self.running_average.current_guess();
 
@@ -141,47 +131,185 @@ impl App {
             });
         });
 
-        if confidence.is_some() && guess.is_some() {
-            let confidence = confidence.unwrap();
-            let guess = guess.unwrap();
+        self.auto_offset_step();
 
-            let (confidence, (c_x, c_y, c_r)) = confidence;
-            let (x, y, r) = guess;
-            let (x, y, r) = self._pixels_to_mm_from_center(x, y, r);
+        //
+    }
+
+    /// Act on the current running average, separate from the UI so it can run headless
+    pub fn auto_offset_step(&mut self) {
+        if self.auto_offset.auto_offset_type == AutoOffsetType::None {
+            /// finished or stopped, however that happened
+            if !self.auto_offset.heated_tools.is_empty() {
+                if self.options.heating.cooldown {
+                    self.cool_down();
+                }
+                self.auto_offset.heated_tools.clear();
+            }
+            return;
+        }
+
+        if self.get_adjusted_position().is_none() {
+            return;
+        }
+
+        if matches!(self.auto_offset.auto_offset_type, AutoOffsetType::AllTools) {
+            if self.auto_offset.current_tool == -1 {
+                let Some(first) = self.auto_offset.next_tool() else {
+                    self.errors.push("No tools to measure".to_string());
+                    self.auto_offset.stop();
+                    return;
+                };
+
+                /// offsets are left as they are until the user confirms the new ones,
+                /// samples are in carriage coordinates so they don't matter
+                self.dropoff_tool();
+                self.pickup_tool(first as i32, true);
+                self.auto_offset.current_tool = first as i32;
+                self.auto_offset.last_move = Instant::now();
 
-            if confidence < self.options.auto_offset_settings.min_confidence_for_move {
-                // ui.label("Confidence is too low to move");
                 return;
             }
+        }
 
-            if self.auto_offset.last_move.elapsed().as_secs_f64()
-                < self.options.auto_offset_settings.min_interval_between_moves
-            {
-                // ui.label("Waiting for interval between moves");
+        /// don't measure the wrong nozzle, if the printer can tell us which one is mounted
+        if let Some(tool) = self.get_active_tool() {
+            if tool != self.auto_offset.current_tool {
+                if self.auto_offset.last_move.elapsed() > TOOL_CHANGE_TIMEOUT {
+                    let msg = format!(
+                        "Expected T{} to be mounted, printer reports {}",
+                        self.auto_offset.current_tool,
+                        if tool < 0 {
+                            "no tool".to_string()
+                        } else {
+                            format!("T{}", tool)
+                        }
+                    );
+                    error!("{}", msg);
+                    self.errors.push(msg);
+                    self.auto_offset.stop();
+                }
                 return;
             }
+        }
+
+        if !self.auto_offset_heater_ready() {
+            /// the nozzle moves as it expands, don't average frames from before it settled
+            self.running_average.clear();
+            return;
+        }
 
-            let move_x = x;
-            let move_y = y;
+        /// frames from while the toolhead was moving would pull the average towards where it was
+        if !self.motion_settled() {
+            self.running_average.clear();
+            return;
+        }
+
+        let (Some(confidence), Some(guess)) = (
+            self.running_average.confidence(),
+            self.running_average.current_guess(),
+        ) else {
+            return;
+        };
 
-            let (move_x, move_y) = self._apply_screen_transform((move_x, move_y));
+        let (confidence, (c_x, c_y, c_r)) = confidence;
+        let (x, y, r) = guess;
+        let (x, y, r) = self._pixels_to_mm_from_center(x, y, r);
 
-            match self.auto_offset.auto_offset_type() {
-                AutoOffsetType::None => {}
-                AutoOffsetType::SingleTool => self._auto_offset_single(ui, (move_x, move_y)),
-                AutoOffsetType::AllTools => self._auto_offset_all(ui, (move_x, move_y)),
-                AutoOffsetType::RepeatabilityTest => {
-                    self._auto_offset_repeatability(ui, (move_x, move_y))
-                }
-                AutoOffsetType::HomingTest => {
-                    self._auto_offset_repeatability(ui, (move_x, move_y));
-                }
+        if confidence < self.options.auto_offset_settings.min_confidence_for_move {
+            // ui.label("Confidence is too low to move");
+            return;
+        }
+
+        let move_x = x;
+        let move_y = y;
+
+        let (move_x, move_y) = self._apply_screen_transform((move_x, move_y));
+
+        /// the nozzle should already be in view, a big jump means the detection is wrong
+        let max_move = self.options.safety.max_centering_move;
+        if move_x.abs() > max_move || move_y.abs() > max_move {
+            let msg = format!(
+                "Auto offset wanted to move ({:.3}, {:.3}), more than the {:.1} mm limit, stopping",
+                move_x, move_y, max_move
+            );
+            error!("{}", msg);
+            self.errors.push(msg);
+            self.auto_offset.stop();
+            return;
+        }
+
+        match self.auto_offset.auto_offset_type() {
+            AutoOffsetType::None => {}
+            AutoOffsetType::SingleTool => self._auto_offset_single((move_x, move_y)),
+            AutoOffsetType::AllTools => self._auto_offset_all((move_x, move_y)),
+            AutoOffsetType::RepeatabilityTest => self._auto_offset_repeatability((move_x, move_y)),
+            AutoOffsetType::HomingTest => {
+                self._auto_offset_repeatability((move_x, move_y));
             }
+        }
+    }
 
-            //
+    /// Heats the current tool if configured to, and refuses to measure while any
+    /// tool heater is still ramping or hasn't settled for long enough
+    fn auto_offset_heater_ready(&mut self) -> bool {
+        let tool = self.auto_offset.current_tool;
+        if tool < 0 {
+            return true;
+        }
+        let settings = self.options.heating.clone();
+
+        if settings.enabled && !self.auto_offset.heated_tools.contains(&tool) {
+            debug!("Heating T{} to {}", tool, settings.temperature);
+            self.set_tool_temperature(tool, settings.temperature);
+            self.auto_offset.heated_tools.push(tool);
+            self.auto_offset.heat_started = Instant::now();
+            self.auto_offset.settled_since = None;
+            return false;
         }
 
-        //
+        let Some(heater) = self.get_heater(tool) else {
+            /// heating is optional, nothing to wait for
+            if !settings.enabled {
+                return true;
+            }
+            let msg = format!("Can't heat T{}, the printer has no heater for it", tool);
+            error!("{}", msg);
+            self.errors.push(msg);
+            self.auto_offset.stop();
+            return false;
+        };
+
+        /// the new target may not have been reported yet
+        let wrong_target =
+            settings.enabled && (heater.target - settings.temperature).abs() > settings.tolerance;
+
+        if heater.is_ramping(settings.tolerance) || wrong_target {
+            self.auto_offset.settled_since = None;
+            if settings.enabled && self.auto_offset.heat_started.elapsed() > HEAT_TIMEOUT {
+                let msg = format!(
+                    "T{} didn't reach {:.0}°C in {} s, at {:.1}°C",
+                    tool,
+                    settings.temperature,
+                    HEAT_TIMEOUT.as_secs(),
+                    heater.temperature
+                );
+                error!("{}", msg);
+                self.errors.push(msg);
+                self.auto_offset.stop();
+            }
+            return false;
+        }
+
+        if !settings.enabled {
+            return true;
+        }
+
+        let settled = *self
+            .auto_offset
+            .settled_since
+            .get_or_insert_with(Instant::now);
+        settled.elapsed().as_secs_f64() >= settings.settle_time
     }
 
     fn auto_offset_controls(&mut self, ui: &mut egui::Ui) {
@@ -195,13 +323,8 @@ impl App {
                 .show_value(true),
             );
 
-            ui.label(format!(
-                "(resolution: {:.5})",
-                self.klipper_status_frame
-                    .as_ref()
-                    .map(|f| f.resolution)
-                    .unwrap_or(-1.)
-            ));
+            let (x, y) = self.options.auto_offset_settings.resolution;
+            ui.label(format!("(resolution: X {:.5}, Y {:.5})", x, y));
         });
 
         ui.horizontal(|ui| {
@@ -226,22 +349,39 @@ impl App {
                 "Park tool/control test: ",
             );
         });
+
+        ui.horizontal(|ui| {
+            let heating = &mut self.options.heating;
+            ui.checkbox(&mut heating.enabled, "Heat tools to");
+            ui.add(
+                egui::DragValue::new(&mut heating.temperature)
+                    .range(0.0..=350.0)
+                    .suffix("°C"),
+            );
+            ui.label("settle for");
+            ui.add(
+                egui::DragValue::new(&mut heating.settle_time)
+                    .range(0.0..=300.0)
+                    .suffix(" s"),
+            );
+            ui.checkbox(&mut heating.cooldown, "Cool down after");
+        });
     }
 
-    fn _auto_offset_single(&mut self, ui: &mut egui::Ui, (x, y): (f64, f64)) {
+    fn _auto_offset_single(&mut self, (x, y): (f64, f64)) {
         if x.abs() < self.options.auto_offset_settings.target_max_offset
             && y.abs() < self.options.auto_offset_settings.target_max_offset
         {
             // ui.label("Offset is within target range");
-            self.auto_offset.stop();
+            self.auto_offset_single_done();
             return;
         }
 
         /// if the nozzle isn't centered, but the offset is too small to move, stop
-        if x.abs() < self.options.auto_offset_settings.resolution
-            && y.abs() < self.options.auto_offset_settings.resolution
+        if x.abs() < self.options.auto_offset_settings.resolution.0
+            && y.abs() < self.options.auto_offset_settings.resolution.1
         {
-            self.auto_offset.stop();
+            self.auto_offset_single_done();
             return;
         }
 
@@ -251,7 +391,7 @@ impl App {
         // self.move_axis_relative(Axis::Y, y, true);
 
         if x.abs() > self.options.auto_offset_settings.target_max_offset
-            && x.abs() > self.options.auto_offset_settings.resolution
+            && x.abs() > self.options.auto_offset_settings.resolution.0
         {
             if x.abs() < 0.01 {
                 debug!("Fine tuning X axis: ({:.5})", x / 4.);
@@ -262,7 +402,7 @@ impl App {
         }
 
         if y.abs() > self.options.auto_offset_settings.target_max_offset
-            && y.abs() > self.options.auto_offset_settings.resolution
+            && y.abs() > self.options.auto_offset_settings.resolution.1
         {
             if y.abs() < 0.01 {
                 debug!("Fine tuning Y axis: ({:.5})", y / 4.);
@@ -275,10 +415,21 @@ impl App {
         self.auto_offset.last_move = Instant::now();
     }
 
+    fn auto_offset_single_done(&mut self) {
+        let tool = self.auto_offset.current_tool;
+        match self.get_adjusted_position() {
+            Some(pos) => self.auto_offset.finish(format!(
+                "T{} centered at X={:.4} Y={:.4}",
+                tool, pos.0, pos.1
+            )),
+            None => self.auto_offset.finish(format!("T{} centered", tool)),
+        }
+    }
+
     /// to auto offset all tools:
     /// first, pick up each tool, measure offset, and save it
     /// repeat multiple times to get a good average
-    fn _auto_offset_all(&mut self, ui: &mut egui::Ui, (x, y): (f64, f64)) {
+    fn _auto_offset_all(&mut self, (x, y): (f64, f64)) {
         // debug!("_auto_offset_all");
 
         let mut stop = false;
@@ -291,36 +442,55 @@ impl App {
         }
 
         /// if the nozzle isn't centered, but the offset is too small to move, stop
-        if x.abs() < self.options.auto_offset_settings.resolution
-            && y.abs() < self.options.auto_offset_settings.resolution
+        if x.abs() < self.options.auto_offset_settings.resolution.0
+            && y.abs() < self.options.auto_offset_settings.resolution.1
         {
             stop = true;
         }
 
         if stop {
-            let Some(pos) = self.get_adjusted_position() else {
+            let (Some(pos), Some(origin)) =
+                (self.get_carriage_position(), self.get_homing_origin())
+            else {
                 warn!("No position data available");
                 return;
             };
-            self.auto_offset.offsets[self.auto_offset.current_tool as usize]
-                .push(((pos.0, pos.1), (x, y)));
+            let Some(i) = self.auto_offset.tool_index() else {
+                error!("T{} isn't being measured", self.auto_offset.current_tool);
+                self.auto_offset.stop();
+                return;
+            };
+            if i == 0 {
+                self.auto_offset.reference_offset = (origin.0, origin.1);
+            }
+            self.auto_offset.offsets[i].push(((pos.0, pos.1), (x, y)));
 
-            if self.auto_offset.offsets[self.auto_offset.current_tool as usize].len()
+            if self.auto_offset.offsets[i].len()
                 >= self.options.auto_offset_settings.samples_per_tool
             {
-                if self.auto_offset.current_tool == self.options.num_tools as i32 - 1 {
-                    // done sampling all tools
-
-                    self.process_offsets();
-
-                    self.auto_offset.stop();
-                } else {
+                if let Some(next) = self.auto_offset.next_tool() {
                     // finished sampling this tool, move to next
-                    self.auto_offset.current_tool += 1;
+                    self.auto_offset.current_tool = next as i32;
+                    self.remote_call_progress(format!(
+                        "measuring T{}",
+                        self.auto_offset.current_tool
+                    ));
                     self.pickup_tool(self.auto_offset.current_tool, true);
 
                     self.running_average.clear();
-                    self.auto_offset.last_move = Instant::now() + std::time::Duration::from_secs(1);
+                    self.auto_offset.last_move = Instant::now();
+                } else {
+                    // done sampling all tools
+
+                    let offsets = self.process_offsets();
+
+                    let result = offsets
+                        .iter()
+                        .map(|(t, (x, y))| format!("T{} X={:.4} Y={:.4}", t, x, y))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    self.auto_offset
+                        .finish(format!("offsets {}, waiting to be confirmed", result));
                 }
             } else {
                 /// found center, parking and unparking
@@ -328,14 +498,14 @@ impl App {
                 self.pickup_tool(self.auto_offset.current_tool, true);
 
                 self.running_average.clear();
-                self.auto_offset.last_move = Instant::now() + std::time::Duration::from_secs(1);
+                self.auto_offset.last_move = Instant::now();
             }
             return;
         } else {
             debug!("Moving to center: ({:.4}, {:.4})", x, y);
 
             if x.abs() > self.options.auto_offset_settings.target_max_offset
-                && x.abs() > self.options.auto_offset_settings.resolution
+                && x.abs() > self.options.auto_offset_settings.resolution.0
             {
                 if x.abs() < 0.02 {
                     debug!("Fine tuning X axis: ({:.5})", x / 4.);
@@ -346,7 +516,7 @@ impl App {
             }
 
             if y.abs() > self.options.auto_offset_settings.target_max_offset
-                && y.abs() > self.options.auto_offset_settings.resolution
+                && y.abs() > self.options.auto_offset_settings.resolution.1
             {
                 if y.abs() < 0.02 {
                     debug!("Fine tuning Y axis: ({:.5})", y / 4.);
@@ -360,7 +530,7 @@ impl App {
         }
     }
 
-    fn _auto_offset_repeatability(&mut self, ui: &mut egui::Ui, (x, y): (f64, f64)) {
+    fn _auto_offset_repeatability(&mut self, (x, y): (f64, f64)) {
         let mut stop = false;
 
         debug!("Checking repeatability: ({:.4}, {:.4})", x, y);
@@ -373,8 +543,8 @@ impl App {
         }
 
         /// if the nozzle isn't centered, but the offset is too small to move, stop
-        if x.abs() < self.options.auto_offset_settings.resolution
-            && y.abs() < self.options.auto_offset_settings.resolution
+        if x.abs() < self.options.auto_offset_settings.resolution.0
+            && y.abs() < self.options.auto_offset_settings.resolution.1
         {
             stop = true;
         }
@@ -390,7 +560,8 @@ impl App {
         if stop {
             if self.auto_offset.check_repeatability == 0 {
                 let t = self.auto_offset.auto_offset_type;
-                self.auto_offset.stop();
+                let summary = self.auto_offset.repeatability_summary();
+                self.auto_offset.finish(summary);
 
                 self.auto_offset
                     .process_repeatibility(matches!(t, AutoOffsetType::HomingTest));
@@ -400,6 +571,10 @@ impl App {
                 debug!("Found center, adding to repeatability data");
 
                 self.auto_offset.check_repeatability -= 1;
+                self.remote_call_progress(format!(
+                    "{} pickups left",
+                    self.auto_offset.check_repeatability
+                ));
 
                 /// save screenshot
                 #[cfg(feature = "nope")]
@@ -444,8 +619,7 @@ impl App {
                         }
                         self.pickup_tool(self.auto_offset.current_tool, true);
                         self.running_average.clear();
-                        self.auto_offset.last_move =
-                            Instant::now() + std::time::Duration::from_secs(1);
+                        self.auto_offset.last_move = Instant::now();
                     }
                     AutoOffsetType::HomingTest => {
                         let Some(cam_pos) = self.camera_pos else {
@@ -456,8 +630,7 @@ impl App {
                         self.home_xy();
                         self.move_to_position(cam_pos, true);
                         self.running_average.clear();
-                        self.auto_offset.last_move =
-                            Instant::now() + std::time::Duration::from_secs(1);
+                        self.auto_offset.last_move = Instant::now();
                     }
                     _ => unreachable!(),
                 }
@@ -469,7 +642,7 @@ impl App {
         debug!("Moving to center: ({:.4}, {:.4})", x, y);
 
         if x.abs() > self.options.auto_offset_settings.target_max_offset
-            && x.abs() > self.options.auto_offset_settings.resolution
+            && x.abs() > self.options.auto_offset_settings.resolution.0
         {
             if x.abs() < 0.02 {
                 debug!("Fine tuning X axis: ({:.5})", x / 4.);
@@ -480,7 +653,7 @@ impl App {
         }
 
         if y.abs() > self.options.auto_offset_settings.target_max_offset
-            && y.abs() > self.options.auto_offset_settings.resolution
+            && y.abs() > self.options.auto_offset_settings.resolution.1
         {
             if y.abs() < 0.02 {
                 debug!("Fine tuning Y axis: ({:.5})", y / 4.);
@@ -547,3 +720,152 @@ fn correct_distortion(dx: f64, dy: f64) -> (f64, f64) {
 
     (corrected_x, corrected_y)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::klipper_async::{sim_printer::SimSettings, ConnectionState, KlipperMessage};
+    use crate::ui::offset_commit::OffsetCommitState;
+    use crate::vision::{
+        blob_detection::BlobDetectors, locate_nozzle::locate_nozzle,
+        synthetic_camera::SyntheticCamera,
+    };
+
+    fn sim_app(sim: SimSettings, num_tools: usize) -> App {
+        let mut app = App::default();
+        app.options.simulate = true;
+        app.options.num_tools = num_tools;
+        app.options.sim_settings = sim;
+        app.options.motion.settle_time = 0.;
+        app.start_klipper_thread().unwrap();
+
+        let t0 = Instant::now();
+        while !matches!(app.klipper_connection, ConnectionState::Connected) {
+            let msgs: Vec<_> = app.inbox.read_without_ctx().collect();
+            if msgs.iter().any(|m| matches!(m, KlipperMessage::Connected)) {
+                app.klipper_connection = ConnectionState::Connected;
+            }
+            assert!(t0.elapsed() < std::time::Duration::from_secs(5));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        app
+    }
+
+    /// no status yet, so no heater, which only matters if heating is on
+    #[test]
+    fn heater_ready_without_heater() {
+        let mut app = App::default();
+        app.auto_offset.current_tool = 1;
+        assert!(!app.options.heating.enabled);
+        assert!(app.get_heater(1).is_none());
+        assert!(app.auto_offset_heater_ready());
+    }
+
+    /// Locate All Nozzles against the simulated printer, and compare with the true nozzle offsets
+    #[test]
+    fn locate_all_nozzles_headless() {
+        let num_tools = 3;
+        let sim = SimSettings {
+            nozzle_offsets: vec![(0., 0.), (0.35, -0.2), (-0.15, 0.45)],
+            backlash: (0.02, 0.02),
+            position_noise: 0.002,
+            pickup_noise: 0.003,
+            camera_position: (150., 150.),
+            image_noise: 0.,
+            seed: 1,
+        };
+        let mut app = sim_app(sim.clone(), num_tools);
+
+        app.home_all();
+        app.camera_pos = Some(sim.camera_position);
+
+        let state = app.sim_printer.clone().unwrap();
+        /// offsets from an earlier calibration, the run shouldn't touch them
+        let before = [(0.05, -0.05), (0.1, 0.), (0., 0.)];
+        for (tool, (x, y)) in before.iter().enumerate() {
+            let mut state = state.lock();
+            state
+                .variables
+                .insert(format!("t{}_x_offset", tool), serde_json::json!(x));
+            state
+                .variables
+                .insert(format!("t{}_y_offset", tool), serde_json::json!(y));
+        }
+
+        let mut camera = SyntheticCamera::new(
+            state.clone(),
+            (
+                app.options.camera_size.0 as u32,
+                app.options.camera_size.1 as u32,
+            ),
+            app.options.swap_axes,
+            app.options.mirror_axes,
+        );
+        let mut detectors = BlobDetectors::new().unwrap();
+        let settings = app.vision_settings;
+
+        let tools = app.tool_numbers();
+        app.auto_offset.start_all_tools(sim.camera_position, tools);
+
+        for _ in 0..500 {
+            if app.auto_offset.auto_offset_type() == AutoOffsetType::None {
+                break;
+            }
+
+            /// queued behind any moves, so the camera sees where the last step left the nozzle
+            app.fetch_position();
+            let t0 = Instant::now();
+            while !app.motion_settled() {
+                assert!(t0.elapsed() < std::time::Duration::from_secs(5));
+                std::thread::sleep(std::time::Duration::from_millis(5));
+            }
+
+            app.running_average.clear();
+            for _ in 0..10 {
+                let frame = camera.render(&settings);
+                let (_, circle) = locate_nozzle(&frame, &settings, &mut detectors, &[]).unwrap();
+                app.running_average.add_frame(circle);
+            }
+
+            app.auto_offset_step();
+        }
+        assert_eq!(app.auto_offset.auto_offset_type(), AutoOffsetType::None);
+
+        /// nothing is written until the diff is confirmed
+        let commit = app.offset_commit.clone().expect("No offsets proposed");
+        assert!(matches!(commit.state, OffsetCommitState::Proposed));
+        assert_eq!(commit.changes.len(), 2 * (num_tools - 1));
+        for (tool, (x, y)) in before.iter().enumerate() {
+            assert_eq!(state.lock().tool_offset(tool as i32), (*x, *y, 0.));
+        }
+        app.commit_offsets(commit.changes);
+
+        let t0 = Instant::now();
+        'commit: loop {
+            for msg in app.inbox.read_without_ctx() {
+                if let KlipperMessage::OffsetsCommitted(report) = msg {
+                    assert_eq!(report.attempts, 1);
+                    break 'commit;
+                }
+            }
+            assert!(t0.elapsed() < std::time::Duration::from_secs(5));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        /// T0 keeps its offset, the others are measured relative to it
+        let state = state.lock();
+        assert_eq!(state.tool_offset(0), (before[0].0, before[0].1, 0.));
+        for tool in 1..num_tools {
+            let expected = sim.expected_tool_offset(tool);
+            let expected = (expected.0 + before[0].0, expected.1 + before[0].1);
+            let found = state.tool_offset(tool as i32);
+            assert!(
+                (found.0 - expected.0).abs() < 0.02 && (found.1 - expected.1).abs() < 0.02,
+                "T{}: expected {:?}, found {:?}",
+                tool,
+                expected,
+                found
+            );
+        }
+    }
+}
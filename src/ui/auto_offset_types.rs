@@ -5,6 +5,7 @@ use egui::RichText;
 use egui_extras::Column;
 use std::time::Instant;
 
+use crate::klipper_async::offsets::OffsetChange;
 use crate::ui::ui_types::Axis;
 
 use super::{auto_offset::AutoOffsetType, ui_types::App};
@@ -24,7 +25,21 @@ pub struct AutoOffset {
     /// (position, guessed offset from center)
     pub(super) repeatability: Vec<((f64, f64), (f64, f64))>,
 
+    /// tool numbers to measure with Locate All Nozzles, the first is the reference
+    pub(super) tools: Vec<u32>,
+    /// samples of each of `tools`, in the same order, carriage coordinates
     pub(super) offsets: Vec<Vec<((f64, f64), (f64, f64))>>,
+    /// XY of `homing_origin` while the first tool was sampled, its offset as the printer has it
+    pub(super) reference_offset: (f64, f64),
+
+    /// tools this run turned the heater on for, cooled down when it stops
+    pub(super) heated_tools: Vec<i32>,
+    pub(super) heat_started: Instant,
+    /// when the current tool got within tolerance of its target
+    pub(super) settled_since: Option<Instant>,
+
+    /// what a run found, None if it was stopped before finishing
+    pub(super) result: Option<String>,
 }
 
 // #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
@@ -33,8 +48,8 @@ pub struct AutoOffsetSettings {
     pub target_max_offset: f64,
     // pub max_margin_of_error: f64,
     pub min_confidence_for_move: f64,
-    pub min_interval_between_moves: f64,
-    pub resolution: f64,
+    /// mm per microstep in X and Y, filled from the printer's config once it's been read
+    pub resolution: (f64, f64),
     pub park_tool: bool,
     pub samples_per_tool: usize,
 }
@@ -45,8 +60,8 @@ impl Default for AutoOffsetSettings {
             // target_max_offset: 0.01,
             target_max_offset: 0.00625,
             min_confidence_for_move: 0.95,
-            min_interval_between_moves: 2.0,
-            resolution: 0.00625,
+            /// until the printer's config has been read
+            resolution: (0.00625, 0.00625),
             park_tool: true,
             samples_per_tool: 3,
             // samples_per_tool: 5,
@@ -64,7 +79,13 @@ impl Default for AutoOffset {
             check_repeatability: 0,
             current_n: 0,
             repeatability: Vec::new(),
+            tools: Vec::new(),
+            reference_offset: (0.0, 0.0),
             offsets: Vec::new(),
+            heated_tools: Vec::new(),
+            heat_started: Instant::now(),
+            settled_since: None,
+            result: None,
         }
     }
 }
@@ -79,6 +100,17 @@ impl AutoOffset {
         self.auto_offset_type = AutoOffsetType::None;
     }
 
+    /// Stop after a successful run
+    pub fn finish(&mut self, result: String) {
+        info!("Auto offset finished: {}", result);
+        self.auto_offset_type = AutoOffsetType::None;
+        self.result = Some(result);
+    }
+
+    pub fn result(&self) -> Option<&str> {
+        self.result.as_deref()
+    }
+
     pub fn start_single(&mut self, pos: (f64, f64), tool: i32) {
         *self = Self::default();
 
@@ -87,14 +119,30 @@ impl AutoOffset {
         self.current_tool = tool;
     }
 
-    pub fn start_all_tools(&mut self, pos: (f64, f64), num_tools: usize) {
+    pub fn start_all_tools(&mut self, pos: (f64, f64), tools: Vec<u32>) {
         *self = Self::default();
 
         self.auto_offset_type = AutoOffsetType::AllTools;
         self.prev_position = pos;
         self.current_tool = -1;
 
-        self.offsets = vec![vec![]; num_tools];
+        self.offsets = vec![vec![]; tools.len()];
+        self.tools = tools;
+    }
+
+    /// Where the current tool is in [`Self::tools`]
+    pub(super) fn tool_index(&self) -> Option<usize> {
+        self.tools
+            .iter()
+            .position(|t| *t as i32 == self.current_tool)
+    }
+
+    /// Tool to measure after the current one, None once they're all done
+    pub(super) fn next_tool(&self) -> Option<u32> {
+        match self.tool_index() {
+            Some(i) => self.tools.get(i + 1).copied(),
+            None => self.tools.first().copied(),
+        }
     }
 
     pub fn start_repeatability(&mut self, pos: (f64, f64), tool: i32) {
@@ -241,13 +289,48 @@ impl AutoOffset {
         }
     }
 
+    /// standard deviation and range of the centered positions
+    pub fn repeatability_summary(&self) -> String {
+        let stats = |vals: Vec<f64>| {
+            let n = vals.len().max(1) as f64;
+            let mean = vals.iter().sum::<f64>() / n;
+            let std_dev = (vals.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n).sqrt();
+            let min = vals.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = vals.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            (std_dev, max - min)
+        };
+        let (std_x, range_x) = stats(
+            self.repeatability
+                .iter()
+                .map(|((x, _), (offset, _))| *x + offset)
+                .collect(),
+        );
+        let (std_y, range_y) = stats(
+            self.repeatability
+                .iter()
+                .map(|((_, y), (_, offset))| *y + offset)
+                .collect(),
+        );
+        format!(
+            "T{} over {} pickups: StdDev X={:.4} Y={:.4}, Range X={:.4} Y={:.4}",
+            self.current_tool,
+            self.repeatability.len(),
+            std_x,
+            std_y,
+            range_x,
+            range_y
+        )
+    }
+
     pub fn repeatability_count_mut(&mut self) -> &mut usize {
         &mut self.check_repeatability
     }
 }
 
 impl App {
-    pub fn process_offsets(&mut self) {
+    /// XY offsets of every tool but the first, relative to the first, proposed for the user to confirm.
+    /// The first tool keeps its offset, the others are measured from it.
+    pub fn process_offsets(&mut self) -> Vec<(u32, (f64, f64))> {
         warn!("TODO: process offsets");
 
         #[cfg(feature = "nope")]
@@ -265,9 +348,10 @@ impl App {
         }
 
         let mut camera_pos = (0.0, 0.0);
+        let mut out = vec![];
 
-        for tool in 0..self.options.num_tools {
-            let offsets = self.auto_offset.offsets[tool as usize].clone();
+        for (i, tool) in self.auto_offset.tools.iter().copied().enumerate() {
+            let offsets = self.auto_offset.offsets[i].clone();
 
             let mut xs = offsets
                 .iter()
@@ -285,20 +369,34 @@ impl App {
             let median_x = xs[xs.len() / 2];
             let median_y = ys[ys.len() / 2];
 
-            if tool == 0 {
+            if i == 0 {
                 camera_pos = (median_x, median_y);
             } else {
-                let offset_x = median_x - camera_pos.0;
-                let offset_y = median_y - camera_pos.1;
+                let offset_x = self.auto_offset.reference_offset.0 + median_x - camera_pos.0;
+                let offset_y = self.auto_offset.reference_offset.1 + median_y - camera_pos.1;
 
                 debug!(
-                    "Setting tool {} offset: ({:.3}, {:.3})",
+                    "Measured tool {} offset: ({:.3}, {:.3})",
                     tool, offset_x, offset_y
                 );
 
-                self.set_tool_offset(tool, Axis::X, offset_x);
-                self.set_tool_offset(tool, Axis::Y, offset_y);
+                out.push((tool, (offset_x, offset_y)));
             }
         }
+
+        /// written all at once, and only after the user has seen the diff
+        let changes = out
+            .iter()
+            .flat_map(|(tool, (x, y))| {
+                [(Axis::X, *x), (Axis::Y, *y)].map(|(axis, value)| OffsetChange {
+                    tool: *tool,
+                    axis,
+                    value,
+                })
+            })
+            .collect();
+        self.propose_offsets(changes);
+
+        out
     }
 }
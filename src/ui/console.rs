@@ -0,0 +1,156 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use egui::{Color32, RichText};
+use std::collections::VecDeque;
+
+use super::ui_types::App;
+
+/// lines kept in the scrollback
+const MAX_LINES: usize = 1000;
+/// commands kept in the history
+const MAX_HISTORY: usize = 100;
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Console {
+    /// everything the printer echoed, and what was sent from here
+    #[serde(skip)]
+    pub lines: VecDeque<ConsoleLine>,
+    #[serde(skip)]
+    pub input: String,
+    /// oldest first, kept between runs
+    pub history: Vec<String>,
+    /// index into `history` while stepping through it with the arrow keys
+    #[serde(skip)]
+    history_pos: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConsoleLine {
+    /// sent from the console
+    Command(String),
+    /// from `notify_gcode_response`
+    Response(String),
+}
+
+impl ConsoleLine {
+    /// klipper prefixes errors with `!!`
+    pub fn is_error(&self) -> bool {
+        matches!(self, ConsoleLine::Response(s) if s.starts_with("!!"))
+    }
+}
+
+impl Console {
+    pub fn push(&mut self, line: ConsoleLine) {
+        if self.lines.len() >= MAX_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    /// Take the input as a command, adding it to the history
+    fn submit(&mut self) -> Option<String> {
+        let cmd = self.input.trim().to_string();
+        self.input.clear();
+        self.history_pos = None;
+        if cmd.is_empty() {
+            return None;
+        }
+
+        if self.history.last() != Some(&cmd) {
+            self.history.push(cmd.clone());
+            if self.history.len() > MAX_HISTORY {
+                self.history.remove(0);
+            }
+        }
+        self.push(ConsoleLine::Command(cmd.clone()));
+        Some(cmd)
+    }
+
+    fn history_prev(&mut self) {
+        if self.history.is_empty() {
+            return;
+        }
+        let pos = match self.history_pos {
+            None => self.history.len() - 1,
+            Some(p) => p.saturating_sub(1),
+        };
+        self.history_pos = Some(pos);
+        self.input = self.history[pos].clone();
+    }
+
+    fn history_next(&mut self) {
+        let Some(pos) = self.history_pos else {
+            return;
+        };
+        if pos + 1 < self.history.len() {
+            self.history_pos = Some(pos + 1);
+            self.input = self.history[pos + 1].clone();
+        } else {
+            self.history_pos = None;
+            self.input.clear();
+        }
+    }
+}
+
+impl App {
+    pub fn console(&mut self, ctx: &egui::Context) {
+        egui::TopBottomPanel::bottom("console_input").show(ctx, |ui| {
+            self.console_input(ui);
+        });
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("Clear").clicked() {
+                    self.console.lines.clear();
+                }
+            });
+            ui.separator();
+
+            egui::ScrollArea::vertical()
+                .auto_shrink([false, false])
+                .stick_to_bottom(true)
+                .show(ui, |ui| {
+                    for line in self.console.lines.iter() {
+                        let text = match line {
+                            ConsoleLine::Command(s) => RichText::new(format!("> {}", s))
+                                .color(Color32::from_rgb(50, 158, 244)),
+                            ConsoleLine::Response(s) if line.is_error() => {
+                                RichText::new(s).color(Color32::from_rgb(255, 80, 80))
+                            }
+                            ConsoleLine::Response(s) => RichText::new(s),
+                        };
+                        ui.label(text.monospace());
+                    }
+                });
+        });
+    }
+
+    fn console_input(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            let resp = ui.add(
+                egui::TextEdit::singleline(&mut self.console.input)
+                    .hint_text("G-code")
+                    .font(egui::TextStyle::Monospace)
+                    .desired_width(ui.available_width() - 60.),
+            );
+
+            if resp.has_focus() {
+                if ui.input(|i| i.key_pressed(egui::Key::ArrowUp)) {
+                    self.console.history_prev();
+                }
+                if ui.input(|i| i.key_pressed(egui::Key::ArrowDown)) {
+                    self.console.history_next();
+                }
+            }
+
+            let enter = resp.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            if enter || ui.button("Send").clicked() {
+                if let Some(cmd) = self.console.submit() {
+                    self.run_gcode(cmd);
+                }
+                resp.request_focus();
+            }
+        });
+    }
+}
@@ -1,9 +1,366 @@
 use anyhow::{anyhow, bail, ensure, Context, Result};
+use egui::{Color32, RichText};
 use tracing::{debug, error, info, trace, warn};
 
-use crate::klipper_async::KlipperCommand;
+use crate::klipper_async::{
+    endpoint::{MoonrakerAuth, MoonrakerEndpoint, Transport},
+    heaters::HeaterState,
+    http_client::HttpClient,
+    offsets::OffsetChange,
+    remote::RespondType,
+    tools::{self, ToolInfo},
+    CommandRunner, ConnectionState, Interrupt, KlipperCommand, KlipperConn, KlippyState,
+};
 
 use super::ui_types::*;
+#[cfg(any(test, feature = "sim"))]
+use crate::klipper_async::mock_moonraker::{MockMoonraker, MockPrinterState};
+
+pub const ESTOP_SHORTCUT: egui::KeyboardShortcut =
+    egui::KeyboardShortcut::new(egui::Modifiers::SHIFT, egui::Key::Escape);
+pub const ABORT_SHORTCUT: egui::KeyboardShortcut =
+    egui::KeyboardShortcut::new(egui::Modifiers::NONE, egui::Key::Escape);
+
+/// connection
+impl App {
+    /// Spawn the tokio runtime running [`KlipperConn`], or [`HttpClient`] with `transport = "http"`.
+    /// The connection itself is made (and remade) in the background.
+    /// With `options.simulate`, a `MockMoonraker` is started in the same runtime and used instead.
+    pub fn start_klipper_thread(&mut self) -> Result<()> {
+        debug!("starting klipper thread");
+        let simulate = self.options.simulate && cfg!(any(test, feature = "sim"));
+        if self.options.simulate && !simulate {
+            warn!("Built without the sim feature, connecting to the printer instead");
+        }
+        let endpoint = if simulate {
+            None
+        } else {
+            Some(MoonrakerEndpoint::parse(
+                &self.options.printer_url,
+                self.options.printer_auth.clone(),
+            )?)
+        };
+
+        #[cfg(any(test, feature = "sim"))]
+        let sim_state = if simulate {
+            info!("Simulating printer");
+            let state = MockPrinterState::new(self.options.num_tools)
+                .with_sim(self.options.sim_settings.clone());
+            Some(std::sync::Arc::new(parking_lot::Mutex::new(state)))
+        } else {
+            None
+        };
+        #[cfg(any(test, feature = "sim"))]
+        {
+            self.sim_printer = sim_state.clone();
+        }
+        /// found again once the new connection reads the config
+        self.printer_tools.clear();
+
+        let toolchanger = self.options.toolchanger.backend();
+        let safety = self.options.safety.clone();
+        let transport = self.options.transport;
+        let recorder = self
+            .options
+            .record_session
+            .as_ref()
+            .map(|path| crate::klipper_async::recorder::SessionRecorder::create(path))
+            .transpose()?;
+        debug!("toolchanger backend: {}", toolchanger.name());
+        debug!("transport: {}", transport);
+
+        // debug!("url = {}", url);
+
+        let sender_pos = self.inbox.sender();
+
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+
+        self.klipper_tx = Some(tx);
+
+        let (tx_interrupt, rx_interrupt) = tokio::sync::mpsc::unbounded_channel();
+        self.klipper_interrupts = Some(tx_interrupt);
+
+        let (tx2, rx2) = tokio::sync::oneshot::channel();
+
+        std::thread::spawn(move || {
+            // let rt = tokio::runtime::Runtime::new().unwrap();
+            let rt = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(3)
+                .enable_all()
+                .build()
+                .unwrap();
+
+            rt.block_on(async move {
+                /// keep the simulated printer alive for as long as the connection
+                #[cfg(any(test, feature = "sim"))]
+                let (endpoint, _sim) = match (endpoint, sim_state) {
+                    (_, Some(state)) => match MockMoonraker::start_shared(state).await {
+                        Ok(server) => {
+                            let endpoint =
+                                MoonrakerEndpoint::from_url(&server.url(), MoonrakerAuth::None);
+                            match endpoint {
+                                Ok(endpoint) => (endpoint, Some(server)),
+                                Err(e) => {
+                                    error!("Failed to start simulated printer: {}", e);
+                                    return;
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            error!("Failed to start simulated printer: {}", e);
+                            return;
+                        }
+                    },
+                    (Some(endpoint), None) => (endpoint, None),
+                    (None, None) => unreachable!(),
+                };
+                #[cfg(not(any(test, feature = "sim")))]
+                let Some(endpoint) = endpoint
+                else {
+                    unreachable!()
+                };
+
+                let res = match transport {
+                    Transport::Websocket => {
+                        match KlipperConn::new(
+                            endpoint,
+                            sender_pos,
+                            rx,
+                            rx_interrupt,
+                            tx2,
+                            toolchanger,
+                            safety,
+                        )
+                        .await
+                        {
+                            Ok(klipper) => match recorder {
+                                Some(recorder) => run_client(klipper.with_recorder(recorder)).await,
+                                None => run_client(klipper).await,
+                            },
+                            Err(e) => Err(e),
+                        }
+                    }
+                    Transport::Http => {
+                        if recorder.is_some() {
+                            warn!("Only websocket sessions can be recorded");
+                        }
+                        match HttpClient::new(
+                            endpoint,
+                            sender_pos,
+                            rx,
+                            rx_interrupt,
+                            tx2,
+                            toolchanger,
+                            safety,
+                        )
+                        .await
+                        {
+                            Ok(klipper) => run_client(klipper).await,
+                            Err(e) => Err(e),
+                        }
+                    }
+                };
+                if let Err(e) = res {
+                    error!("Klipper connection stopped: {}", e);
+                }
+            });
+        });
+
+        let status = rx2
+            .blocking_recv()
+            .map_err(|_| anyhow!("Klipper thread exited before sending status"))?;
+
+        self.klipper_status = Some(status);
+
+        Ok(())
+    }
+
+    pub fn connection_status(&self, ui: &mut egui::Ui) {
+        let (text, color) = match self.klipper_connection {
+            ConnectionState::Connecting => ("Printer: connecting".to_string(), Color32::GRAY),
+            ConnectionState::Connected => (
+                "Printer: connected".to_string(),
+                Color32::from_rgb(100, 200, 100),
+            ),
+            ConnectionState::Disconnected => (
+                "Printer: disconnected".to_string(),
+                Color32::from_rgb(255, 100, 100),
+            ),
+            ConnectionState::Reconnecting(attempt, delay) => (
+                format!(
+                    "Printer: offline, reconnecting (attempt {}, retry in {:.1}s)",
+                    attempt,
+                    delay.as_secs_f64()
+                ),
+                Color32::from_rgb(251, 149, 20),
+            ),
+        };
+        ui.label(RichText::new(text).color(color).size(16.));
+
+        if self.klipper_connection == ConnectionState::Connected {
+            let (state, message) = &self.klippy_state;
+            match state {
+                KlippyState::Ready => {}
+                KlippyState::Unknown | KlippyState::Startup => {
+                    ui.label(RichText::new(format!("Klipper: {}", state)).color(Color32::GRAY));
+                }
+                _ => {
+                    ui.label(
+                        RichText::new(format!("Klipper: {}", state))
+                            .color(Color32::from_rgb(255, 100, 100))
+                            .size(16.),
+                    );
+                    if !message.is_empty() {
+                        ui.label(message.trim());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Moves are refused until the axes they use are homed, so offer to do it here
+    pub fn homing_status(&mut self, ui: &mut egui::Ui) {
+        if !self.klippy_state.0.is_ready() {
+            return;
+        }
+        let Some((x, y, z)) = self.klipper_status_frame.as_ref().map(|s| s.homed_axes) else {
+            return;
+        };
+        if x && y && z {
+            return;
+        }
+
+        let unhomed = [(x, "X"), (y, "Y"), (z, "Z")]
+            .iter()
+            .filter(|(homed, _)| !homed)
+            .map(|(_, a)| *a)
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        ui.horizontal(|ui| {
+            ui.label(
+                RichText::new(format!("Not homed: {}", unhomed))
+                    .color(Color32::from_rgb(251, 149, 20)),
+            );
+            if ui.button("Home All").clicked() {
+                self.home_all();
+            }
+        });
+    }
+
+    /// from `motion_report`, nothing if the printer doesn't send it
+    pub fn motion_status(&mut self, ui: &mut egui::Ui) {
+        let Some(status) = self.klipper_status_frame.as_ref() else {
+            return;
+        };
+        let Some((x, y, z)) = status.motion.position else {
+            return;
+        };
+
+        let (state, color) = if status.motion.is_moving() || status.moves_queued > 0 {
+            ("moving", Color32::from_rgb(251, 149, 20))
+        } else {
+            ("still", Color32::from_rgb(100, 200, 100))
+        };
+        ui.horizontal(|ui| {
+            ui.label(format!("Live: X {:.3} Y {:.3} Z {:.3}", x, y, z));
+            ui.label(RichText::new(state).color(color));
+        });
+    }
+
+    pub fn heater_status(&mut self, ui: &mut egui::Ui) {
+        let Some(status) = self.klipper_status_frame.as_ref() else {
+            return;
+        };
+        if status.heaters.is_empty() {
+            return;
+        }
+        let tolerance = self.options.heating.tolerance;
+
+        let mut cool_down = false;
+        ui.horizontal_wrapped(|ui| {
+            for (name, heater) in status.heaters.iter() {
+                let tool = tools::tool_for_extruder(&status.tools, name)
+                    .map(|t| format!("T{}", t))
+                    .unwrap_or_else(|| name.clone());
+                let text = if heater.is_on() {
+                    format!(
+                        "{}: {:.1} / {:.0}°C",
+                        tool, heater.temperature, heater.target
+                    )
+                } else {
+                    format!("{}: {:.1}°C", tool, heater.temperature)
+                };
+                let text = if heater.is_ramping(tolerance) {
+                    RichText::new(text).color(Color32::from_rgb(251, 149, 20))
+                } else {
+                    RichText::new(text)
+                };
+                ui.label(text);
+            }
+            if status.heaters.values().any(|h| h.is_on()) && ui.button("Cool down").clicked() {
+                cool_down = true;
+            }
+        });
+        if cool_down {
+            self.cool_down();
+        }
+    }
+
+    pub fn compatibility_report(&self, ui: &mut egui::Ui) {
+        let Some(report) = self.compatibility.as_ref() else {
+            return;
+        };
+
+        let tools = report
+            .tools
+            .iter()
+            .map(|t| format!("T{}", t))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        if report.is_ok() {
+            ui.label(format!(
+                "Printer setup OK ({}), tools: {}",
+                report.backend, tools
+            ));
+        } else {
+            ui.label(
+                RichText::new(format!("Printer setup incomplete ({})", report.backend))
+                    .color(Color32::from_rgb(251, 149, 20))
+                    .size(16.),
+            );
+            ui.label("Missing:");
+            for r in report.missing.iter() {
+                let features = r
+                    .features
+                    .iter()
+                    .map(|f| f.to_str())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                ui.label(format!("  {} (needed for: {})", r.object, features));
+            }
+            ui.label("Disabled:");
+            for f in report.disabled_features() {
+                ui.label(format!("  {}", f));
+            }
+        }
+
+        /// defined in the config, but the backend has no way to pick them up
+        let unchangeable = self
+            .tool_numbers()
+            .into_iter()
+            .filter(|t| !report.tools.contains(t))
+            .map(|t| format!("T{}", t))
+            .collect::<Vec<_>>();
+        if !unchangeable.is_empty() {
+            ui.label(
+                RichText::new(format!("No tool change for: {}", unchangeable.join(", ")))
+                    .color(Color32::from_rgb(251, 149, 20)),
+            );
+        }
+    }
+}
 
 impl App {
     fn with_klipper<F>(&mut self, f: F)
@@ -29,13 +386,99 @@ impl App {
     }
 
     fn send_klipper(&mut self, cmd: KlipperCommand) {
-        self.with_klipper(|tx| {
-            tx.blocking_send(cmd).unwrap_or_else(|e| {
+        /// counted before it's sent, so it can't be counted off first
+        let moves = cmd.moves();
+        if moves {
+            if let Some(status) = self.klipper_status.as_ref() {
+                status.blocking_write().moves_queued += 1;
+            }
+        }
+        let sent = self.with_klipper_val(|tx| {
+            tx.blocking_send(cmd).map_err(|e| {
                 error!("Failed to send klipper command: {}", e);
-            });
+            })
+        });
+        if moves && !matches!(sent, Some(Ok(()))) {
+            if let Some(status) = self.klipper_status.as_ref() {
+                let mut status = status.blocking_write();
+                status.moves_queued = status.moves_queued.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Every move sent has finished and the toolhead has been still for `settle_time`
+    pub fn motion_settled(&self) -> bool {
+        let settle = std::time::Duration::from_secs_f64(self.options.motion.settle_time.max(0.));
+        self.klipper_status
+            .as_ref()
+            .map_or(false, |s| s.blocking_read().is_settled(settle))
+    }
+
+    fn send_interrupt(&mut self, interrupt: Interrupt) {
+        let Some(tx) = self.klipper_interrupts.as_ref() else {
+            warn!("klipper is not connected, can't send {:?}", interrupt);
+            return;
+        };
+        tx.send(interrupt).unwrap_or_else(|e| {
+            error!("Failed to send {:?}: {}", interrupt, e);
         });
     }
 
+    /// Stop auto offset and drop every queued command, moves already sent still finish
+    pub fn abort(&mut self) {
+        warn!("Aborting");
+        self.auto_offset.stop();
+        self.send_interrupt(Interrupt::Abort);
+    }
+
+    /// Klipper's emergency stop, it has to be restarted afterwards
+    pub fn emergency_stop(&mut self) {
+        warn!("Emergency stop");
+        self.auto_offset.stop();
+        /// the heaters are off after a shutdown, and klipper would refuse the cooldown
+        self.auto_offset.heated_tools.clear();
+        /// nothing to report to, klipper is shut down
+        self.remote_call = None;
+        self.send_interrupt(Interrupt::EmergencyStop);
+    }
+
+    /// always shown, right to left
+    pub fn stop_buttons(&mut self, ui: &mut egui::Ui) {
+        let estop = egui::Button::new(RichText::new("E-STOP").strong().color(Color32::WHITE))
+            .fill(Color32::from_rgb(200, 30, 30));
+        if ui
+            .add(estop)
+            .on_hover_text(format!(
+                "Emergency stop, Klipper needs a restart after ({})",
+                ui.ctx().format_shortcut(&ESTOP_SHORTCUT)
+            ))
+            .clicked()
+        {
+            self.emergency_stop();
+        }
+
+        if ui
+            .button("Abort")
+            .on_hover_text(format!(
+                "Stop auto offset and drop queued commands ({})",
+                ui.ctx().format_shortcut(&ABORT_SHORTCUT)
+            ))
+            .clicked()
+        {
+            self.abort();
+        }
+    }
+
+    /// from the console, the output comes back as `KlipperMessage::GcodeResponse`
+    pub fn run_gcode(&mut self, gcode: String) {
+        self.send_klipper(KlipperCommand::RunGcode(gcode));
+    }
+
+    /// shown in the printer's console, e.g. to the macro that called a remote method
+    pub fn respond(&mut self, kind: RespondType, msg: String) {
+        self.send_klipper(KlipperCommand::Respond(kind, msg));
+    }
+
     pub fn home_all(&mut self) {
         // self.send_klipper(KlipperCommand::PickTool(0));
         self.send_klipper(KlipperCommand::HomeAll);
@@ -45,6 +488,11 @@ impl App {
         self.send_klipper(KlipperCommand::HomeXY);
     }
 
+    /// offset the printer is applying now, the mounted tool's
+    pub fn get_homing_origin(&mut self) -> Option<(f64, f64, f64)> {
+        Some(self.klipper_status.as_ref()?.blocking_read().homing_origin)
+    }
+
     /// fetch the most recent position (before offsets applied)
     pub fn get_carriage_position(&mut self) -> Option<(f64, f64, f64)> {
         let Some(s) = self.klipper_status.as_ref() else {
@@ -68,6 +516,58 @@ impl App {
         pos
     }
 
+    /// mounted tool as last reported by the printer, -1 if none
+    pub fn get_active_tool(&mut self) -> Option<i32> {
+        self.klipper_status.as_ref()?.blocking_read().active_tool
+    }
+
+    /// the tool's extruder, None if the printer doesn't have one
+    pub fn get_heater(&self, tool: i32) -> Option<HeaterState> {
+        if tool < 0 {
+            return None;
+        }
+        let status = self.klipper_status.as_ref()?.blocking_read();
+        status
+            .heaters
+            .get(&tools::extruder_for(&status.tools, tool as u32))
+            .copied()
+    }
+
+    /// Tools the printer defines, or T0.. up to `num_tools` until it has said
+    pub fn tools(&self) -> Vec<ToolInfo> {
+        if self.printer_tools.is_empty() {
+            tools::from_options(self.options.num_tools)
+        } else {
+            self.printer_tools.clone()
+        }
+    }
+
+    pub fn tool_numbers(&self) -> Vec<u32> {
+        self.tools().iter().map(|t| t.number).collect()
+    }
+
+    pub fn set_tool_temperature(&mut self, tool: i32, target: f64) {
+        if tool < 0 {
+            error!("Invalid tool number: {}", tool);
+            return;
+        }
+        self.send_klipper(KlipperCommand::SetToolTemperature(tool as u32, target));
+    }
+
+    /// Probe every tool, the Z offsets are proposed for confirmation when it's done
+    pub fn calibrate_z(&mut self) {
+        let tools = self.tool_numbers();
+        self.send_klipper(KlipperCommand::CalibrateZ(
+            self.options.z_probe.clone(),
+            tools,
+            self.options.motion.travel,
+        ));
+    }
+
+    pub fn cool_down(&mut self) {
+        self.send_klipper(KlipperCommand::CoolDown);
+    }
+
     pub fn fetch_position(&mut self) -> Option<(f64, f64, f64)> {
         let (tx, rx) = tokio::sync::oneshot::channel();
 
@@ -89,6 +589,7 @@ impl App {
             } else {
                 None
             },
+            self.options.motion.travel,
         ));
     }
 
@@ -110,12 +611,12 @@ impl App {
             } else {
                 None
             },
+            self.options.motion.fine,
         ));
     }
 
     pub fn dropoff_tool(&mut self) {
         self.send_klipper(KlipperCommand::DropTool);
-        self.active_tool = None;
         self.send_klipper(KlipperCommand::WaitForMoves);
     }
 
@@ -128,8 +629,6 @@ impl App {
         self.send_klipper(KlipperCommand::PickTool(tool as u32));
         self.send_klipper(KlipperCommand::WaitForMoves);
 
-        self.active_tool = Some(tool as usize);
-
         if move_to_camera {
             if let Some(pos) = self.camera_pos {
                 self.move_to_position(pos, true);
@@ -145,6 +644,11 @@ impl App {
         self.send_klipper(KlipperCommand::SetToolOffset(tool as u32, axis, amount));
     }
 
+    /// Use [`Self::propose_offsets`] for measured offsets, so they're confirmed first
+    pub fn commit_offsets(&mut self, changes: Vec<OffsetChange>) {
+        self.send_klipper(KlipperCommand::CommitOffsets(changes));
+    }
+
     pub fn fetch_tool_offsets(&mut self) {
         self.send_klipper(KlipperCommand::GetToolOffsets);
     }
@@ -153,3 +657,8 @@ impl App {
         todo!()
     }
 }
+
+/// Either client, until the UI hangs up
+async fn run_client(mut client: impl CommandRunner) -> Result<()> {
+    client.run().await
+}
@@ -1,10 +1,13 @@
 // pub mod app;
 pub mod auto_offset;
 pub mod auto_offset_types;
+pub mod console;
 pub mod data_labeling;
 pub mod klipper_ui;
+pub mod offset_commit;
 pub mod options;
 pub mod preprocess_ui;
+pub mod remote_calls;
 pub mod ui_types;
 pub mod utils;
 pub mod webcam_controls;
@@ -137,8 +140,8 @@ impl App {
                             self.auto_offset.stop();
                         }
                         _ => {
-                            self.auto_offset
-                                .start_all_tools((x, y), self.options.num_tools as usize);
+                            let tools = self.tool_numbers();
+                            self.auto_offset.start_all_tools((x, y), tools);
                         }
                     }
                 }
@@ -187,6 +190,13 @@ impl App {
                 }
             }
 
+            if ui
+                .add(egui::Button::new(RichText::new("Calibrate Z").size(16.)))
+                .clicked()
+            {
+                self.calibrate_z();
+            }
+
             ui.label("Repeatability Count: ");
             let resp = ui.add(
                 egui::DragValue::new(self.auto_offset.repeatability_count_mut())
@@ -263,7 +273,8 @@ impl App {
                 self.dropoff_tool();
             }
 
-            for t in 0..4 {
+            for tool in self.tools() {
+                let t = tool.number as usize;
                 let but = egui::Button::new(RichText::new(format!("T{}", t)).size(16.));
                 let but = if self.active_tool == Some(t) {
                     but.fill(egui::Color32::from_rgb(50, 158, 244))
@@ -271,7 +282,7 @@ impl App {
                     but
                 };
 
-                if ui.add(but).clicked() {
+                if ui.add(but).on_hover_text(tool.describe()).clicked() {
                     self.pickup_tool(t as i32, true);
                     if let Some(pos) = self.camera_pos {
                         self.move_to_position(pos, true);
@@ -1017,16 +1028,24 @@ impl App {
                 let (tx_to_vision, rx_to_vision) = crossbeam_channel::bounded(10);
                 self.channel_to_vision = Some(tx_to_vision);
 
-                crate::vision::spawn_locator_thread(
-                    ui.ctx().clone(),
-                    texture.clone(),
-                    0,
-                    rx_to_vision,
-                    tx_to_ui,
-                    self.webcam_settings_mutex.clone(),
-                    // self.options.camera_size,
-                    self.selected_camera_format,
-                );
+                #[cfg(any(test, feature = "sim"))]
+                let simulating =
+                    self.spawn_sim_camera(ui.ctx(), &texture, &rx_to_vision, &tx_to_ui);
+                #[cfg(not(any(test, feature = "sim")))]
+                let simulating = false;
+
+                if !simulating {
+                    crate::vision::spawn_locator_thread(
+                        ui.ctx().clone(),
+                        texture.clone(),
+                        0,
+                        rx_to_vision,
+                        tx_to_ui,
+                        self.webcam_settings_mutex.clone(),
+                        // self.options.camera_size,
+                        self.selected_camera_format,
+                    );
+                }
 
                 &self.webcam_texture.as_ref().unwrap()
             }
@@ -1146,6 +1165,38 @@ impl App {
     }
 
     #[cfg(feature = "nope")]
+    /// The simulated printer brings its own camera, false if not simulating
+    #[cfg(any(test, feature = "sim"))]
+    fn spawn_sim_camera(
+        &self,
+        ctx: &egui::Context,
+        texture: &egui::TextureHandle,
+        rx_to_vision: &crossbeam_channel::Receiver<crate::vision::WebcamCommand>,
+        tx_to_ui: &crossbeam_channel::Sender<WebcamMessage>,
+    ) -> bool {
+        let Some(state) = self.sim_printer.clone() else {
+            return false;
+        };
+        let camera = crate::vision::synthetic_camera::SyntheticCamera::new(
+            state,
+            (
+                self.options.camera_size.0 as u32,
+                self.options.camera_size.1 as u32,
+            ),
+            self.options.swap_axes,
+            self.options.mirror_axes,
+        );
+        crate::vision::spawn_synthetic_camera_thread(
+            ctx.clone(),
+            texture.clone(),
+            rx_to_vision.clone(),
+            tx_to_ui.clone(),
+            self.webcam_settings_mutex.clone(),
+            camera,
+        );
+        true
+    }
+
     fn webcam_controls(&mut self, ui: &mut egui::Ui) {
         egui_probe::Probe::new(&mut self.webcam_settings).show(ui);
 
@@ -1227,89 +1278,80 @@ impl eframe::App for App {
         //     ctx.send_viewport_cmd(egui::ViewportCommand::Close);
         // }
 
-        if ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
-            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+        /// e-stop first, the plain escape would match shift+escape too
+        if ctx.input_mut(|i| i.consume_shortcut(&klipper_ui::ESTOP_SHORTCUT)) {
+            self.emergency_stop();
+        } else if ctx.input_mut(|i| i.consume_shortcut(&klipper_ui::ABORT_SHORTCUT)) {
+            self.abort();
         }
 
         /// Init klipper
         if !self.klipper_started {
             // self.errors.push("Starting klipper".to_string());
 
-            debug!("starting klipper thread");
-            let url = url::Url::parse(&format!("{}", self.options.printer_url)).unwrap();
-            let url = url::Url::parse(&format!("ws://{}:7125/websocket", url.host_str().unwrap()))
-                .unwrap();
-
-            // debug!("url = {}", url);
-
-            let sender_pos = self.inbox.sender();
-
-            let (mut tx, rx) = tokio::sync::mpsc::channel(2);
-
-            tx.blocking_send(crate::klipper_async::KlipperCommand::GetToolOffsets)
-                .unwrap_or_else(|e| {
-                    error!("Failed to send command: {}", e);
-                });
-
-            self.klipper_tx = Some(tx);
-
-            let (tx2, mut rx2) = tokio::sync::oneshot::channel();
-
-            std::thread::spawn(move || {
-                // let rt = tokio::runtime::Runtime::new().unwrap();
-                let rt = tokio::runtime::Builder::new_multi_thread()
-                    .worker_threads(3)
-                    .enable_all()
-                    .build()
-                    .unwrap();
-
-                rt.block_on(async move {
-                    let mut klipper =
-                        crate::klipper_async::KlipperConn::new(url, sender_pos, rx, tx2)
-                            .await
-                            .unwrap();
-                    klipper.run().await.unwrap();
-                });
-            });
-
-            let status = loop {
-                if let Ok(status) = rx2.try_recv() {
-                    break status;
-                };
-            };
-
-            self.klipper_status = Some(status);
+            if let Err(e) = self.start_klipper_thread() {
+                error!("Failed to start klipper connection: {}", e);
+                self.errors
+                    .push(format!("Failed to start klipper connection: {}", e));
+            }
 
             self.klipper_started = true;
         }
 
-        if self
-            .last_position_fetch
-            .map(|t| t.elapsed() > std::time::Duration::from_millis(500))
-            .unwrap_or(true)
+        /// only needed when the printer doesn't send `motion_report`, the subscription keeps it current otherwise
+        let live = self
+            .klipper_status_frame
+            .as_ref()
+            .map_or(false, |s| s.motion.position.is_some());
+        if !live
+            && self
+                .last_position_fetch
+                .map(|t| t.elapsed() > std::time::Duration::from_millis(500))
+                .unwrap_or(true)
         {
             if let Some(tx) = self.klipper_tx.as_mut() {
-                tx.blocking_send(crate::klipper_async::KlipperCommand::FetchPosition)
-                    .unwrap_or_else(|e| {
-                        error!("Failed to send command: {}", e);
-                    });
+                /// skip this poll if the queue is busy rather than blocking the UI
+                if let Err(e) = tx.try_send(crate::klipper_async::KlipperCommand::FetchPosition) {
+                    trace!("Skipping position fetch: {}", e);
+                }
                 self.last_position_fetch = Some(std::time::Instant::now());
             }
         }
 
         self.inbox.set_ctx(ctx);
         while let Some(msg) = self.inbox.read_without_ctx().next() {
+            self.offset_commit_message(&msg);
             match msg {
                 crate::klipper_async::KlipperMessage::Position(pos) => self.last_position = pos,
                 // crate::klipper_async::KlipperMessage::AxesHomed((x, y, z)) => todo!(),
                 crate::klipper_async::KlipperMessage::KlipperError(e) => {
                     error!("Klipper error: {}", e);
                     self.errors.push(e.to_string());
+                    if self.auto_offset.auto_offset_type() != auto_offset::AutoOffsetType::None {
+                        warn!("Stopping auto offset after klipper error");
+                        self.auto_offset.stop();
+                    }
+                }
+                crate::klipper_async::KlipperMessage::GcodeResponse(line) => {
+                    self.console.push(console::ConsoleLine::Response(line));
+                }
+                crate::klipper_async::KlipperMessage::RemoteMethodCalled(method, params) => {
+                    self.handle_remote_call(&method, &params);
+                }
+                crate::klipper_async::KlipperMessage::ZOffsetsMeasured(offsets) => {
+                    for (tool, z) in offsets.iter() {
+                        info!("T{} Z offset: {:.4}", tool, z);
+                    }
+                    /// same confirm, commit and read back as XY
+                    self.propose_offsets(crate::klipper_async::zprobe::z_changes(&offsets));
                 }
                 crate::klipper_async::KlipperMessage::ToolOffsets(offsets) => {
                     debug!("Updating tool offsets: {:?}", offsets);
                     self.tool_offsets = offsets
                 }
+                /// already handled by `offset_commit_message`
+                crate::klipper_async::KlipperMessage::OffsetsCommitted(_)
+                | crate::klipper_async::KlipperMessage::OffsetCommitFailed(_) => {}
                 crate::klipper_async::KlipperMessage::HomingOriginChanged((x, y, z)) => {
                     // unimplemented!()
                     // warn!(
@@ -1319,15 +1361,72 @@ impl eframe::App for App {
 
                     // self.fetch_tool_offsets();
                 }
+                crate::klipper_async::KlipperMessage::Connected => {
+                    info!("Connected to printer");
+                    self.klipper_connection = crate::klipper_async::ConnectionState::Connected;
+                }
+                crate::klipper_async::KlipperMessage::Disconnected => {
+                    warn!("Disconnected from printer");
+                    self.klipper_connection = crate::klipper_async::ConnectionState::Disconnected;
+                    self.klippy_state = Default::default();
+                    self.auto_offset.stop();
+                }
+                crate::klipper_async::KlipperMessage::Reconnecting(attempt, delay) => {
+                    self.klipper_connection =
+                        crate::klipper_async::ConnectionState::Reconnecting(attempt, delay);
+                }
+                crate::klipper_async::KlipperMessage::Compatibility(report) => {
+                    self.compatibility = Some(report);
+                }
+                crate::klipper_async::KlipperMessage::KlippyStateChanged(state, message) => {
+                    if state.is_ready() {
+                        self.fetch_tool_offsets();
+                    } else if self.auto_offset.auto_offset_type()
+                        != auto_offset::AutoOffsetType::None
+                    {
+                        warn!("Stopping auto offset, klipper is {}", state);
+                        self.auto_offset.stop();
+                    }
+                    self.klippy_state = (state, message);
+                }
+                crate::klipper_async::KlipperMessage::ActiveToolChanged(tool) => {
+                    debug!("Active tool changed: {}", tool);
+                    self.active_tool = if tool >= 0 { Some(tool as usize) } else { None };
+                    if self.options.toolchanger.backend().follows_active_tool() {
+                        if let Some(tx) = self.klipper_tx.as_mut() {
+                            if let Err(e) =
+                                tx.try_send(crate::klipper_async::KlipperCommand::FollowActiveTool)
+                            {
+                                /// the next command checks the mounted tool anyway
+                                trace!("Skipping active tool check: {}", e);
+                            }
+                        }
+                    }
+                }
                 _ => {
                     debug!("Unhandled message: {:?}", msg);
                 }
             }
         }
 
+        self.remote_call_step();
+
         // if let Some(status) = self.klipper_status.as_ref()
         if let Some(status) = self.klipper_status.as_ref() {
             if let Ok(status) = status.try_read() {
+                /// so a value typed in afterwards isn't overwritten every frame
+                if let Some(res) = status
+                    .resolution
+                    .filter(|r| Some(*r) != self.printer_resolution)
+                {
+                    info!("Printer resolution: {:?}", res);
+                    self.printer_resolution = Some(res);
+                    self.options.auto_offset_settings.resolution = res.xy();
+                }
+                if status.tools != self.printer_tools {
+                    info!("Printer tools: {:?}", status.tools);
+                    self.printer_tools = status.tools.clone();
+                }
                 self.klipper_status_frame = Some(status.clone());
             } else {
                 self.klipper_status_frame = None;
@@ -1374,7 +1473,12 @@ impl eframe::App for App {
             ui.horizontal(|ui| {
                 ui.selectable_value(&mut self.current_tab, Tab::Webcam, "Webcam");
                 // ui.selectable_value(&mut self.current_tab, Tab::DataLabeling, "Data Labeling");
+                ui.selectable_value(&mut self.current_tab, Tab::Console, "Console");
                 ui.selectable_value(&mut self.current_tab, Tab::Options, "Options");
+
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    self.stop_buttons(ui);
+                });
             });
         });
 
@@ -1385,6 +1489,13 @@ impl eframe::App for App {
                     .resizable(false)
                     .default_width(400.)
                     .show(ctx, |ui| {
+                        self.connection_status(ui);
+                        self.homing_status(ui);
+                        self.motion_status(ui);
+                        self.heater_status(ui);
+                        self.compatibility_report(ui);
+                        ui.separator();
+
                         // Let's show errors at the top of the panel
                         if !self.errors.is_empty() {
                             ui.heading("Errors");
@@ -1479,9 +1590,14 @@ impl eframe::App for App {
                     //
                 });
             }
+            Tab::Console => {
+                self.console(ctx);
+            }
             Tab::Options => {
                 self.options(ctx);
             }
         }
+
+        self.offset_commit_window(ctx);
     }
 }
@@ -0,0 +1,154 @@
+//! Measured offsets wait here until the user has seen what they change and confirmed
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use egui::{Color32, RichText};
+
+use crate::klipper_async::offsets::{self, CommitReport, OffsetChange};
+
+use super::ui_types::App;
+
+#[derive(Debug, Clone)]
+pub enum OffsetCommitState {
+    /// waiting for the user
+    Proposed,
+    Committing,
+    Committed(CommitReport),
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct OffsetCommit {
+    pub changes: Vec<OffsetChange>,
+    pub state: OffsetCommitState,
+}
+
+impl App {
+    /// Shown next frame, nothing is sent until it's confirmed
+    pub fn propose_offsets(&mut self, changes: Vec<OffsetChange>) {
+        /// the table compares against what the printer has right now
+        self.fetch_tool_offsets();
+        self.offset_commit = Some(OffsetCommit {
+            changes,
+            state: OffsetCommitState::Proposed,
+        });
+    }
+
+    /// Before/after table of a proposed commit, and how it went once confirmed
+    pub fn offset_commit_window(&mut self, ctx: &egui::Context) {
+        let Some(commit) = self.offset_commit.as_ref() else {
+            return;
+        };
+        let rows = offsets::diff(&self.tool_offsets, &commit.changes);
+        let persistence = self.options.toolchanger.backend().persistence();
+
+        let mut confirm = false;
+        let mut close = false;
+
+        egui::Window::new("Commit tool offsets")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                egui::Grid::new("Offset Diff")
+                    .striped(true)
+                    .min_col_width(60.)
+                    .show(ui, |ui| {
+                        for h in ["Tool", "Axis", "Before", "After", "Change"] {
+                            ui.label(RichText::new(h).strong());
+                        }
+                        ui.end_row();
+
+                        for row in rows.iter() {
+                            ui.label(format!("T{}", row.change.tool));
+                            ui.label(row.change.axis.to_str());
+                            ui.label(row.before.map_or("-".to_string(), |v| format!("{:.4}", v)));
+                            ui.label(format!("{:.4}", row.change.value));
+                            ui.label(
+                                row.delta()
+                                    .map_or("-".to_string(), |d| format!("{:+.4}", d)),
+                            );
+                            ui.end_row();
+                        }
+                    });
+
+                ui.separator();
+
+                match &commit.state {
+                    OffsetCommitState::Proposed => {
+                        ui.label(format!("Offsets will be {}", persistence.describe()));
+                        ui.horizontal(|ui| {
+                            confirm = ui.button("Confirm").clicked();
+                            close = ui.button("Discard").clicked();
+                        });
+                    }
+                    OffsetCommitState::Committing => {
+                        ui.horizontal(|ui| {
+                            ui.spinner();
+                            ui.label("Writing offsets and reading them back");
+                        });
+                    }
+                    OffsetCommitState::Committed(report) => {
+                        ui.label(format!(
+                            "Read back after {} attempt{}, {}",
+                            report.attempts,
+                            if report.attempts == 1 { "" } else { "s" },
+                            report.persistence.describe()
+                        ));
+                        if !report.unverified.is_empty() {
+                            ui.label(
+                                RichText::new(format!(
+                                    "Set but couldn't be read back: {}",
+                                    offsets::describe_changes(&report.unverified)
+                                ))
+                                .color(Color32::from_rgb(255, 200, 100)),
+                            );
+                        }
+                        close = ui.button("Close").clicked();
+                    }
+                    OffsetCommitState::Failed(e) => {
+                        ui.label(RichText::new(e).color(Color32::from_rgb(255, 100, 100)));
+                        ui.horizontal(|ui| {
+                            confirm = ui.button("Retry").clicked();
+                            close = ui.button("Close").clicked();
+                        });
+                    }
+                }
+            });
+
+        if confirm {
+            let changes = commit.changes.clone();
+            if let Some(commit) = self.offset_commit.as_mut() {
+                commit.state = OffsetCommitState::Committing;
+            }
+            self.commit_offsets(changes);
+        } else if close {
+            self.offset_commit = None;
+        }
+    }
+
+    /// Called with every message from klipper
+    pub fn offset_commit_message(&mut self, msg: &crate::klipper_async::KlipperMessage) {
+        let Some(commit) = self.offset_commit.as_mut() else {
+            return;
+        };
+        if !matches!(commit.state, OffsetCommitState::Committing) {
+            return;
+        }
+        match msg {
+            crate::klipper_async::KlipperMessage::OffsetsCommitted(report) => {
+                info!(
+                    "Offsets committed after {} attempt(s), {}",
+                    report.attempts,
+                    report.persistence.describe()
+                );
+                commit.state = OffsetCommitState::Committed(report.clone());
+            }
+            /// errors from anything else, e.g. a position poll, leave the commit running
+            crate::klipper_async::KlipperMessage::OffsetCommitFailed(e) => {
+                commit.state = OffsetCommitState::Failed(e.clone());
+            }
+            _ => {}
+        }
+    }
+}
@@ -2,6 +2,15 @@ use anyhow::{anyhow, bail, ensure, Context, Result};
 use egui::{DragValue, Slider};
 use tracing::{debug, error, info, trace, warn};
 
+use crate::klipper_async::{
+    endpoint::{MoonrakerAuth, Transport},
+    heaters::HeatingSettings,
+    limits::SafetySettings,
+    motion::MotionSettings,
+    sim_printer::SimSettings,
+    toolchanger::ToolchangerConfig,
+    zprobe::ZProbeSettings,
+};
 use crate::ui::{auto_offset_types::AutoOffsetSettings, ui_types::App};
 
 use super::utils::make_scrollable;
@@ -10,6 +19,12 @@ use super::utils::make_scrollable;
 pub struct Options {
     pub camera_index: String,
     pub printer_url: String,
+    /// only needed if moonraker doesn't trust this machine
+    #[serde(default)]
+    pub printer_auth: MoonrakerAuth,
+    #[serde(default)]
+    pub transport: Transport,
+    /// only used until the printer says which tools it has, and by the simulator
     pub num_tools: usize,
     pub bounce_amount: f64,
     pub camera_size: (f64, f64),
@@ -20,6 +35,36 @@ pub struct Options {
     // pub rotate: usize,
     pub z_height: f64,
 
+    /// how tools are changed and offsets stored on this printer
+    #[serde(default)]
+    pub toolchanger: ToolchangerConfig,
+
+    /// keep-out zones and how far auto-centering may move at once
+    #[serde(default)]
+    pub safety: SafetySettings,
+
+    /// speed and acceleration for travel and for fine moves
+    #[serde(default)]
+    pub motion: MotionSettings,
+
+    /// heating tools before measuring them
+    #[serde(default)]
+    pub heating: HeatingSettings,
+
+    /// where and how to probe for Z offsets
+    #[serde(default)]
+    pub z_probe: ZProbeSettings,
+
+    /// use a simulated printer and camera instead of moonraker and the webcam, needs the `sim` feature
+    #[serde(default)]
+    pub simulate: bool,
+    #[serde(default)]
+    pub sim_settings: SimSettings,
+
+    /// append every websocket frame to this JSONL file, to reproduce bugs with
+    #[serde(default)]
+    pub record_session: Option<std::path::PathBuf>,
+
     #[serde(skip)]
     pub auto_offset_settings: AutoOffsetSettings,
 }
@@ -29,6 +74,8 @@ impl Default for Options {
         Options {
             camera_index: "0".to_string(),
             printer_url: "".to_string(),
+            printer_auth: MoonrakerAuth::default(),
+            transport: Transport::default(),
             num_tools: 4,
             bounce_amount: 0.5,
             camera_size: (1280., 800.),
@@ -40,6 +87,17 @@ impl Default for Options {
             // z_height: 33.51,
             z_height: 33.2,
 
+            toolchanger: ToolchangerConfig::default(),
+            safety: SafetySettings::default(),
+            motion: MotionSettings::default(),
+            heating: HeatingSettings::default(),
+            z_probe: ZProbeSettings::default(),
+
+            simulate: false,
+            sim_settings: SimSettings::default(),
+
+            record_session: None,
+
             auto_offset_settings: AutoOffsetSettings::default(),
         }
     }
@@ -73,6 +131,33 @@ impl App {
 
         ui.separator();
 
+        for (label, speed) in [
+            ("Travel", &mut self.options.motion.travel),
+            ("Fine moves", &mut self.options.motion.fine),
+        ] {
+            ui.horizontal(|ui| {
+                ui.label(format!("{} speed (mm/s): ", label));
+                optional_drag_value(ui, &mut speed.speed, 150.);
+                ui.label("accel (mm/s²): ");
+                optional_drag_value(ui, &mut speed.accel, 3000.);
+            });
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Settle time before measuring (s): ");
+            ui.add(
+                egui::DragValue::new(&mut self.options.motion.settle_time)
+                    .range(0.0..=5.0)
+                    .speed(0.05),
+            );
+        });
+
+        ui.separator();
+
+        self.z_probe_options(ui);
+
+        ui.separator();
+
         ui.horizontal(|ui| {
             let prev_format = self.selected_camera_format;
             let resp = egui::ComboBox::new("Camera Format", "Camera Format")
@@ -124,9 +209,80 @@ impl App {
         ui.separator();
 
         ui.horizontal(|ui| {
-            ui.label("Number of tools: ");
-            let resp = ui.add(Slider::new(&mut self.options.num_tools, 1..=4));
-            make_scrollable(ui, resp, &mut self.options.num_tools, 1);
+            if self.printer_tools.is_empty() {
+                ui.label("Number of tools: ");
+                let resp = ui.add(Slider::new(&mut self.options.num_tools, 1..=16));
+                make_scrollable(ui, resp, &mut self.options.num_tools, 1);
+            } else {
+                let tools = self
+                    .printer_tools
+                    .iter()
+                    .map(|t| t.describe())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                ui.label(format!("Tools from the printer:\n{}", tools));
+            }
+        });
+
+        #[cfg(any(test, feature = "sim"))]
+        {
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                ui.checkbox(
+                    &mut self.options.simulate,
+                    "Simulate printer and camera (restart to apply)",
+                );
+            });
+        }
+    }
+}
+
+impl App {
+    fn z_probe_options(&mut self, ui: &mut egui::Ui) {
+        let carriage = self.get_carriage_position();
+        let z_probe = &mut self.options.z_probe;
+
+        ui.horizontal(|ui| {
+            ui.label("Z probe position: ");
+            match z_probe.position.as_mut() {
+                Some((x, y)) => {
+                    ui.add(DragValue::new(x).speed(0.1).prefix("X "));
+                    ui.add(DragValue::new(y).speed(0.1).prefix("Y "));
+                }
+                None => {
+                    ui.label("not set");
+                }
+            }
+            if let Some((x, y, _)) = carriage {
+                if ui.button("Set to current").clicked() {
+                    z_probe.position = Some((x, y));
+                }
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Probe command: ");
+            ui.add(egui::TextEdit::singleline(&mut z_probe.probe_gcode).desired_width(120.));
+            ui.label("start Z: ");
+            ui.add(
+                DragValue::new(&mut z_probe.start_z)
+                    .range(1.0..=50.0)
+                    .speed(0.1),
+            );
+            ui.label("samples: ");
+            ui.add(DragValue::new(&mut z_probe.samples).range(1..=20));
         });
     }
 }
+
+/// Unchecked means leave it to the printer
+fn optional_drag_value(ui: &mut egui::Ui, value: &mut Option<f64>, default: f64) {
+    let mut enabled = value.is_some();
+    if ui.checkbox(&mut enabled, "").changed() {
+        *value = if enabled { Some(default) } else { None };
+    }
+    if let Some(v) = value.as_mut() {
+        ui.add(DragValue::new(v).range(1.0..=10000.0).speed(1.0));
+    }
+}
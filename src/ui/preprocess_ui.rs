@@ -1,11 +1,18 @@
 use egui::{Frame, Vec2};
 
-use crate::vision::preprocess::{PreprocessStep, PreprocessStepType};
+use crate::vision::{
+    preprocess::{PreprocessStep, PreprocessStepType, ThresholdType},
+    WebcamCommand,
+};
 
 use super::ui_types::App;
 
 impl App {
     pub fn preprocess_ui(&mut self, ui: &mut egui::Ui) {
+        ui.checkbox(&mut self.use_preprocess_pipeline, "Use Preprocess Pipeline")
+            .on_hover_text("Replaces the built-in filters when any step is enabled");
+        ui.end_row();
+
         ui.horizontal(|ui| {
             egui::ComboBox::from_id_salt("Preprocess Add")
                 .selected_text(self.preprocess_add.to_str())
@@ -28,6 +35,19 @@ impl App {
                         },
                         "Gaussian Blur",
                     );
+                    ui.selectable_value(
+                        &mut self.preprocess_add,
+                        PreprocessStepType::Threshold {
+                            threshold: 127.,
+                            threshold_type: ThresholdType::BinaryTriangle,
+                        },
+                        "Threshold",
+                    );
+                    ui.selectable_value(
+                        &mut self.preprocess_add,
+                        PreprocessStepType::AdaptiveThreshold,
+                        "Adaptive Threshold",
+                    );
                 });
 
             if ui.button("Add").clicked() {
@@ -42,8 +62,31 @@ impl App {
 
         let frame = Frame::default().inner_margin(4.0);
 
+        let mut remove = None;
         for (i, step) in self.preprocess_pipeline.iter_mut().enumerate() {
-            Self::show_preprocess(ui, step);
+            if Self::show_preprocess(ui, i, step) {
+                remove = Some(i);
+            }
+        }
+        if let Some(i) = remove {
+            self.preprocess_pipeline.remove(i);
+        }
+
+        /// only send when something changed, the vision thread keeps the last one
+        let pipeline = if self.use_preprocess_pipeline {
+            self.preprocess_pipeline.clone()
+        } else {
+            vec![]
+        };
+        if pipeline != self.preprocess_pipeline_prev {
+            if let Some(tx) = self.channel_to_vision.as_ref() {
+                if tx
+                    .send(WebcamCommand::SetPreprocessPipeline(pipeline.clone()))
+                    .is_ok()
+                {
+                    self.preprocess_pipeline_prev = pipeline;
+                }
+            }
         }
 
         #[cfg(feature = "nope")]
@@ -70,16 +113,28 @@ impl App {
         //
     }
 
-    pub fn show_preprocess(ui: &mut egui::Ui, preprocess: &mut PreprocessStep) {
+    /// Returns true if the step should be removed
+    pub fn show_preprocess(ui: &mut egui::Ui, i: usize, preprocess: &mut PreprocessStep) -> bool {
+        let mut remove = false;
         ui.group(|ui| {
-            ui.horizontal(|ui| {
-                ui.checkbox(&mut preprocess.enabled, "");
-                ui.label(preprocess.step.to_str());
+            ui.vertical(|ui| {
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut preprocess.enabled, "");
+                    ui.label(format!("{}: {}", i, preprocess.step.to_str()));
+                    if ui.small_button("Remove").clicked() {
+                        remove = true;
+                    }
+                });
+                // if !preprocess.enabled {
+                //     ui.disable();
+                // }
+                ui.push_id(i, |ui| {
+                    Self::_show_preprocess(ui, &mut preprocess.step);
+                });
             });
-            // if !preprocess.enabled {
-            //     ui.disable();
-            // }
         });
+        ui.end_row();
+        remove
     }
 
     fn _show_preprocess(ui: &mut egui::Ui, step: &mut PreprocessStepType) {
@@ -107,8 +162,25 @@ impl App {
             PreprocessStepType::Threshold {
                 threshold,
                 threshold_type,
-            } => todo!(),
-            PreprocessStepType::AdaptiveThreshold => todo!(),
+            } => {
+                ui.horizontal(|ui| {
+                    ui.label("Threshold:");
+                    ui.add(
+                        egui::DragValue::new(threshold)
+                            .range(0.0..=255.0)
+                            .fixed_decimals(0),
+                    );
+                });
+                egui::ComboBox::from_id_salt("Threshold Type")
+                    .selected_text(threshold_type.to_str())
+                    .show_ui(ui, |ui| {
+                        for t in ThresholdType::ALL {
+                            let label = t.to_str().to_string();
+                            ui.selectable_value(threshold_type, t, label);
+                        }
+                    });
+            }
+            PreprocessStepType::AdaptiveThreshold => {}
         }
     }
 }
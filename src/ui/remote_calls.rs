@@ -0,0 +1,137 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use crate::klipper_async::remote::{RemoteCall, RespondType};
+
+use super::{
+    auto_offset::AutoOffsetType,
+    ui_types::{App, Tab},
+};
+
+/// A calibration started by a klipper macro, reported back to it when it ends
+#[derive(Debug, Clone)]
+pub struct ActiveRemoteCall {
+    pub call: RemoteCall,
+    /// errors from before the call aren't why it failed
+    errors_at_start: usize,
+}
+
+impl App {
+    pub fn handle_remote_call(&mut self, method: &str, params: &serde_json::Value) {
+        let call = match RemoteCall::parse(method, params) {
+            Ok(call) => call,
+            Err(e) => {
+                warn!("Bad remote call: {}", e);
+                self.respond(RespondType::Error, format!("{}: {}", method, e));
+                return;
+            }
+        };
+        info!("Remote call: {:?}", call);
+
+        if let Err(e) = self.start_remote_call(call) {
+            warn!("Refused remote call {:?}: {}", call, e);
+            self.respond(
+                RespondType::Error,
+                format!("{} refused: {}", call.method(), e),
+            );
+            return;
+        }
+
+        self.remote_call = Some(ActiveRemoteCall {
+            call,
+            errors_at_start: self.errors.len(),
+        });
+        /// the auto offset steps run from the webcam tab
+        self.current_tab = Tab::Webcam;
+        self.respond(RespondType::Echo, format!("{} started", call.method()));
+    }
+
+    fn start_remote_call(&mut self, call: RemoteCall) -> Result<()> {
+        ensure!(
+            self.remote_call.is_none()
+                && self.auto_offset.auto_offset_type() == AutoOffsetType::None,
+            "a calibration is already running"
+        );
+        let Some(cam_pos) = self.camera_pos else {
+            bail!("no camera position set");
+        };
+        let homed = self
+            .klipper_status_frame
+            .as_ref()
+            .map(|s| s.homed_axes)
+            .unwrap_or_default();
+        ensure!(homed == (true, true, true), "printer isn't homed");
+
+        match call {
+            RemoteCall::CalibrateAll => {
+                let tools = self.tool_numbers();
+                self.auto_offset.start_all_tools(cam_pos, tools);
+            }
+            RemoteCall::LocateTool(tool) => {
+                let tool = self.remote_call_tool(tool, cam_pos)?;
+                self.auto_offset.start_single(cam_pos, tool);
+            }
+            RemoteCall::Repeatability(tool, count) => {
+                let tool = self.remote_call_tool(tool, cam_pos)?;
+                self.auto_offset.start_repeatability(cam_pos, tool);
+                if let Some(count) = count {
+                    *self.auto_offset.repeatability_count_mut() = count;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Mount `tool`, or keep the mounted one if None, and bring it over the camera
+    fn remote_call_tool(&mut self, tool: Option<u32>, cam_pos: (f64, f64)) -> Result<i32> {
+        let tool = match tool {
+            Some(t) => {
+                ensure!(self.tool_numbers().contains(&t), "no tool T{}", t);
+                t as usize
+            }
+            None => self.active_tool.ok_or_else(|| anyhow!("no tool mounted"))?,
+        };
+
+        if self.active_tool == Some(tool) {
+            self.move_to_position(cam_pos, true);
+        } else {
+            self.pickup_tool(tool as i32, true);
+        }
+        Ok(tool as i32)
+    }
+
+    /// Only sent while a macro started the run
+    pub fn remote_call_progress(&mut self, msg: String) {
+        if let Some(active) = self.remote_call.as_ref() {
+            let msg = format!("{}: {}", active.call.method(), msg);
+            self.respond(RespondType::Echo, msg);
+        }
+    }
+
+    /// Report the outcome once the run started by a macro has stopped, called every frame
+    pub fn remote_call_step(&mut self) {
+        if self.auto_offset.auto_offset_type() != AutoOffsetType::None {
+            return;
+        }
+        let Some(active) = self.remote_call.take() else {
+            return;
+        };
+
+        match self.auto_offset.result() {
+            Some(result) => {
+                let msg = format!("{} done: {}", active.call.method(), result);
+                self.respond(RespondType::Echo, msg);
+            }
+            None => {
+                let reason = self
+                    .errors
+                    .get(active.errors_at_start..)
+                    .and_then(|e| e.last())
+                    .cloned()
+                    .unwrap_or_else(|| "stopped before finishing".to_string());
+                let msg = format!("{} failed: {}", active.call.method(), reason);
+                self.respond(RespondType::Error, msg);
+            }
+        }
+    }
+}
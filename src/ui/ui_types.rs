@@ -18,6 +18,18 @@ pub struct App {
     #[serde(skip)]
     pub tool_offsets: Vec<(f64, f64, f64)>,
 
+    /// as found on the printer, see [`App::tools`]
+    #[serde(skip)]
+    pub printer_tools: Vec<crate::klipper_async::tools::ToolInfo>,
+
+    /// last read from the printer's kinematics, only copied into the settings when it changes
+    #[serde(skip)]
+    pub printer_resolution: Option<crate::klipper_async::resolution::AxisResolution>,
+
+    /// measured offsets waiting to be confirmed, or being written
+    #[serde(skip)]
+    pub offset_commit: Option<crate::ui::offset_commit::OffsetCommit>,
+
     pub camera_pos: Option<(f64, f64)>,
 
     #[serde(skip)]
@@ -68,6 +80,14 @@ pub struct App {
 
     pub preprocess_pipeline: Vec<PreprocessStep>,
 
+    /// off runs the built-in filters, the pipeline is kept either way
+    #[serde(default)]
+    pub use_preprocess_pipeline: bool,
+
+    /// last sent to the vision thread
+    #[serde(skip)]
+    pub preprocess_pipeline_prev: Vec<PreprocessStep>,
+
     #[serde(skip)]
     pub camera_formats: Vec<crate::vision::vision_types::CameraFormat>,
     #[serde(skip)]
@@ -80,6 +100,14 @@ pub struct App {
 
     #[serde(skip)]
     pub klipper_tx: Option<tokio::sync::mpsc::Sender<crate::klipper_async::KlipperCommand>>,
+    /// started from a klipper macro
+    #[serde(skip)]
+    pub remote_call: Option<super::remote_calls::ActiveRemoteCall>,
+
+    /// e-stop and abort, bypassing the command queue
+    #[serde(skip)]
+    pub klipper_interrupts:
+        Option<tokio::sync::mpsc::UnboundedSender<crate::klipper_async::Interrupt>>,
     #[serde(skip)]
     pub inbox: egui_inbox::UiInbox<crate::klipper_async::KlipperMessage>,
 
@@ -89,6 +117,23 @@ pub struct App {
     #[serde(skip)]
     pub klipper_status_frame: Option<crate::klipper_async::KlipperStatus>,
 
+    #[serde(skip)]
+    pub klipper_connection: crate::klipper_async::ConnectionState,
+
+    /// with the reason it isn't ready
+    #[serde(skip)]
+    pub klippy_state: (crate::klipper_async::KlippyState, String),
+
+    /// what the printer is missing, from the preflight check on connect
+    #[serde(skip)]
+    pub compatibility: Option<crate::klipper_async::preflight::CompatibilityReport>,
+
+    /// shared with the synthetic camera when simulating
+    #[cfg(any(test, feature = "sim"))]
+    #[serde(skip)]
+    pub sim_printer:
+        Option<Arc<parking_lot::Mutex<crate::klipper_async::mock_moonraker::MockPrinterState>>>,
+
     #[serde(skip)]
     /// for display only, not for sending to klipper
     pub last_position: (f64, f64, f64),
@@ -98,11 +143,16 @@ pub struct App {
 
     #[serde(skip)]
     pub blob_params: BlobParams,
+
+    /// printer output and G-code typed by hand
+    #[serde(default)]
+    pub console: crate::ui::console::Console,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, PartialOrd)]
 pub enum Tab {
     Webcam,
+    Console,
     Options,
     // DataLabeling,
 }
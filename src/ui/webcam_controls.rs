@@ -261,6 +261,10 @@ impl App {
 
         self.blob_controls(ui);
         ui.end_row();
+        ui.separator();
+        ui.end_row();
+
+        self.preprocess_ui(ui);
 
         if self.vision_settings != self.vision_settings_prev {
             let mut settings = self.webcam_settings_mutex.lock().unwrap();
@@ -4,6 +4,7 @@ use opencv::imgproc;
 use tracing::{debug, error, info, trace, warn};
 
 use super::blob_detection::BlobDetectors;
+use super::preprocess::{self, PreprocessStep};
 use super::utilities;
 use super::VisionSettings;
 
@@ -309,11 +310,13 @@ pub fn locate_nozzle(
     Ok((img_out, None))
 }
 
+/// `pipeline` replaces the built-in gamma/luma/blur/threshold chain if any of its steps are enabled
 // #[cfg(feature = "nope")]
 pub fn locate_nozzle(
     img0: &image::ImageBuffer<image::Rgb<u8>, Vec<u8>>,
     settings: &VisionSettings,
     detectors: &mut BlobDetectors,
+    pipeline: &[PreprocessStep],
 ) -> Result<(Mat, Option<(f64, f64, f64)>)> {
     let mut img = utilities::imagebuffer_to_mat(img0)?;
     let img2 = img.clone();
@@ -335,42 +338,51 @@ pub fn locate_nozzle(
 
     // debug!("Image size 1: {}x{}", img.cols(), img.rows());
 
-    let mut img2 = img.clone();
-
-    // Adjust gamma to 1.2
-    let gamma = 1.2;
-    let mut lut = Mat::new_rows_cols_with_default(1, 256, opencv::core::CV_8U, 0.0f64.into())?;
-    for i in 0..256 {
-        let value = ((i as f64 / 255.0).powf(1.0 / gamma) * 255.0) as u8;
-        *lut.at_mut::<u8>(i)? = value;
-    }
-    opencv::core::lut(&img, &lut, &mut img2)?;
-    std::mem::swap(&mut img, &mut img2);
+    /// each is tried in turn until one has a blob
+    let (img_out, mats) = if preprocess::is_active(pipeline) {
+        let (img_out, mat) = preprocess::run_pipeline(pipeline, &img, settings.filter_step)?;
+        (img_out, vec![mat])
+    } else {
+        let mut img2 = img.clone();
+
+        // Adjust gamma to 1.2
+        let gamma = 1.2;
+        let mut lut = Mat::new_rows_cols_with_default(1, 256, opencv::core::CV_8U, 0.0f64.into())?;
+        for i in 0..256 {
+            let value = ((i as f64 / 255.0).powf(1.0 / gamma) * 255.0) as u8;
+            *lut.at_mut::<u8>(i)? = value;
+        }
+        opencv::core::lut(&img, &lut, &mut img2)?;
+        std::mem::swap(&mut img, &mut img2);
 
-    // opencv::imgcodecs::imwrite(&format!("test0.jpg"), &img, &opencv::core::Vector::new()).unwrap();
+        // opencv::imgcodecs::imwrite(&format!("test0.jpg"), &img, &opencv::core::Vector::new()).unwrap();
 
-    // let (thresh0, thresh1) = if settings.threshold_type == 1 {
-    //     (1, 2)
-    // } else if settings.threshold_type == 2 {
-    //     (2, 1)
-    // } else {
-    //     (0, 0)
-    // };
+        // let (thresh0, thresh1) = if settings.threshold_type == 1 {
+        //     (1, 2)
+        // } else if settings.threshold_type == 2 {
+        //     (2, 1)
+        // } else {
+        //     (0, 0)
+        // };
+
+        let (img_out_pre0, mat0) = preprocess_0(&img, settings, 0, false)?;
+        let (img_out_pre1, mat1) = preprocess_0(&img, settings, 1, false)?;
+        let (img_out_pre2, mat2) = preprocess_0(&img, settings, 2, false)?;
+        // let mat1 = preprocess_1(&img, settings)?;
+        // let mat2 = preprocess_2(&img, settings)?;
+        drop(img);
+        drop(img2);
+
+        /// setting filter type only changes which is displayed in UI
+        let img_out = match settings.threshold_type {
+            0 => img_out_pre0,
+            1 => img_out_pre1,
+            2 => img_out_pre2,
+            _ => bail!("Invalid threshold type"),
+        };
 
-    let (img_out_pre0, mat0) = preprocess_0(&img, settings, 0, false)?;
-    let (img_out_pre1, mat1) = preprocess_0(&img, settings, 1, false)?;
-    let (img_out_pre2, mat2) = preprocess_0(&img, settings, 2, false)?;
-    // let mat1 = preprocess_1(&img, settings)?;
-    // let mat2 = preprocess_2(&img, settings)?;
-    drop(img);
-    drop(img2);
-
-    /// setting filter type only changes which is displayed in UI
-    let img_out = match settings.threshold_type {
-        0 => img_out_pre0.clone(),
-        1 => img_out_pre1.clone(),
-        2 => img_out_pre2.clone(),
-        _ => bail!("Invalid threshold type"),
+        /// triangle, otsu, then plain binary
+        (img_out, vec![mat1, mat2, mat0])
     };
 
     let mut best_circle: Option<(f64, f64, f64)> = None;
@@ -465,7 +477,7 @@ pub fn locate_nozzle(
 
     // #[cfg(feature = "nope")]
     if settings.use_hough {
-        let Some(color) = locate_keypoints(settings, detectors, &mat0, &mat1, &mat2)? else {
+        let Some(color) = locate_keypoints(settings, detectors, &mats)? else {
             // debug!("Keypoints not found, skipping circle detection");
             return Ok((img_out2, None));
         };
@@ -535,8 +547,6 @@ pub fn locate_nozzle(
         }
     }
 
-    let mat0 = img_out_pre1;
-
     /// Find keypoints
     #[cfg(feature = "nope")]
     if settings.use_hough {
@@ -685,9 +695,7 @@ fn locate_keypoints_hough(
 fn locate_keypoints(
     settings: &VisionSettings,
     detectors: &mut BlobDetectors,
-    mat_binary: &Mat,
-    mat_triangle: &Mat,
-    mat_otsu: &Mat,
+    mats: &[Mat],
 ) -> Result<Option<opencv::core::Scalar>> {
     detectors.keypoints.clear();
 
@@ -697,38 +705,16 @@ fn locate_keypoints(
         opencv::core::Scalar::new(0., 0., 255., 0.),   // blue
         opencv::core::Scalar::new(255., 0., 0., 0.),   // red
     ];
-    // let mut color = None;
-
-    /// Combo 1: preprocess 0 (binary + triangle) + standard
-    detectors.standard.detect(
-        &mat_triangle,
-        &mut detectors.keypoints,
-        &opencv::core::no_array(),
-    )?;
-    if detectors.keypoints.len() > 0 {
-        // debug!("Combo 1: found {} keypoints", detectors.keypoints.len());
-        return Ok(Some(colors[0]));
-    }
-
-    /// Combo 2: preprocess 1 (binary + otso) + standard
-    detectors.standard.detect(
-        &mat_otsu,
-        &mut detectors.keypoints,
-        &opencv::core::no_array(),
-    )?;
-    if detectors.keypoints.len() > 0 {
-        // debug!("Combo 2: found {} keypoints", detectors.keypoints.len());
-        return Ok(Some(colors[1]));
-    }
 
-    detectors.standard.detect(
-        &mat_binary,
-        &mut detectors.keypoints,
-        &opencv::core::no_array(),
-    )?;
-    if detectors.keypoints.len() > 0 {
-        // debug!("Combo 3: found {} keypoints", detectors.keypoints.len());
-        return Ok(Some(colors[2]));
+    /// the color shows which image the blob was found in
+    for (mat, color) in mats.iter().zip(colors) {
+        detectors
+            .standard
+            .detect(mat, &mut detectors.keypoints, &opencv::core::no_array())?;
+        if detectors.keypoints.len() > 0 {
+            // debug!("found {} keypoints", detectors.keypoints.len());
+            return Ok(Some(color));
+        }
     }
 
     // unimplemented!()
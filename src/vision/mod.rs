@@ -2,6 +2,8 @@ pub mod blob_detection;
 pub mod locate_nozzle;
 pub mod preprocess;
 pub mod running_average;
+#[cfg(any(test, feature = "sim"))]
+pub mod synthetic_camera;
 pub mod utilities;
 pub mod vision_types;
 
@@ -24,6 +26,9 @@ use self::locate_nozzle::*;
 pub use self::vision_types::*;
 use crate::ui::data_labeling::SavedTargets;
 use blob_detection::BlobDetectors;
+use preprocess::PreprocessStep;
+#[cfg(any(test, feature = "sim"))]
+use synthetic_camera::SyntheticCamera;
 
 pub fn spawn_locator_thread(
     ctx: egui::Context,
@@ -38,6 +43,8 @@ pub fn spawn_locator_thread(
 ) {
     std::thread::spawn(move || {
         debug!("Camera supervisor thread running");
+        /// kept here so it survives the camera thread restarting
+        let mut pipeline: Vec<PreprocessStep> = vec![];
         loop {
             while let Ok(cmd) = channel_from_ui.try_recv() {
                 match cmd {
@@ -67,6 +74,9 @@ pub fn spawn_locator_thread(
                         debug!("Can't set mirror axes here");
                         // todo!()
                     }
+                    WebcamCommand::SetPreprocessPipeline(p) => {
+                        pipeline = p;
+                    }
                 }
             }
 
@@ -83,6 +93,7 @@ pub fn spawn_locator_thread(
                     // camera_size,
                     format,
                     (false, false),
+                    &mut pipeline,
                 ) {
                     debug!("Failed to spawn camera thread: {}", e);
                 }
@@ -94,6 +105,116 @@ pub fn spawn_locator_thread(
     });
 }
 
+/// Stand-in for [`spawn_locator_thread`] when simulating, frames come from a [`SyntheticCamera`]
+#[cfg(any(test, feature = "sim"))]
+pub fn spawn_synthetic_camera_thread(
+    ctx: egui::Context,
+    mut handle: egui::TextureHandle,
+    channel_from_ui: crossbeam_channel::Receiver<WebcamCommand>,
+    channel_to_ui: crossbeam_channel::Sender<WebcamMessage>,
+    webcam_settings_mutex: Arc<Mutex<crate::vision::VisionSettings>>,
+    mut camera: SyntheticCamera,
+) {
+    std::thread::spawn(move || {
+        debug!("Synthetic camera thread running");
+
+        let mut detectors = BlobDetectors::new().unwrap();
+        let mut pipeline: Vec<PreprocessStep> = vec![];
+
+        loop {
+            while let Ok(cmd) = channel_from_ui.try_recv() {
+                match cmd {
+                    WebcamCommand::GetCameraFormats => {
+                        let format = CameraFormat {
+                            size: camera.size,
+                            format: 0,
+                            framerate: 30,
+                        };
+                        if channel_to_ui
+                            .send(WebcamMessage::CameraFormats(vec![format]))
+                            .is_err()
+                        {
+                            debug!("Failed to send formats message to UI");
+                        }
+                    }
+                    WebcamCommand::SetBlobParams(params) => {
+                        detectors.set_params_standard(params.0);
+                    }
+                    WebcamCommand::SetMirrorAxes(x, y) => {
+                        camera.mirror_axes = (x, y);
+                    }
+                    WebcamCommand::SetPreprocessPipeline(p) => {
+                        pipeline = p;
+                    }
+                    cmd => {
+                        debug!("Ignoring command for synthetic camera: {:?}", cmd);
+                    }
+                }
+            }
+
+            let settings = webcam_settings_mutex.lock().unwrap().clone();
+
+            let mut buffer = camera.render(&settings);
+
+            if let Err(e) = locate_and_show(
+                &ctx,
+                &mut handle,
+                &mut buffer,
+                &settings,
+                &mut detectors,
+                &pipeline,
+                &channel_to_ui,
+            ) {
+                debug!("Failed to locate nozzle: {}", e);
+            }
+
+            std::thread::sleep(std::time::Duration::from_millis(33));
+        }
+    });
+}
+
+/// Find the nozzle in a frame, report it to the UI and display the annotated frame
+fn locate_and_show(
+    ctx: &egui::Context,
+    handle: &mut egui::TextureHandle,
+    buffer: &mut image::ImageBuffer<image::Rgb<u8>, Vec<u8>>,
+    settings: &VisionSettings,
+    detectors: &mut BlobDetectors,
+    pipeline: &[PreprocessStep],
+    channel_to_ui: &crossbeam_channel::Sender<WebcamMessage>,
+) -> Result<()> {
+    let (img_out, circle) = locate_nozzle(buffer, settings, detectors, pipeline)?;
+
+    // debug!("Nozzle located");
+    utilities::mat_to_imagebuffer(buffer, &img_out).unwrap();
+
+    if let Some(circle) = circle {
+        if channel_to_ui
+            .send(WebcamMessage::FoundNozzle(circle))
+            .is_err()
+        {
+            debug!("Failed to send message to UI");
+        }
+    } else {
+        if channel_to_ui.send(WebcamMessage::NozzleNotFound).is_err() {
+            debug!("Failed to send message to UI");
+        }
+    }
+
+    let mut img = egui::ColorImage::from_rgb(
+        [buffer.width() as usize, buffer.height() as usize],
+        buffer.as_flat_samples().as_slice(),
+    );
+
+    crate::ui::webcam_controls::draw_crosshair(settings.crosshair_size, &mut img);
+
+    handle.set(img, Default::default());
+
+    ctx.request_repaint();
+
+    Ok(())
+}
+
 fn get_camera_formats(
     index: usize,
     channel_to_ui: &crossbeam_channel::Sender<WebcamMessage>,
@@ -133,6 +254,7 @@ fn _spawn_camera_thread(
     // camera_size: (f64, f64),
     set_format: CameraFormat,
     mirror: (bool, bool),
+    pipeline: &mut Vec<PreprocessStep>,
 ) -> Result<()> {
     let _format = RequestedFormat::new::<RgbFormat>(RequestedFormatType::AbsoluteHighestFrameRate);
 
@@ -257,6 +379,10 @@ fn _spawn_camera_thread(
                     // todo!()
                 }
                 WebcamCommand::SetMirrorAxes(x, y) => todo!(),
+                WebcamCommand::SetPreprocessPipeline(p) => {
+                    debug!("Setting preprocess pipeline, {} steps", p.len());
+                    *pipeline = p;
+                }
             }
         }
 
@@ -391,28 +517,17 @@ fn _spawn_camera_thread(
 
         // let mut buffer = resizer.resize(&buffer, dst_image, options)
 
-        match locate_nozzle(&buffer, &settings, &mut detectors) {
-            Ok((img_out, circle)) => {
-                // debug!("Nozzle located");
-                utilities::mat_to_imagebuffer(&mut buffer, &img_out).unwrap();
-
-                if let Some(circle) = circle {
-                    if channel_to_ui
-                        .send(WebcamMessage::FoundNozzle(circle))
-                        .is_err()
-                    {
-                        debug!("Failed to send message to UI");
-                    }
-                } else {
-                    if channel_to_ui.send(WebcamMessage::NozzleNotFound).is_err() {
-                        debug!("Failed to send message to UI");
-                    }
-                }
-            }
-            Err(e) => {
-                // eprintln!("Failed to locate nozzle: {}", e);
-                continue;
-            }
+        if let Err(e) = locate_and_show(
+            &ctx,
+            &mut handle,
+            &mut buffer,
+            &settings,
+            &mut detectors,
+            pipeline,
+            channel_to_ui,
+        ) {
+            // eprintln!("Failed to locate nozzle: {}", e);
+            continue;
         }
 
         // buffer = image::imageops::resize(&buffer2, buffer.width(), buffer.height(), filter);
@@ -421,17 +536,6 @@ fn _spawn_camera_thread(
         // let elapsed = t1.duration_since(t0);
         // debug!("Frame time: {:.1} ms", elapsed.as_micros() as f64 / 1000.0);
 
-        let mut img = egui::ColorImage::from_rgb(
-            [res.width() as usize, res.height() as usize],
-            buffer.as_flat_samples().as_slice(),
-        );
-
-        crate::ui::webcam_controls::draw_crosshair(settings.crosshair_size, &mut img);
-
-        handle.set(img, Default::default());
-
-        ctx.request_repaint();
-
         //
     }
 }
@@ -8,7 +8,7 @@ use opencv::{
     prelude::*,
 };
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct PreprocessStep {
     pub step: PreprocessStepType,
     pub enabled: bool,
@@ -26,6 +26,7 @@ pub enum PreprocessStepType {
         threshold: f64,
         threshold_type: ThresholdType,
     },
+    /// gaussian, with the block size and constant from kTAMV
     AdaptiveThreshold,
 }
 
@@ -40,29 +41,106 @@ impl PreprocessStepType {
         }
     }
 
+    /// Frames start out RGB, thresholds need a single channel
     pub fn apply(&self, img: &Mat, img2: &mut Mat) -> Result<()> {
         match self {
-            PreprocessStepType::ConvertGrayscale => todo!(),
-            PreprocessStepType::ConvertLuma => {
+            PreprocessStepType::ConvertGrayscale => {
+                if img.channels() == 1 {
+                    img.copy_to(img2)?;
+                    return Ok(());
+                }
                 cvt_color(
                     &img,
                     img2,
+                    opencv::imgproc::COLOR_RGB2GRAY,
+                    0,
+                    opencv::core::AlgorithmHint::ALGO_HINT_DEFAULT,
+                )?;
+            }
+            PreprocessStepType::ConvertLuma => {
+                if img.channels() == 1 {
+                    img.copy_to(img2)?;
+                    return Ok(());
+                }
+                let mut yuv = Mat::default();
+                cvt_color(
+                    &img,
+                    &mut yuv,
                     opencv::imgproc::COLOR_RGB2YUV,
                     0,
                     opencv::core::AlgorithmHint::ALGO_HINT_DEFAULT,
                 )?;
+                opencv::core::extract_channel(&yuv, img2, 0)?;
+            }
+            PreprocessStepType::GaussianBlur { ksize, sigma } => {
+                /// kernel size has to be odd
+                let ksize = (*ksize | 1) as i32;
+                gaussian_blur(
+                    &img,
+                    img2,
+                    Size::new(ksize, ksize),
+                    *sigma,
+                    *sigma,
+                    opencv::core::BorderTypes::BORDER_REPLICATE.into(),
+                    opencv::core::AlgorithmHint::ALGO_HINT_DEFAULT,
+                )?;
             }
-            PreprocessStepType::GaussianBlur { ksize, sigma } => todo!(),
             PreprocessStepType::Threshold {
-                threshold,
+                threshold: thresh,
                 threshold_type,
-            } => todo!(),
-            PreprocessStepType::AdaptiveThreshold => todo!(),
+            } => {
+                ensure!(
+                    img.channels() == 1,
+                    "Threshold needs a grayscale or luma image"
+                );
+                threshold(&img, img2, *thresh, 255.0, threshold_type.flags())?;
+            }
+            PreprocessStepType::AdaptiveThreshold => {
+                ensure!(
+                    img.channels() == 1,
+                    "Adaptive threshold needs a grayscale or luma image"
+                );
+                opencv::imgproc::adaptive_threshold(
+                    &img,
+                    img2,
+                    255.,
+                    opencv::imgproc::ADAPTIVE_THRESH_GAUSSIAN_C.into(),
+                    ThresholdTypes::THRESH_BINARY.into(),
+                    35,
+                    1.,
+                )?;
+            }
         }
         Ok(())
     }
 }
 
+/// If there's anything to run, otherwise the built-in chain is used
+pub fn is_active(pipeline: &[PreprocessStep]) -> bool {
+    pipeline.iter().any(|s| s.enabled)
+}
+
+/// Runs the enabled steps in order.
+/// Returns the image after `show` steps for display (0 for the input), and the final image
+pub fn run_pipeline(pipeline: &[PreprocessStep], img: &Mat, show: usize) -> Result<(Mat, Mat)> {
+    let mut img = img.clone();
+    let mut img2 = Mat::default();
+    let mut img_out = img.clone();
+
+    for (i, step) in pipeline.iter().filter(|s| s.enabled).enumerate() {
+        step.step
+            .apply(&img, &mut img2)
+            .with_context(|| format!("{} failed", step.step.to_str()))?;
+        std::mem::swap(&mut img, &mut img2);
+
+        if i < show {
+            img_out = img.clone();
+        }
+    }
+
+    Ok((img_out, img))
+}
+
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum ThresholdType {
     Binary,
@@ -73,8 +151,110 @@ pub enum ThresholdType {
     BinaryInvOtsu,
 }
 
+impl ThresholdType {
+    pub const ALL: [ThresholdType; 6] = [
+        ThresholdType::Binary,
+        ThresholdType::BinaryInv,
+        ThresholdType::BinaryTriangle,
+        ThresholdType::BinaryInvTriangle,
+        ThresholdType::BinaryOtsu,
+        ThresholdType::BinaryInvOtsu,
+    ];
+
+    pub fn to_str(&self) -> &str {
+        match self {
+            ThresholdType::Binary => "Binary",
+            ThresholdType::BinaryInv => "Binary Inv",
+            ThresholdType::BinaryTriangle => "Binary + Triangle",
+            ThresholdType::BinaryInvTriangle => "Binary Inv + Triangle",
+            ThresholdType::BinaryOtsu => "Binary + Otsu",
+            ThresholdType::BinaryInvOtsu => "Binary Inv + Otsu",
+        }
+    }
+
+    /// Triangle and Otsu pick the threshold themselves
+    pub fn flags(&self) -> i32 {
+        match self {
+            ThresholdType::Binary => imgproc::THRESH_BINARY,
+            ThresholdType::BinaryInv => imgproc::THRESH_BINARY_INV,
+            ThresholdType::BinaryTriangle => imgproc::THRESH_BINARY + imgproc::THRESH_TRIANGLE,
+            ThresholdType::BinaryInvTriangle => {
+                imgproc::THRESH_BINARY_INV + imgproc::THRESH_TRIANGLE
+            }
+            ThresholdType::BinaryOtsu => imgproc::THRESH_BINARY + imgproc::THRESH_OTSU,
+            ThresholdType::BinaryInvOtsu => imgproc::THRESH_BINARY_INV + imgproc::THRESH_OTSU,
+        }
+    }
+}
+
 impl Default for PreprocessStepType {
     fn default() -> Self {
         PreprocessStepType::ConvertLuma
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// dark nozzle on a light background
+    fn nozzle_image() -> Mat {
+        let mut img = Mat::new_rows_cols_with_default(
+            480,
+            640,
+            opencv::core::CV_8UC3,
+            opencv::core::Scalar::all(200.),
+        )
+        .unwrap();
+        imgproc::circle(
+            &mut img,
+            Point::new(320, 240),
+            60,
+            opencv::core::Scalar::all(40.),
+            -1,
+            imgproc::LINE_8,
+            0,
+        )
+        .unwrap();
+        img
+    }
+
+    #[test]
+    fn pipeline_runs_enabled_steps() {
+        let step = |step: PreprocessStepType, enabled: bool| PreprocessStep { step, enabled };
+        let mut pipeline = vec![
+            step(PreprocessStepType::ConvertLuma, true),
+            step(
+                PreprocessStepType::GaussianBlur {
+                    ksize: 6,
+                    sigma: 2.,
+                },
+                true,
+            ),
+            step(
+                PreprocessStepType::Threshold {
+                    threshold: 127.,
+                    threshold_type: ThresholdType::BinaryInv,
+                },
+                true,
+            ),
+            step(PreprocessStepType::AdaptiveThreshold, false),
+        ];
+        assert!(is_active(&pipeline));
+
+        let img = nozzle_image();
+        let (shown, out) = run_pipeline(&pipeline, &img, 1).unwrap();
+        assert_eq!(shown.channels(), 1);
+        assert_eq!(out.channels(), 1);
+        assert_eq!((out.cols(), out.rows()), (640, 480));
+        assert_eq!(*out.at_2d::<u8>(240, 320).unwrap(), 255);
+        assert_eq!(*out.at_2d::<u8>(10, 10).unwrap(), 0);
+
+        /// thresholding a colour frame is a mistake in the pipeline
+        pipeline[0].enabled = false;
+        assert!(run_pipeline(&pipeline, &img, 0).is_err());
+
+        pipeline.iter_mut().for_each(|s| s.enabled = false);
+        assert!(!is_active(&pipeline));
+    }
+}
@@ -0,0 +1,102 @@
+//! Fake camera looking up at the nozzle of a [`MockPrinterState`], for running auto-offset without hardware
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tracing::{debug, error, info, trace, warn};
+
+use rand::prelude::*;
+use rand_xoshiro::{rand_core::SeedableRng, Xoshiro256PlusPlus};
+
+use crate::klipper_async::mock_moonraker::MockPrinterState;
+
+use super::VisionSettings;
+
+/// grey level of the background and the nozzle
+const BACKGROUND: f64 = 200.;
+const NOZZLE: f64 = 40.;
+
+pub struct SyntheticCamera {
+    state: Arc<parking_lot::Mutex<MockPrinterState>>,
+    pub size: (u32, u32),
+    pub swap_axes: bool,
+    pub mirror_axes: (bool, bool),
+    rng: Xoshiro256PlusPlus,
+}
+
+impl SyntheticCamera {
+    pub fn new(
+        state: Arc<parking_lot::Mutex<MockPrinterState>>,
+        size: (u32, u32),
+        swap_axes: bool,
+        mirror_axes: (bool, bool),
+    ) -> Self {
+        let seed = state.lock().sim.settings.seed;
+        Self {
+            state,
+            size,
+            swap_axes,
+            mirror_axes,
+            rng: Xoshiro256PlusPlus::seed_from_u64(seed.wrapping_add(1)),
+        }
+    }
+
+    /// Where the nozzle shows up in the image, or None with no tool mounted.
+    /// Inverse of the screen transform and pixels to mm conversion in auto offset.
+    pub fn nozzle_pixel(&self, pixels_per_mm: f64) -> Option<(f64, f64)> {
+        let state = self.state.lock();
+        if state.active_tool < 0 {
+            return None;
+        }
+
+        let (nx, ny) = state.nozzle_position();
+        let (cx, cy) = state.sim.settings.camera_position;
+        let (mut x, mut y) = (nx - cx, ny - cy);
+
+        if self.mirror_axes.0 {
+            x *= -1.0;
+        }
+        if self.mirror_axes.1 {
+            y *= -1.0;
+        }
+        if self.swap_axes {
+            std::mem::swap(&mut x, &mut y);
+        }
+
+        Some((
+            self.size.0 as f64 / 2. + x * pixels_per_mm,
+            self.size.1 as f64 / 2. + y * pixels_per_mm,
+        ))
+    }
+
+    /// Dark nozzle on a light background, anti-aliased so sub-pixel moves show up
+    pub fn render(
+        &mut self,
+        settings: &VisionSettings,
+    ) -> image::ImageBuffer<image::Rgb<u8>, Vec<u8>> {
+        let center = self.nozzle_pixel(settings.pixels_per_mm);
+        let radius = settings.target_radius;
+        let noise = self.state.lock().sim.settings.image_noise;
+
+        let mut buffer = image::ImageBuffer::new(self.size.0, self.size.1);
+
+        for (x, y, pixel) in buffer.enumerate_pixels_mut() {
+            let mut v = BACKGROUND;
+
+            if let Some((cx, cy)) = center {
+                let d = ((x as f64 + 0.5 - cx).powi(2) + (y as f64 + 0.5 - cy).powi(2)).sqrt();
+                let coverage = (radius + 0.5 - d).clamp(0., 1.);
+                v += (NOZZLE - BACKGROUND) * coverage;
+            }
+
+            if noise > 0. {
+                v += noise * (self.rng.random::<f64>() * 2. - 1.) * 3f64.sqrt();
+            }
+
+            let v = v.round().clamp(0., 255.) as u8;
+            *pixel = image::Rgb([v, v, v]);
+        }
+
+        buffer
+    }
+}
@@ -28,6 +28,8 @@ pub enum WebcamCommand {
     SetCameraFormat(CameraFormat),
     SetBlobParams(BlobParams),
     SetMirrorAxes(bool, bool),
+    /// empty or all disabled to go back to the built-in chain
+    SetPreprocessPipeline(Vec<super::preprocess::PreprocessStep>),
 }
 
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]